@@ -9,6 +9,9 @@ use quicksilver::{
     lifecycle::{Window, Event},
 };
 
+/// A menu that's never shown, for buttons that aren't needed right now.
+pub const HIDDEN: usize = std::usize::MAX;
+
 pub struct Buttons {
     buttons: Vec<Button>,
     menu: usize,
@@ -57,6 +60,10 @@ impl Buttons {
         self.menu = menu;
     }
 
+    pub fn menu(&self) -> usize {
+        self.menu
+    }
+
     pub fn update(&mut self, window: &mut Window) {
         let mouse = window.mouse().pos();
         let mouse_inside = move |rect: &Rectangle| {
@@ -164,6 +171,10 @@ impl Button {
         self.text = text;
     }
 
+    pub fn set_menu(&mut self, menu: usize) {
+        self.menu = menu;
+    }
+
     fn rectangles<'a>(&'a self) -> impl Iterator<Item=Rectangle> + 'a {
         let v = self.hover.min(0.15) / 0.15;
         let u = 1.0 - v;
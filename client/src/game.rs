@@ -3,12 +3,21 @@ use crate::util::*;
 use crate::persistent::*;
 use crate::controls::*;
 use crate::buttons::*;
+use tetris_net::prediction::{Input, Prediction};
+use tetris_net::clock::Clock;
+use tetris_net::connection::version_mismatch;
 use mirror::{Remote, Client};
 use tetris_model::instance::*;
 use std::time::Duration;
 use rand::thread_rng;
 use rand::seq::SliceRandom;
 
+/// Replays are loaded from next to the page of the web client. A native client would look for
+/// them on the local disk instead, so it doesn't offer them.
+const REPLAYS_AVAILABLE: bool = cfg!(target_arch = "wasm32");
+
+type Connecting<R> = Box<Future<Item=Client<InstanceState, R>, Error=mirror::Error>>;
+
 use quicksilver::{
     Future,
     Result,
@@ -26,8 +35,20 @@ pub struct Game<R: Remote> {
     buttons: Buttons,
 
     state: ActiveState,
+    prediction: Prediction,
+    clock: Clock,
+    forced: usize,
     last_line_drop: Duration,
     return_to_menu: bool,
+    watch_replay: bool,
+    spectating: bool,
+
+    connect: Option<Box<Fn() -> Connecting<R>>>,
+    reconnecting: Option<Connecting<R>>,
+    connection_lost: Option<Duration>,
+    attempt_time: f64,
+    retry_in: f64,
+    attempts: i32,
     game_over_duration: Option<Duration>,
 
     font: Font,
@@ -38,7 +59,13 @@ pub struct Game<R: Remote> {
     result_style: FontStyle,
     result: Option<Image>,
 
+    label_style: FontStyle,
+    labels: Vec<(String, Image)>,
+    standings: Vec<Image>,
+
     message: Image,
+    countdown: Option<(String, Image)>,
+    ping: Option<(u64, Image)>,
     own_blocks: Image,
     other_blocks: Image,
     own_bg: Image,
@@ -54,8 +81,34 @@ pub struct Game<R: Remote> {
 }
 
 impl<R: Remote + 'static> Game<R> {
-    pub fn new<F>(client: F, player_id: usize, player_key: String, data: Persistent)
+    /// Creates a game that connects using `connect`. The same function is used to get back
+    /// into the match when the connection drops.
+    pub fn new<F, C>(connect: F, player_id: usize, player_key: String, data: Persistent)
         -> Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>
+        where
+            F: 'static + Fn() -> C,
+            C: 'static + Future<Item=Client<InstanceState, R>, Error=mirror::Error>
+    {
+        let connect: Box<Fn() -> Connecting<R>> = Box::new(move || Box::new(connect()) as Connecting<R>);
+        let client = connect();
+
+        Box::new(Self::load(client, player_id, player_key, data, Some(connect))
+            .map(|game| Box::new(game) as Box<Scene>))
+    }
+
+    /// Creates a game that only renders the mirrored state, following `player_id`.
+    /// No input is processed and nothing is sent to the server.
+    pub fn spectate<F>(client: F, player_id: usize, data: Persistent)
+        -> Box<Future<Item=Self, Error=quicksilver::Error>>
+        where
+            F: 'static + Future<Item=Client<InstanceState, R>, Error=mirror::Error>
+    {
+        Self::load(client, player_id, String::new(), data, None)
+    }
+
+    fn load<F>(client: F, player_id: usize, player_key: String, data: Persistent,
+               connect: Option<Box<Fn() -> Connecting<R>>>)
+        -> Box<Future<Item=Self, Error=quicksilver::Error>>
         where
             F: 'static + Future<Item=Client<InstanceState, R>, Error=mirror::Error>
     {
@@ -69,20 +122,19 @@ impl<R: Remote + 'static> Game<R> {
         let bomb = Image::load("bomb.png");
         let bomb_small = Image::load("bomb_small.png");
         let pattern = Image::load("pattern.png");
+        let spectating = connect.is_none();
 
         Box::new(client.join(font.join(own_blocks.join(other_blocks.join(own_bg.join(other_bg.join(ko.join(bomb.join(bomb_small.join(pattern)))))))))
             .map(move |(mut client, (font, (own_blocks, (other_blocks, (own_bg, (other_bg, (ko, (bomb, (bomb_small, pattern)))))))))| {
-                client.command(format!("call:login:\"{}\"", player_key).as_str()).unwrap();
-
-                let mut mapping = [0; 8];
-                let mut mapping_i = (0..9).filter(|&i| i != player_id);
-                for i in mapping.iter_mut() {
-                    *i = mapping_i.next().unwrap();
+                if !spectating {
+                    client.command(Self::login_command(player_key.as_str(), &data).as_str()).unwrap();
                 }
-                mapping.shuffle(&mut thread_rng());
+
+                let mapping = Self::make_mapping(player_id);
 
                 let position_style = FontStyle::new(32.0, Color::WHITE);
                 let result_style = FontStyle::new(160.0, Color::WHITE);
+                let label_style = FontStyle::new(16.0, Color::WHITE);
                 let position_header = font.render("Place: ", &position_style).unwrap();
                 let message = font.render("Get Ready!", &result_style).unwrap();
                 let mut buttons = Buttons::new();
@@ -91,37 +143,171 @@ impl<R: Remote + 'static> Game<R> {
                                          Color { r: 0.1, g: 0.1, b:  0.8, a: 1.0 }, 1,
                                          font.render("Return", &position_style).ok()));
 
-                Box::new(Self {
+                // only offer the replay when the server actually recorded one
+                let replay_menu = if spectating || client.replay.is_empty() || !REPLAYS_AVAILABLE {
+                    2
+                } else {
+                    1
+                };
+                buttons.push(Button::new(vec![util::rect(40.0, 220.0, 150.0, 40.0)],
+                                         vec![util::rect(20.0, 210.0, 190.0, 60.0)],
+                                         Color { r: 0.8, g: 0.1, b: 0.4, a: 1.0 }, replay_menu,
+                                         font.render("Replay", &position_style).ok()));
+
+                let forced = client.games.get(player_id).map(|g| g.forced).unwrap_or(0);
+
+                Self {
                     client, player_id, player_key, data, buttons, state: ActiveState::new(),
+                    prediction: Prediction::new(), clock: Clock::new(), countdown: None, ping: None,
+                    forced,
                     last_line_drop: Duration::from_secs(0), return_to_menu: false,
-                    game_over_duration: None, font, position_style, result_style, position: None,
-                    position_header, result: None, message, own_blocks, other_blocks, own_bg,
-                    other_bg, ko, bomb, bomb_small, pattern, pattern_timer: 0.0, mapping,
-                }) as Box<Scene>
+                    watch_replay: false, spectating, connect, reconnecting: None,
+                    connection_lost: None, attempt_time: 0.0, retry_in: 0.0, attempts: 0,
+                    game_over_duration: None, font,
+                    position_style, result_style, position: None, position_header, result: None,
+                    label_style, labels: Vec::new(), standings: Vec::new(),
+                    message, own_blocks, other_blocks, own_bg, other_bg, ko, bomb, bomb_small,
+                    pattern, pattern_timer: 0.0, mapping,
+                }
             }))
     }
 
-    fn drop_current(&mut self) {
-        // send drop command
-        self.client.command(format!("call:drop:\"{}\" {}",
-                                    self.player_key,
-                                    serde_json::to_string(&self.state).unwrap()).as_str()).unwrap();
+    fn login_command(player_key: &str, data: &Persistent) -> String {
+        let nickname: serde_json::Value = sanitize_nickname(data.nickname.as_str()).into();
+        format!("call:login:\"{}\" {}", player_key, nickname)
+    }
 
-        // update the field in advance
-        for y in 0..4 {
-            for x in 0..4 {
-                let shape = self.client.games[self.player_id].current as usize;
-                let rotation = self.state.rotation as usize;
-                let col = tetris_model::shapes::SHAPES[shape][rotation][x+y*4];
-                if col != 0 {
-                    if self.state.y + y as i32 >= 0 {
-                        let index = ((self.state.y + y as i32) * 10 + self.state.x + x as i32) as usize;
-                        self.client.games[self.player_id].field[index] = col;
-                    }
-                }
+    /// Returns true if the player is in the match. The match starts at the same moment for
+    /// everyone, as far as our estimate of the server's clock goes.
+    fn in_game(&self) -> bool {
+        match self.clock.server_now() {
+            Some(now) => self.client.in_game_at(self.player_id, now),
+            None => self.client.in_game(self.player_id),
+        }
+    }
+
+    /// Returns what the countdown to the start of the match shows right now, if anything.
+    fn countdown_text(&self) -> Option<String> {
+        let now = self.clock.server_now()?;
+        if self.client.start_at == 0 || self.client.done {
+            return None;
+        }
+
+        let left = self.client.start_at as i64 - now as i64;
+        let countdown = COUNTDOWN.as_secs() as i64 * 1000;
+        if left > countdown {
+            None
+        } else if left > 0 {
+            Some(format!("{}", (left + 999) / 1000))
+        } else if left > -1000 {
+            Some("GO!".to_string())
+        } else {
+            None
+        }
+    }
+
+    fn make_mapping(player_id: usize) -> [usize; 8] {
+        let mut mapping = [0; 8];
+        let mut mapping_i = (0..9).filter(|&i| i != player_id);
+        for i in mapping.iter_mut() {
+            *i = mapping_i.next().unwrap();
+        }
+        mapping.shuffle(&mut thread_rng());
+        mapping
+    }
+
+    /// Changes the player that is shown on the main board.
+    pub fn follow(&mut self, player_id: usize) {
+        if player_id != self.player_id && player_id < self.client.games.len() {
+            self.player_id = player_id;
+            self.mapping = Self::make_mapping(player_id);
+            self.position = None;
+            self.result = None;
+        }
+    }
+
+    pub fn player_id(&self) -> usize {
+        self.player_id
+    }
+
+    pub fn client(&self) -> &Client<InstanceState, R> {
+        &self.client
+    }
+
+    pub fn client_mut(&mut self) -> &mut Client<InstanceState, R> {
+        &mut self.client
+    }
+
+    pub fn set_client(&mut self, client: Client<InstanceState, R>) {
+        self.client = client;
+        self.position = None;
+        self.result = None;
+        self.game_over_duration = None;
+        self.standings.clear();
+        self.message = self.font.render("Get Ready!", &self.result_style).unwrap();
+        self.buttons.set_menu(0);
+    }
+
+    /// Tries to get a new connection to the match, backing off after every failed attempt.
+    fn reconnect(&mut self, dt: f64) {
+        if self.connection_lost.is_none() {
+            self.connection_lost = Some(Duration::from_secs(0));
+            self.retry_in = 0.0;
+            self.attempts = 0;
+            self.message = self.font.render("Connection lost", &self.result_style).unwrap();
+        }
+
+        // there's no point in coming back with a client the server doesn't understand
+        if version_mismatch() {
+            self.connect = None;
+            self.reconnecting = None;
+            self.message = self.font.render("Please update", &self.result_style).unwrap();
+            self.buttons.set_menu(1);
+            return;
+        }
+
+        let lost = self.connection_lost.as_mut().unwrap();
+        add_seconds(lost, dt);
+
+        if *lost > Duration::from_secs(self.client.disconnect_timeout) {
+            // the server has given our seat away by now
+            self.connect = None;
+            self.reconnecting = None;
+            self.message = self.font.render("Disconnected", &self.result_style).unwrap();
+            self.buttons.set_menu(1);
+            return;
+        }
+
+        if let Some(mut future) = self.reconnecting.take() {
+            self.attempt_time += dt;
+            match future.poll() {
+                Ok(Async::Ready(mut client)) => {
+                    let login = Self::login_command(self.player_key.as_str(), &self.data);
+                    client.command(login.as_str()).ok();
+                    self.client = client;
+                    self.state = ActiveState::new();
+                    self.prediction.reconnected(&mut self.client.games[self.player_id]);
+                    self.clock.reconnected();
+                    self.connection_lost = None;
+                    self.message = self.font.render("Get Ready!", &self.result_style).unwrap();
+                },
+                Ok(Async::NotReady) if self.attempt_time < 5.0 => {
+                    self.reconnecting = Some(future);
+                },
+                _ => {
+                    self.attempts += 1;
+                    self.retry_in = (0.5 * 2.0f64.powi(self.attempts)).min(8.0);
+                },
             }
+        } else if self.retry_in > 0.0 {
+            self.retry_in -= dt;
+        } else {
+            self.attempt_time = 0.0;
+            self.reconnecting = self.connect.as_ref().map(|connect| connect());
         }
+    }
 
+    fn drop_current(&mut self) {
         // update statistics
         self.data.statistics.bricks += 1;
         match self.client.games[self.player_id].current {
@@ -135,9 +321,18 @@ impl<R: Remote + 'static> Game<R> {
             _ => (),
         }
 
-        // update the current tetrimino in advance
+        // update the field and the current tetrimino in advance, then send the drop command,
+        // the server knows which seat it's for from our login
+        // the server ignores drops made before it placed a tetrimino for us, so it's told how
+        // many of those we knew about
+        let forced = self.client.games[self.player_id].forced;
+        let input = self.prediction.input(&mut self.client.games[self.player_id],
+                                          Input::Drop(self.state));
+        self.client.command(format!("call:drop:{} {} {}",
+                                    serde_json::to_string(&self.state).unwrap(),
+                                    input,
+                                    forced).as_str()).unwrap();
         self.state = ActiveState::new();
-        self.client.games[self.player_id].current = self.client.games[self.player_id].next[0];
     }
 
     fn draw_state<F: Fn(&Image)->Background>(&self,
@@ -187,11 +382,23 @@ impl<R: Remote + 'static> Game<R> {
 
 impl<R: Remote> Drop for Game<R> {
     fn drop(&mut self) {
+        // spectators have no statistics of their own
+        if self.spectating {
+            return;
+        }
+
         // append the server side session statistics
         self.data.statistics.lines_cleared += self.client.games[self.player_id].lines_cleared;
         self.data.statistics.garbage_sent += self.client.games[self.player_id].garbage_sent;
         self.data.statistics.garbage_received += self.client.games[self.player_id].garbage_received;
 
+        // the server publishes our new rating when the match is over
+        let rating = self.client.games[self.player_id].rating;
+        if self.client.done && rating > 0.0 && rating != self.data.rating {
+            self.data.rating = rating;
+            self.data.rating_history.push(rating);
+        }
+
         // update statics that relate a number of games
         if self.client.games[self.player_id].ko || self.client.done {
             match self.client.games_ko.iter()
@@ -217,7 +424,63 @@ impl<R: Remote + 'static> Scene for Game<R> {
         self.data.controls.update(window);
         self.buttons.update(window);
 
-        if self.client.in_game(self.player_id) {
+        // connections that went quiet are given up on, even if they were never closed
+        let lost = self.connection_lost.is_some() || !self.client.alive() || self.clock.silent();
+        if self.connect.is_some() && !self.client.done && lost {
+            self.reconnect(window.update_rate() / 1000.0);
+        }
+
+        // keep comparing clocks with the server, replays have nothing to compare with
+        if self.connect.is_some() && self.connection_lost.is_none() {
+            if let Some(command) = self.clock.update(self.client.clock) {
+                self.client.command(command.as_str()).ok();
+            }
+        }
+
+        // show how long messages take to get to the server and back
+        let rtt = self.clock.rtt().filter(|_| self.connection_lost.is_none());
+        if rtt != self.ping.as_ref().map(|&(rtt, _)| rtt) {
+            self.ping = rtt.map(|rtt| {
+                let color = if rtt < 100 {
+                    Color { r: 0.4, g: 1.0, b: 0.4, a: 1.0 }
+                } else if rtt < 250 {
+                    Color { r: 1.0, g: 0.9, b: 0.3, a: 1.0 }
+                } else {
+                    Color { r: 1.0, g: 0.3, b: 0.3, a: 1.0 }
+                };
+                let text = format!("{} ms", rtt);
+                (rtt, self.font.render(text.as_str(), &FontStyle::new(16.0, color)).unwrap())
+            });
+        }
+
+        let text = self.countdown_text();
+        if text.as_ref() != self.countdown.as_ref().map(|(text, _)| text) {
+            self.countdown = text.map(|text| {
+                let image = self.font.render(text.as_str(), &self.result_style).unwrap();
+                (text, image)
+            });
+        }
+
+        let playing = !self.spectating &&
+            self.connection_lost.is_none() &&
+            self.in_game();
+
+        // the server placed our tetrimino because we took too long, continue with the next one
+        let forced = self.client.games[self.player_id].forced;
+        if forced != self.forced {
+            self.forced = forced;
+            self.state = ActiveState::new();
+        }
+
+        // ask for the server's copy of our state when ours went out of sync
+        if playing {
+            let resynced = self.client.resynced;
+            if self.prediction.check(&mut self.client.games[self.player_id], resynced) {
+                self.client.command("call:resync:").ok();
+            }
+        }
+
+        if playing {
             if self.data.controls[BindPoint::Left] {
                 self.state = self.client.games[self.player_id].slide_left(self.state);
             }
@@ -242,22 +505,26 @@ impl<R: Remote + 'static> Scene for Game<R> {
             }
             if self.data.controls[BindPoint::Hold] {
                 if !self.client.games[self.player_id].held {
-                    self.client.games[self.player_id].held = true;
+                    let input = self.prediction.input(&mut self.client.games[self.player_id],
+                                                      Input::Hold);
                     self.data.statistics.holds += 1;
                     self.state = ActiveState::new();
-                    self.client
-                        .command(format!("call:hold:\"{}\"", self.player_key).as_str())
-                        .unwrap();
+                    self.client.command(format!("call:hold:{}", input).as_str()).unwrap();
                 }
             }
-        } else if self.buttons[0].clicked() {
-            self.return_to_menu = true;
+        } else {
+            if self.buttons[0].clicked() {
+                self.return_to_menu = true;
+            }
+            if self.buttons[1].clicked() {
+                self.watch_replay = true;
+            }
         }
 
         add_seconds(&mut self.last_line_drop, window.update_rate() / 1000.0);
         self.game_over_duration.as_mut().map(|go| add_seconds(go, window.update_rate() / 1000.0));
 
-        if self.client.in_game(self.player_id) {
+        if playing {
             while self.last_line_drop >= Duration::from_millis(self.client.speed) {
                 self.last_line_drop -= Duration::from_millis(self.client.speed);
                 let before = self.state;
@@ -267,7 +534,7 @@ impl<R: Remote + 'static> Scene for Game<R> {
                     self.drop_current();
                 }
             }
-        } else {
+        } else if self.connection_lost.is_none() {
             self.last_line_drop = Duration::from_secs(0);
             if self.client.started && self.game_over_duration.is_none() {
                 self.game_over_duration = Some(Duration::from_secs(0));
@@ -284,7 +551,7 @@ impl<R: Remote + 'static> Scene for Game<R> {
     }
 
     fn event(&mut self, event: &Event, window: &mut Window) -> Result<()> {
-        if !self.client.in_game(self.player_id) {
+        if self.connection_lost.is_some() || !self.in_game() {
             self.buttons.event(*event, window);
         }
 
@@ -343,9 +610,16 @@ impl<R: Remote + 'static> Scene for Game<R> {
             window.draw(&Rectangle::new(Vector::new(520.0 - size.x * 0.5, 310.0), size), Img(image));
         }
 
+        // render the round trip time
+        if let Some((_, image)) = self.ping.as_ref() {
+            let size = image.area().size;
+            window.draw(&Rectangle::new(Vector::new(8.0, 352.0 - size.y), size), Img(image));
+        }
+
         // render the result
-        if !self.client.started || self.client.done || self.client.games[self.player_id].ko {
-            if self.client.started && self.result.is_none() {
+        let lost = self.connection_lost.is_some();
+        if !self.client.started || self.client.done || self.client.games[self.player_id].ko || lost {
+            if self.client.started && self.result.is_none() && !lost {
                 let final_position = self.client.games_ko.iter()
                     .enumerate()
                     .find(|(_, e)| **e == self.player_id)
@@ -363,7 +637,8 @@ impl<R: Remote + 'static> Scene for Game<R> {
 
             let tick = 0.75;
 
-            if self.game_over_duration
+            let countdown = self.countdown.is_some() && !lost;
+            if !countdown && self.game_over_duration
                 .map(|d| d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0)
                 .map(|d| d < tick * 3.0 && (d / tick).fract() < 0.5)
                 .unwrap_or(true) {
@@ -374,7 +649,14 @@ impl<R: Remote + 'static> Scene for Game<R> {
             }
         }
 
-        if self.client.in_game(self.player_id) {
+        // render the countdown to the start of the match
+        if let Some((_, image)) = self.countdown.as_ref().filter(|_| !lost) {
+            let size = image.area().size;
+            window.draw_ex(&Rectangle::new(Vector::new(320.0 - size.x * 0.25, 200.0), size * 0.5),
+                           Img(image), Transform::IDENTITY, 1);
+        }
+
+        if self.in_game() {
             // render the falling tetrimino
             if !self.client.games[self.player_id].ko {
                 self.draw_state(window,
@@ -438,6 +720,40 @@ impl<R: Remote + 'static> Scene for Game<R> {
             }
         }
 
+        // render the names of the players, only when they change
+        for (i, game) in self.client.games.iter().enumerate() {
+            let name = game.display_name(i);
+            if self.labels.get(i).map(|(n, _)| *n != name).unwrap_or(true) {
+                let image = self.font.render(name.as_str(), &self.label_style).unwrap();
+                if i < self.labels.len() {
+                    self.labels[i] = (name, image);
+                } else {
+                    self.labels.push((name, image));
+                }
+            }
+        }
+
+        // render who finished where once the match is over
+        let limit = Duration::from_secs(3);
+        if self.client.done && self.game_over_duration.map(|t| t > limit).unwrap_or(false) {
+            if self.standings.is_empty() {
+                for (place, &index) in self.client.standings().iter().enumerate() {
+                    let name = self.client.games[index].display_name(index);
+                    let text = format!("{}. {}", place + 1, name);
+                    self.standings.push(self.font.render(text.as_str(), &self.label_style).unwrap());
+                }
+            }
+
+            let panel = Rectangle::new(Vector::new(240.0, 170.0),
+                                       Vector::new(160.0, 12.0 + 14.0 * self.standings.len() as f32));
+            window.draw_ex(&panel, Col(Color::BLACK.with_alpha(0.6)), Transform::IDENTITY, 2);
+            for (i, line) in self.standings.iter().enumerate() {
+                let size = line.area().size * 0.5;
+                window.draw_ex(&Rectangle::new(Vector::new(250.0, 176.0 + 14.0 * i as f32), size),
+                               Img(line), Transform::IDENTITY, 3);
+            }
+        }
+
         // render other games
         for y in 0..2 {
             for x in 0..4 {
@@ -455,6 +771,15 @@ impl<R: Remote + 'static> Scene for Game<R> {
 
                     window.draw_ex(&bg, Img(&self.other_bg), Transform::IDENTITY, -1);
 
+                    // label the board, shrinking long names to fit
+                    if let Some((_, label)) = self.labels.get(i) {
+                        let size = label.area().size;
+                        let scale = (0.5f32).min(48.0 / size.x);
+                        let pos = Vector::new(bg.pos.x + 20.0 - size.x * scale * 0.5,
+                                              bg.pos.y + 81.0);
+                        window.draw(&Rectangle::new(pos, size * scale), Img(label));
+                    }
+
                     self.draw_game(window, blocks.as_slice(), &self.client.games[i].field[10..],
                                    Vector::new(4.0, 4.0), bg.pos);
 
@@ -483,6 +808,9 @@ impl<R: Remote + 'static> Scene for Game<R> {
     fn advance(&mut self) -> Option<Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>> {
         if self.return_to_menu {
             Some(super::menu::Menu::new())
+        } else if self.watch_replay {
+            let path = format!("replays/{}", self.client.replay);
+            Some(super::replay::Replay::new(path, self.data.clone()))
         } else {
             None
         }
@@ -0,0 +1,253 @@
+use std::mem::replace;
+
+use quicksilver::{
+    geom::{Transform, Rectangle, Vector},
+    graphics::{Background::Img, Background::Col, Color, Font, FontStyle, Image},
+    input::{ButtonState, GamepadButton, Key, MouseButton},
+    lifecycle::{Window, Event},
+};
+
+/// Characters offered by the on-screen keyboard, one string per row.
+const KEYBOARD: [&str; 4] = [
+    "ABCDEFGHIJ",
+    "KLMNOPQRST",
+    "UVWXYZ0123",
+    "456789_-. ",
+];
+
+/// A single line of editable text. Typing works with a keyboard, or with a gamepad through an
+/// on-screen keyboard that shows up as soon as a gamepad button is pressed while focused.
+pub struct TextInput {
+    rect: Rectangle,
+    menu: usize,
+    max_len: usize,
+    style: FontStyle,
+
+    text: String,
+    caret: usize,
+    focused: bool,
+    blink: f32,
+    changed: bool,
+    submitted: bool,
+
+    rendered: Option<(Image, f32)>,
+    keys: Vec<Image>,
+    keyboard: Option<(usize, usize)>,
+}
+
+impl TextInput {
+    /// Creates an input covering `rect` that's only shown in `menu`.
+    /// No more than `max_len` characters can be entered.
+    pub fn new(rect: Rectangle, menu: usize, max_len: usize, text: &str) -> Self {
+        let text: String = text.chars().take(max_len).collect();
+        Self {
+            rect,
+            menu,
+            max_len,
+            style: FontStyle::new(48.0, Color::WHITE),
+            caret: text.chars().count(),
+            text,
+            focused: false,
+            blink: 0.0,
+            changed: false,
+            submitted: false,
+            rendered: None,
+            keys: Vec::new(),
+            keyboard: None,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        self.text.as_str()
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.chars().take(self.max_len).collect();
+        self.caret = self.text.chars().count();
+        self.rendered = None;
+    }
+
+    pub fn focus(&mut self) {
+        self.focused = true;
+        self.blink = 0.0;
+    }
+
+    /// Returns true once after the text was edited.
+    pub fn changed(&mut self) -> bool {
+        replace(&mut self.changed, false)
+    }
+
+    /// Returns true once after the text was confirmed using enter or the start button.
+    pub fn submitted(&mut self) -> bool {
+        replace(&mut self.submitted, false)
+    }
+
+    pub fn update(&mut self, window: &mut Window, menu: usize) {
+        if menu != self.menu {
+            self.focused = false;
+            self.keyboard = None;
+        }
+        self.blink = (self.blink + window.update_rate() as f32 / 1000.0) % 1.0;
+    }
+
+    pub fn event(&mut self, event: Event, window: &mut Window, menu: usize) {
+        if menu != self.menu {
+            return;
+        }
+
+        match event {
+            Event::MouseButton(MouseButton::Left, ButtonState::Pressed) => {
+                let mouse = window.mouse().pos();
+                self.focused = mouse.x >= self.rect.pos.x &&
+                    mouse.y >= self.rect.pos.y &&
+                    mouse.x < self.rect.pos.x + self.rect.size.x &&
+                    mouse.y < self.rect.pos.y + self.rect.size.y;
+                self.keyboard = None;
+                self.blink = 0.0;
+            },
+            Event::Typed(c) if self.focused && !c.is_control() => {
+                self.insert(c);
+            },
+            Event::Key(key, ButtonState::Pressed) if self.focused => {
+                self.keyboard = None;
+                match key {
+                    Key::Back => self.backspace(),
+                    Key::Delete => {
+                        if self.caret < self.text.chars().count() {
+                            self.caret += 1;
+                            self.backspace();
+                        }
+                    },
+                    Key::Left => self.caret = self.caret.saturating_sub(1),
+                    Key::Right => self.caret = (self.caret + 1).min(self.text.chars().count()),
+                    Key::Home => self.caret = 0,
+                    Key::End => self.caret = self.text.chars().count(),
+                    Key::Return => {
+                        self.submitted = true;
+                        self.focused = false;
+                    },
+                    Key::Escape => self.focused = false,
+                    _ => (),
+                }
+                self.rendered = None;
+                self.blink = 0.0;
+            },
+            Event::GamepadButton(_, button, ButtonState::Pressed) if self.focused => {
+                let (mut row, mut col) = self.keyboard.unwrap_or((0, 0));
+                let rows = KEYBOARD.len();
+                let cols = KEYBOARD[0].len();
+                match button {
+                    GamepadButton::DpadUp => row = (row + rows - 1) % rows,
+                    GamepadButton::DpadDown => row = (row + 1) % rows,
+                    GamepadButton::DpadLeft => col = (col + cols - 1) % cols,
+                    GamepadButton::DpadRight => col = (col + 1) % cols,
+                    GamepadButton::A if self.keyboard.is_some() => {
+                        if let Some(c) = KEYBOARD[row].chars().nth(col) {
+                            self.insert(c);
+                        }
+                    },
+                    GamepadButton::B | GamepadButton::X => self.backspace(),
+                    GamepadButton::Start => {
+                        self.submitted = true;
+                        self.focused = false;
+                    },
+                    _ => (),
+                }
+                self.keyboard = if self.focused { Some((row, col)) } else { None };
+                self.blink = 0.0;
+            },
+            _ => (),
+        }
+    }
+
+    pub fn draw(&mut self, window: &mut Window, font: &Font, menu: usize) {
+        if menu != self.menu {
+            return;
+        }
+
+        let background = if self.focused {
+            Color { r: 0.2, g: 0.2, b: 0.2, a: 1.0 }
+        } else {
+            Color { r: 0.1, g: 0.1, b: 0.1, a: 1.0 }
+        };
+        window.draw_ex(&self.rect, Col(background), Transform::IDENTITY, -1);
+
+        // render the text, remembering where the caret goes
+        if self.rendered.is_none() {
+            let prefix: String = self.text.chars().take(self.caret).collect();
+            let caret = font.render(prefix.as_str(), &self.style)
+                .map(|image| image.area().size.x * 0.5)
+                .unwrap_or(0.0);
+            if let Ok(image) = font.render(self.text.as_str(), &self.style) {
+                self.rendered = Some((image, caret));
+            }
+        }
+
+        let origin = self.rect.pos + Vector::new(6.0, 0.0);
+        let mut caret = 0.0;
+        if let Some((image, x)) = self.rendered.as_ref() {
+            let size = image.area().size * 0.5;
+            let pos = origin + Vector::new(0.0, (self.rect.size.y - size.y) * 0.5);
+            window.draw_ex(&Rectangle::new(pos, size), Img(image), Transform::IDENTITY, 0);
+            caret = *x;
+        }
+
+        if self.focused && self.blink < 0.5 {
+            window.draw_ex(&Rectangle::new(origin + Vector::new(caret, 4.0),
+                                           Vector::new(2.0, self.rect.size.y - 8.0)),
+                           Col(Color::WHITE), Transform::IDENTITY, 1);
+        }
+
+        // render the on-screen keyboard below the input
+        if let Some((row, col)) = self.keyboard {
+            if self.keys.is_empty() {
+                for c in KEYBOARD.iter().flat_map(|r| r.chars()) {
+                    let label = if c == ' ' { "_".to_string() } else { c.to_string() };
+                    self.keys.push(font.render(label.as_str(), &self.style).unwrap());
+                }
+            }
+
+            let cols = KEYBOARD[0].len();
+            for (i, key) in self.keys.iter().enumerate() {
+                let cell = Rectangle::new(self.rect.pos + Vector::new((i % cols) as f32 * 22.0,
+                                                                      self.rect.size.y + 4.0 +
+                                                                          (i / cols) as f32 * 22.0),
+                                          Vector::new(20.0, 20.0));
+                let color = if (i / cols, i % cols) == (row, col) {
+                    Color { r: 1.0, g: 0.1, b: 0.9, a: 1.0 }
+                } else {
+                    Color { r: 0.1, g: 0.1, b: 0.1, a: 1.0 }
+                };
+                window.draw_ex(&cell, Col(color), Transform::IDENTITY, 1);
+
+                let size = key.area().size * 0.4;
+                let pos = cell.pos + (cell.size - size) * 0.5;
+                window.draw_ex(&Rectangle::new(pos, size), Img(key), Transform::IDENTITY, 2);
+            }
+        }
+    }
+
+    fn insert(&mut self, c: char) {
+        if self.text.chars().count() < self.max_len {
+            let at = self.byte_offset(self.caret);
+            self.text.insert(at, c);
+            self.caret += 1;
+            self.changed = true;
+            self.rendered = None;
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.caret > 0 {
+            self.caret -= 1;
+            let at = self.byte_offset(self.caret);
+            self.text.remove(at);
+            self.changed = true;
+            self.rendered = None;
+        }
+    }
+
+    fn byte_offset(&self, caret: usize) -> usize {
+        self.text.char_indices().nth(caret).map(|(i, _)| i).unwrap_or(self.text.len())
+    }
+}
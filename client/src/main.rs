@@ -2,11 +2,12 @@ mod game;
 mod menu;
 mod matchmaking;
 mod util;
-mod connection;
 mod controls;
 mod buttons;
 mod persistent;
 mod stats;
+mod replay;
+mod input;
 
 use quicksilver::{
     Result,
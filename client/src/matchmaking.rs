@@ -1,10 +1,18 @@
 use super::*;
 use crate::game::Game;
-use crate::connection::make_connection;
+use tetris_net::connection::{make_connection, version_mismatch, TlsOptions};
 use crate::persistent::*;
 use mirror::{Remote, Client};
 use tetris_model::matchmaking::MatchmakingState;
+use tetris_model::instance::sanitize_nickname;
 use quicksilver::Future;
+use futures::future::{self, Either};
+
+/// How often connecting to the matchmaker is tried before giving up.
+const CONNECT_ATTEMPTS: u32 = 3;
+
+type Connecting<R> = Box<Future<Item=Client<MatchmakingState, R>, Error=String>>;
+type Connect<R> = Box<Fn() -> Connecting<R>>;
 
 pub trait Matchmaking {
     fn update(&mut self);
@@ -13,54 +21,97 @@ pub trait Matchmaking {
 
     fn status(&self) -> String;
 
+    /// Returns the identity the matchmaker knows us by, once it does.
+    fn identity(&self) -> Option<String>;
+
     fn take(&mut self) -> Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>;
 }
 
 pub enum MatchmakingImpl<R: Remote> {
-    Connecting(Persistent, Box<Future<Item=Client<MatchmakingState, R>, Error=mirror::Error>>),
+    Connecting(Persistent, TlsOptions, String, Connect<R>, u32, Connecting<R>),
+
+    /// Connecting failed, the next attempt is made at the given local time.
+    Retrying(Persistent, TlsOptions, String, Connect<R>, u32, u64, String),
 
-    Waiting(Persistent, Client<MatchmakingState, R>),
+    Waiting(Persistent, TlsOptions, Client<MatchmakingState, R>),
 
     Ok(Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>),
 
-    Error(mirror::Error),
+    Error(String),
 
     Poisoned,
 }
 
 impl<R: Remote + 'static> MatchmakingImpl<R> {
-    pub fn new<F>(client: F, data: Persistent) -> Self where
-        F: 'static + Future<Item=Client<MatchmakingState, R>, Error=mirror::Error>
+    pub fn new<F, C>(connect: F, data: Persistent, tls: TlsOptions, queue: String) -> Self where
+        F: 'static + Fn() -> C,
+        C: 'static + Future<Item=Client<MatchmakingState, R>, Error=String>
     {
-        MatchmakingImpl::Connecting(data, Box::new(client))
+        let connect: Connect<R> = Box::new(move || Box::new(connect()) as Connecting<R>);
+        let future = connect();
+        MatchmakingImpl::Connecting(data, tls, queue, connect, 1, future)
     }
 }
 
 impl<R: Remote + 'static> Matchmaking for MatchmakingImpl<R> {
     fn update(&mut self) {
         let next = match replace(self, MatchmakingImpl::Poisoned) {
-            MatchmakingImpl::Connecting(data, mut future) => {
+            MatchmakingImpl::Connecting(data, tls, queue, connect, attempt, mut future) => {
                 match future.poll() {
-                    Ok(Async::NotReady) => MatchmakingImpl::Connecting(data, future),
-                    Ok(Async::Ready(o)) => MatchmakingImpl::Waiting(data, o),
-                    Err(e) => MatchmakingImpl::Error(e),
+                    Ok(Async::NotReady) => {
+                        MatchmakingImpl::Connecting(data, tls, queue, connect, attempt, future)
+                    },
+                    Ok(Async::Ready(mut o)) => {
+                        let identity: serde_json::Value = data.identity.clone().into();
+                        let nickname = sanitize_nickname(data.nickname.as_str());
+                        let nickname: serde_json::Value = nickname.into();
+                        let queue: serde_json::Value = queue.into();
+                        o.command(format!("call:identify:{} {}", identity, nickname).as_str()).ok();
+                        o.command(format!("call:join:{}", queue).as_str()).ok();
+                        MatchmakingImpl::Waiting(data, tls, o)
+                    },
+                    Err(e) => if attempt < CONNECT_ATTEMPTS && !version_mismatch() {
+                        let retry_at = util::local_millis() + 1000 * attempt as u64;
+                        MatchmakingImpl::Retrying(data, tls, queue, connect, attempt, retry_at, e)
+                    } else {
+                        MatchmakingImpl::Error(e)
+                    },
                 }
             },
-            MatchmakingImpl::Waiting(data, mut client) => {
+            MatchmakingImpl::Retrying(data, tls, queue, connect, attempt, retry_at, e) => {
+                if util::local_millis() >= retry_at {
+                    let future = connect();
+                    MatchmakingImpl::Connecting(data, tls, queue, connect, attempt + 1, future)
+                } else {
+                    MatchmakingImpl::Retrying(data, tls, queue, connect, attempt, retry_at, e)
+                }
+            },
+            MatchmakingImpl::Waiting(mut data, tls, mut client) => {
                 client.update();
+                if !client.identity.is_empty() {
+                    data.identity = client.identity.clone();
+                }
+                if client.rated {
+                    data.rating = client.rating;
+                    data.rating_history = client.rating_history.clone();
+                }
                 if client.done {
-                    let address = format!("{}//{}/instance/{}", util::get_protocol(), util::get_host(),
-                                          client.instance_address);
-                    let game_client = Client::new(make_connection(address.as_str()));
+                    let address = format!("{}/instance/{}", data.server(), client.instance_address);
+                    let connect = move || match make_connection(address.as_str(), &tls) {
+                        Ok(remote) => Either::A(Client::new(remote)),
+                        Err(_) => Either::B(future::err(mirror::Error::ConnectionDropped)),
+                    };
 
-                    MatchmakingImpl::Ok(Game::new(game_client,
+                    MatchmakingImpl::Ok(Game::new(connect,
                                                   client.player_id,
                                                   client.player_key.clone(),
                                                   data))
+                } else if !client.error.is_empty() {
+                    MatchmakingImpl::Error(client.error.clone())
                 } else if !client.alive() {
-                    MatchmakingImpl::Error(mirror::Error::ConnectionDropped)
+                    MatchmakingImpl::Error("Lost the connection to the matchmaker".to_string())
                 } else {
-                    MatchmakingImpl::Waiting(data, client)
+                    MatchmakingImpl::Waiting(data, tls, client)
                 }
             },
             other => other,
@@ -77,14 +128,33 @@ impl<R: Remote + 'static> Matchmaking for MatchmakingImpl<R> {
 
     fn status(&self) -> String {
         match self {
-            &MatchmakingImpl::Connecting(_, _) => "Connecting...".to_string(),
-            &MatchmakingImpl::Waiting(_, ref client) => format!("Matching... {}", client.wait_time),
+            &MatchmakingImpl::Connecting(_, _, _, _, _, _) => "Connecting...".to_string(),
+            &MatchmakingImpl::Retrying(_, _, _, _, _, _, ref e) => format!("{}, retrying...", e),
+            &MatchmakingImpl::Waiting(_, _, ref client) if client.max_players == 0 => {
+                "Matching...".to_string()
+            },
+            &MatchmakingImpl::Waiting(_, _, ref client) => format!("Matching {}/{} ~{}s",
+                                                                  client.players_found,
+                                                                  client.max_players,
+                                                                  client.estimated_wait),
             &MatchmakingImpl::Ok(_) => "Done!".to_string(),
-            &MatchmakingImpl::Error(ref e) => format!("Error: {:?}", e),
+            &MatchmakingImpl::Error(_) if version_mismatch() => {
+                "Please refresh or update the game".to_string()
+            },
+            &MatchmakingImpl::Error(ref e) => format!("Error: {}", e),
             &MatchmakingImpl::Poisoned => panic!(),
         }
     }
 
+    fn identity(&self) -> Option<String> {
+        match self {
+            &MatchmakingImpl::Waiting(_, _, ref client) if !client.identity.is_empty() => {
+                Some(client.identity.clone())
+            },
+            &_ => None,
+        }
+    }
+
     fn take(&mut self) -> Box<Future<Item=Box<Scene>, Error=quicksilver::Error>> {
         match replace(self, MatchmakingImpl::Poisoned) {
             MatchmakingImpl::Ok(result) => result,
@@ -1,17 +1,29 @@
 use super::*;
-use crate::connection::make_connection;
+use tetris_net::connection::{make_connection, Probe, TlsOptions};
 use crate::matchmaking::*;
 use crate::controls::*;
 use crate::persistent::*;
 use crate::buttons::*;
 use crate::stats::*;
+use crate::input::*;
+use tetris_model::matchmaking::MatchmakingState;
+use tetris_model::instance::{sanitize_nickname, MAX_NICKNAME_LEN};
+use futures::future::{self, Either};
 
 use std::collections::HashMap;
 
+/// How long server addresses can be.
+const MAX_ADDRESS_LEN: usize = 64;
+
+/// How many queues fit on the play page.
+const MAX_QUEUES: usize = 3;
+
+type QueueList = Box<Future<Item=Vec<(String, String)>, Error=String>>;
+
 use quicksilver::{
     Result,
     geom::{Transform, Rectangle},
-    graphics::{Background::Img, Color, Font, FontStyle, Image, View},
+    graphics::{Background::Img, Background::Col, Color, Font, FontStyle, Image, View},
     lifecycle::Window,
     combinators::Future,
     saving::{save, load},
@@ -24,7 +36,23 @@ pub struct Menu {
     pattern: Image,
     pattern_timer: f32,
     data: Persistent,
+    tls: TlsOptions,
     control_buttons: HashMap<BindPoint, usize>,
+    queue_buttons: Vec<usize>,
+    queues: Vec<String>,
+    queue_list: Option<QueueList>,
+    queue_back: usize,
+    profile_button: usize,
+    profile_back: usize,
+    nickname: TextInput,
+    server_button: usize,
+    server_rows: Vec<(usize, usize)>,
+    server_texts: Vec<String>,
+    server_add: usize,
+    server_back: usize,
+    server_input: TextInput,
+    current_server: String,
+    probes: HashMap<String, Probe>,
     await_remap: Option<BindPoint>,
     matchmaking: Option<Box<Matchmaking>>,
     current_status: String,
@@ -39,9 +67,17 @@ impl Menu {
         Box::new(font.join(pattern.join(logo)).map(|(font, (pattern, logo))| {
             let button_style = FontStyle::new(48.0, Color::WHITE);
 
-            let data = load("tutris9", "data").unwrap_or(Persistent::default());
+            let mut data = load("tutris9", "data").unwrap_or(Persistent::default());
             println!("{:?}", data.statistics);
 
+            // a server given on the command line is selected, so scripts can pick one
+            if let Some(address) = util::server_arg() {
+                data.server = data.add_server(address);
+            }
+            if data.servers.is_empty() {
+                data.add_server(util::default_server());
+            }
+
             let mut buttons = Buttons::new();
             buttons.push(Button::new(
                 vec![
@@ -155,6 +191,111 @@ impl Menu {
                 i += 1;
             }).unwrap();
 
+            let rating = match data.rating_history.len() {
+                0 => "Unrated".to_string(),
+                1 => format!("Rating: {:.0}", data.rating),
+                n => format!("Rating: {:.0} ({:+.0})", data.rating,
+                             data.rating_history[n - 1] - data.rating_history[n - 2]),
+            };
+            buttons.push(Button::new(
+                vec![util::rect(20.0, 130.0, 150.0, 25.0)],
+                vec![util::rect(20.0, 130.0, 150.0, 25.0)],
+                Color { r: 0.8, g: 0.1, b: 0.4, a: 1.0 }, 2,
+                Some(font.render(rating.as_str(), &button_style).unwrap())));
+
+            // the queues are filled in once the server told us which ones it runs
+            let queue_buttons: Vec<usize> = (0..MAX_QUEUES)
+                .map(|i| {
+                    let y = 130.0 + i as f32 * 32.0;
+                    buttons.push(Button::new(
+                        vec![util::rect(200.0, y, 240.0, 30.0)],
+                        vec![util::rect(180.0, y, 280.0, 30.0)],
+                        Color { r: 1.0, g: 0.1, b: 0.9, a: 1.0 }, HIDDEN,
+                        Some(font.render("", &button_style).unwrap())))
+                })
+                .collect();
+            let queue_back = buttons.push(Button::new(
+                vec![
+                    Rectangle::new(Vector::new(120.0, 240.0), Vector::new(40.0, 40.0)),
+                    Rectangle::new(Vector::new(40.0, 280.0), Vector::new(120.0, 40.0)),
+                ],
+                vec![
+                    Rectangle::new(Vector::new(120.0, 200.0), Vector::new(80.0, 80.0)),
+                    Rectangle::new(Vector::new(-40.0, 280.0), Vector::new(240.0, 80.0)),
+                ],
+                Color { r: 1.0, g: 0.45, b: 0.25, a: 1.0 }, 4,
+                Some(font.render("Back", &button_style).unwrap())));
+
+            let profile_button = buttons.push(Button::new(
+                vec![
+                    Rectangle::new(Vector::new(360.0, 160.0), Vector::new(40.0, 80.0)),
+                    Rectangle::new(Vector::new(400.0, 200.0), Vector::new(40.0, 40.0)),
+                ],
+                vec![
+                    Rectangle::new(Vector::new(360.0, 80.0), Vector::new(80.0, 160.0)),
+                    Rectangle::new(Vector::new(440.0, 160.0), Vector::new(80.0, 80.0)),
+                ],
+                Color { r: 0.1, g: 0.8, b: 1.0, a: 1.0 }, 0,
+                Some(font.render("Profile", &button_style).unwrap())));
+            buttons.push(Button::new(
+                vec![util::rect(200.0, 130.0, 240.0, 25.0)],
+                vec![util::rect(200.0, 130.0, 240.0, 25.0)],
+                Color { r: 0.1, g: 0.1, b: 0.8, a: 1.0 }, 5,
+                Some(font.render("Nickname", &button_style).unwrap())));
+            let profile_back = buttons.push(Button::new(
+                vec![
+                    Rectangle::new(Vector::new(120.0, 240.0), Vector::new(40.0, 40.0)),
+                    Rectangle::new(Vector::new(40.0, 280.0), Vector::new(120.0, 40.0)),
+                ],
+                vec![
+                    Rectangle::new(Vector::new(120.0, 200.0), Vector::new(80.0, 80.0)),
+                    Rectangle::new(Vector::new(-40.0, 280.0), Vector::new(240.0, 80.0)),
+                ],
+                Color { r: 1.0, g: 0.45, b: 0.25, a: 1.0 }, 5,
+                Some(font.render("Back", &button_style).unwrap())));
+            let nickname = TextInput::new(util::rect(200.0, 160.0, 240.0, 30.0), 5,
+                                          MAX_NICKNAME_LEN, data.nickname.as_str());
+
+            let server_button = buttons.push(Button::new(
+                vec![util::rect(200.0, 230.0, 240.0, 30.0)],
+                vec![util::rect(180.0, 230.0, 280.0, 30.0)],
+                Color { r: 0.1, g: 0.1, b: 0.8, a: 1.0 }, 4,
+                Some(font.render("", &button_style).unwrap())));
+            let server_rows = (0..MAX_SERVERS)
+                .map(|i| {
+                    let y = 130.0 + i as f32 * 30.0;
+                    let select = buttons.push(Button::new(
+                        vec![util::rect(200.0, y, 280.0, 25.0)],
+                        vec![util::rect(190.0, y, 300.0, 25.0)],
+                        Color { r: 0.1, g: 0.1, b: 0.8, a: 1.0 }, HIDDEN,
+                        Some(font.render("", &button_style).unwrap())));
+                    let remove = buttons.push(Button::new(
+                        vec![util::rect(490.0, y, 70.0, 25.0)],
+                        vec![util::rect(490.0, y, 80.0, 25.0)],
+                        Color { r: 1.0, g: 0.45, b: 0.25, a: 1.0 }, HIDDEN,
+                        Some(font.render("Remove", &button_style).unwrap())));
+                    (select, remove)
+                })
+                .collect();
+            let server_add = buttons.push(Button::new(
+                vec![util::rect(490.0, 250.0, 70.0, 25.0)],
+                vec![util::rect(490.0, 250.0, 80.0, 25.0)],
+                Color { r: 0.2, g: 1.0, b: 0.1, a: 1.0 }, 6,
+                Some(font.render("Add", &button_style).unwrap())));
+            let server_back = buttons.push(Button::new(
+                vec![
+                    Rectangle::new(Vector::new(120.0, 240.0), Vector::new(40.0, 40.0)),
+                    Rectangle::new(Vector::new(40.0, 280.0), Vector::new(120.0, 40.0)),
+                ],
+                vec![
+                    Rectangle::new(Vector::new(120.0, 200.0), Vector::new(80.0, 80.0)),
+                    Rectangle::new(Vector::new(-40.0, 280.0), Vector::new(240.0, 80.0)),
+                ],
+                Color { r: 1.0, g: 0.45, b: 0.25, a: 1.0 }, 6,
+                Some(font.render("Back", &button_style).unwrap())));
+            let server_input = TextInput::new(util::rect(200.0, 250.0, 280.0, 25.0), 6,
+                                              MAX_ADDRESS_LEN, "");
+
             Box::new(Self {
                 font,
                 logo,
@@ -162,13 +303,110 @@ impl Menu {
                 pattern,
                 pattern_timer: 0.0,
                 data,
+                tls: util::tls_args(),
                 control_buttons,
+                queue_buttons,
+                queues: Vec::new(),
+                queue_list: None,
+                queue_back,
+                profile_button,
+                profile_back,
+                nickname,
+                server_button,
+                server_rows,
+                server_texts: vec![String::new(); MAX_SERVERS],
+                server_add,
+                server_back,
+                server_input,
+                current_server: String::new(),
+                probes: HashMap::new(),
                 await_remap: None,
                 matchmaking: None,
                 current_status: "".to_string(),
             }) as Box<Scene>
         }))
     }
+
+    /// Asks the selected server which queues it runs.
+    fn fetch_queues(&mut self) {
+        let address = format!("{}/instance/matchmaking", self.data.server());
+        self.queue_list = Some(match make_connection(address.as_str(), &self.tls) {
+            Ok(remote) => Box::new(mirror::Client::<MatchmakingState, _>::new(remote)
+                .map(|client| client.queues.clone())
+                .map_err(|e| format!("{:?}", e))),
+            Err(e) => Box::new(future::err(e)),
+        });
+        self.show_queues(Ok(Vec::new()));
+    }
+
+    /// Shows the queues of the selected server. While they're not known the first button tells
+    /// why, and can't be picked.
+    fn show_queues(&mut self, queues: std::result::Result<Vec<(String, String)>, String>) {
+        let style = FontStyle::new(48.0, Color::WHITE);
+        let (titles, status) = match queues {
+            Ok(ref queues) if queues.is_empty() && self.queue_list.is_some() => {
+                (Vec::new(), "Loading...".to_string())
+            },
+            Ok(ref queues) if queues.is_empty() => (Vec::new(), "No queues".to_string()),
+            Ok(queues) => {
+                self.queues = queues.iter().map(|(name, _)| name.clone()).collect();
+                (queues.into_iter().map(|(_, title)| title).collect(), String::new())
+            },
+            Err(e) => (Vec::new(), e),
+        };
+        if titles.is_empty() {
+            self.queues.clear();
+        }
+
+        for (i, &button) in self.queue_buttons.iter().enumerate() {
+            let text = match titles.get(i) {
+                Some(title) => title.clone(),
+                None if i == 0 => status.clone(),
+                None => String::new(),
+            };
+            self.buttons[button].set_menu(if text.is_empty() { HIDDEN } else { 4 });
+            self.buttons[button].set_text(Some(self.font.render(text.as_str(), &style)
+                .unwrap()));
+        }
+    }
+
+    /// Shows the saved servers along with what probing them found out.
+    fn update_servers(&mut self) {
+        let style = FontStyle::new(32.0, Color::WHITE);
+
+        for (i, &(select, remove)) in self.server_rows.iter().enumerate() {
+            let text = match self.data.servers.get(i) {
+                Some(address) => {
+                    let status = match self.probes.get_mut(address).and_then(|p| p.poll()) {
+                        Some(Ok(ms)) => format!("{} ms", ms),
+                        Some(Err(e)) => e.clone(),
+                        None => "...".to_string(),
+                    };
+                    let selected = if i == self.data.server { "> " } else { "" };
+                    format!("{}{} {}", selected, address, status)
+                },
+                None => String::new(),
+            };
+
+            if text != self.server_texts[i] {
+                self.buttons[select].set_menu(if text.is_empty() { HIDDEN } else { 6 });
+                self.buttons[select].set_text(Some(self.font.render(text.as_str(), &style)
+                    .unwrap()));
+                self.server_texts[i] = text;
+            }
+
+            // the last server can't be removed, there has to be one to play on
+            let removable = i < self.data.servers.len() && self.data.servers.len() > 1;
+            self.buttons[remove].set_menu(if removable { 6 } else { HIDDEN });
+        }
+
+        let server = format!("Server: {}", self.data.server());
+        if server != self.current_server {
+            self.buttons[self.server_button].set_text(Some(self.font.render(server.as_str(),
+                                                                            &style).unwrap()));
+            self.current_server = server;
+        }
+    }
 }
 
 impl Drop for Menu {
@@ -182,6 +420,16 @@ impl Scene for Menu {
         if self.await_remap.is_none() {
             self.buttons.update(window);
         }
+        self.nickname.update(window, self.buttons.menu());
+        self.server_input.update(window, self.buttons.menu());
+
+        // the identity ties our rating on the server to this installation, the matchmaker
+        // issues one the first time we play
+        let identity = self.matchmaking.as_ref().and_then(|mm| mm.identity());
+        if let Some(identity) = identity.filter(|identity| *identity != self.data.identity) {
+            self.data.identity = identity;
+            save("tutris9", "data", &self.data).ok();
+        }
 
         if let Some(status) = self.matchmaking.as_ref().map(|mm| mm.status()) {
             if status.as_str() != self.current_status.as_str() {
@@ -204,21 +452,120 @@ impl Scene for Menu {
 
         // process the play button
         if self.buttons[2].clicked() {
-            self.buttons.set_menu(1);
+            self.buttons.set_menu(4);
+            self.fetch_queues();
+        }
 
-            let address = format!("{}//{}/instance/0", util::get_protocol(), util::get_host());
-            let client = mirror::Client::new(make_connection(address.as_str()));
-            self.matchmaking = Some(Box::new(MatchmakingImpl::new(client, self.data.clone())));
+        let polled = self.queue_list.as_mut().map(|list| list.poll());
+        match polled {
+            Some(Ok(Async::Ready(queues))) => {
+                self.queue_list = None;
+                self.show_queues(Ok(queues));
+            },
+            Some(Err(e)) => {
+                self.queue_list = None;
+                self.show_queues(Err(e));
+            },
+            _ => (),
         }
 
+        // process the profile button
+        if self.buttons[self.profile_button].clicked() {
+            self.buttons.set_menu(5);
+            self.nickname.focus();
+        }
+
+        // store the nickname once it's confirmed or the profile page is left
+        if self.nickname.submitted() || self.buttons[self.profile_back].clicked() {
+            let nickname = sanitize_nickname(self.nickname.text());
+            self.nickname.set_text(nickname.as_str());
+            self.data.nickname = nickname;
+            save("tutris9", "data", &self.data).ok();
+            self.buttons.set_menu(0);
+        }
+
+        // process the queue buttons
+        for (btn, queue) in self.queue_buttons.iter().zip(self.queues.iter()) {
+            if self.buttons[*btn].clicked() {
+                self.buttons.set_menu(1);
+
+                let address = format!("{}/instance/matchmaking", self.data.server());
+                let tls = self.tls.clone();
+                let connect = move || match make_connection(address.as_str(), &tls) {
+                    Ok(remote) => Either::A(mirror::Client::new(remote)
+                        .map_err(|e| format!("{:?}", e))),
+                    Err(e) => Either::B(future::err(e)),
+                };
+                self.matchmaking = Some(Box::new(MatchmakingImpl::new(connect,
+                                                                      self.data.clone(),
+                                                                      self.tls.clone(),
+                                                                      queue.clone())));
+            }
+        }
+
+        // process the server button, every server is probed again whenever the page opens
+        if self.buttons[self.server_button].clicked() {
+            self.buttons.set_menu(6);
+            let tls = &self.tls;
+            self.probes = self.data.servers
+                .iter()
+                .map(|address| {
+                    let uri = format!("{}/instance/matchmaking", address);
+                    (address.clone(), Probe::new(uri.as_str(), tls))
+                })
+                .collect();
+        }
+
+        // process the server list
+        for (i, &(select, remove)) in self.server_rows.iter().enumerate() {
+            if self.buttons[select].clicked() && i < self.data.servers.len() {
+                self.data.server = i;
+                save("tutris9", "data", &self.data).ok();
+            }
+            if self.buttons[remove].clicked() && i < self.data.servers.len() {
+                self.probes.remove(&self.data.servers[i]);
+                self.data.remove_server(i);
+                save("tutris9", "data", &self.data).ok();
+            }
+        }
+
+        // add the typed server, or point out it's not an address
+        let button_style = FontStyle::new(48.0, Color::WHITE);
+        if self.server_input.changed() {
+            self.buttons[self.server_add].set_text(Some(self.font.render("Add", &button_style)
+                .unwrap()));
+        }
+        let submitted = self.server_input.submitted();
+        if self.buttons[self.server_add].clicked() || submitted {
+            match util::normalize_address(self.server_input.text()) {
+                Some(address) => {
+                    let uri = format!("{}/instance/matchmaking", address);
+                    self.probes.insert(address.clone(), Probe::new(uri.as_str(), &self.tls));
+                    self.data.server = self.data.add_server(address);
+                    self.server_input.set_text("");
+                    save("tutris9", "data", &self.data).ok();
+                },
+                None => {
+                    let text = self.font.render("Invalid", &button_style).unwrap();
+                    self.buttons[self.server_add].set_text(Some(text));
+                },
+            }
+        }
+
+        if self.buttons[self.server_back].clicked() {
+            self.buttons.set_menu(4);
+            self.fetch_queues();
+        }
+        self.update_servers();
+
         // process the matchmaking cancel button
         if self.buttons[3].clicked() {
             self.matchmaking = None;
             self.buttons.set_menu(0);
         }
 
-        // process the back buttons (4 and 5)
-        for i in 4..=5 {
+        // process the back buttons
+        for &i in [4, 5, self.queue_back].iter() {
             if self.buttons[i].clicked() {
                 self.buttons.set_menu(0);
             }
@@ -251,6 +598,8 @@ impl Scene for Menu {
                 self.await_remap = None;
             }
         } else {
+            self.nickname.event(*event, window, self.buttons.menu());
+            self.server_input.event(*event, window, self.buttons.menu());
             self.buttons.event(*event, window);
         }
 
@@ -273,6 +622,24 @@ impl Scene for Menu {
 
         // buttons
         self.buttons.draw(window);
+        self.nickname.draw(window, &self.font, self.buttons.menu());
+        self.server_input.draw(window, &self.font, self.buttons.menu());
+
+        // rating history on the stats page
+        if self.buttons.menu() == 2 && self.data.rating_history.len() > 1 {
+            let history = &self.data.rating_history[self.data.rating_history.len().max(20) - 20..];
+            let min = history.iter().cloned().fold(std::f64::MAX, f64::min) - 10.0;
+            let max = history.iter().cloned().fold(std::f64::MIN, f64::max) + 10.0;
+            let width = 150.0 / history.len() as f32;
+
+            for (i, &rating) in history.iter().enumerate() {
+                let height = ((rating - min) / (max - min) * 100.0) as f32;
+                window.draw_ex(&util::rect(20.0 + width * i as f32, 260.0 - height,
+                                           width - 1.0, height),
+                               Col(Color { r: 0.8, g: 0.1, b: 0.4, a: 1.0 }),
+                               Transform::IDENTITY, 0);
+            }
+        }
 
         Ok(())
     }
@@ -1,4 +1,5 @@
 use crate::controls::ControlMap;
+use crate::util;
 
 use serde::*;
 
@@ -7,6 +8,47 @@ pub struct Persistent {
     pub nickname: String,
     pub controls: ControlMap,
     pub statistics: Statistics,
+    #[serde(default)]
+    pub identity: String,
+    #[serde(default)]
+    pub rating: f64,
+    #[serde(default)]
+    pub rating_history: Vec<f64>,
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub server: usize,
+}
+
+/// How many servers can be saved.
+pub const MAX_SERVERS: usize = 4;
+
+impl Persistent {
+    /// Returns the address of the selected server.
+    pub fn server(&self) -> String {
+        self.servers.get(self.server).cloned().unwrap_or_else(util::default_server)
+    }
+
+    /// Adds a server unless it's already saved, making room by forgetting the last one.
+    /// Returns where it is in the list.
+    pub fn add_server(&mut self, address: String) -> usize {
+        if let Some(i) = self.servers.iter().position(|s| *s == address) {
+            return i;
+        }
+        self.servers.truncate(MAX_SERVERS - 1);
+        self.servers.push(address);
+        self.servers.len() - 1
+    }
+
+    /// Forgets a server, the one after it is selected if it was the selected one.
+    pub fn remove_server(&mut self, i: usize) {
+        if i < self.servers.len() {
+            self.servers.remove(i);
+            if self.server > i || self.server >= self.servers.len() {
+                self.server = self.server.saturating_sub(1);
+            }
+        }
+    }
 }
 
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
@@ -0,0 +1,226 @@
+use super::*;
+use crate::game::Game;
+use crate::persistent::Persistent;
+use mirror::{Remote, Client};
+use tetris_model::instance::InstanceState;
+use tetris_model::replay::*;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{Error as IoError, ErrorKind};
+
+use quicksilver::{
+    Future,
+    Result,
+    load_file,
+    geom::Rectangle,
+    graphics::{Background::Img, Color, Font, FontStyle, Image},
+    input::{ButtonState, Key},
+    lifecycle::Window,
+};
+use futures::future::err;
+
+struct Playback {
+    entries: Vec<ReplayEntry>,
+    position: usize,
+    clock: f64,
+}
+
+/// A remote that hands out the recorded messages of a replay as soon as the playback clock
+/// reaches their timestamp.
+pub struct ReplayRemote {
+    playback: Rc<RefCell<Playback>>,
+}
+
+pub struct Replay {
+    game: Game<ReplayRemote>,
+    playback: Rc<RefCell<Playback>>,
+    seeking: Option<Box<Future<Item=Client<InstanceState, ReplayRemote>, Error=mirror::Error>>>,
+    speed: f64,
+    paused: bool,
+    return_to_menu: bool,
+
+    font: Font,
+    status_style: FontStyle,
+    status: Option<(Image, String)>,
+}
+
+impl Playback {
+    fn due(&self) -> bool {
+        self.entries
+            .get(self.position)
+            .map(|e| e.time as f64 <= self.clock)
+            .unwrap_or(false)
+    }
+
+    fn start(&self) -> f64 {
+        self.entries.first().map(|e| e.time as f64).unwrap_or(0.0)
+    }
+
+    fn end(&self) -> f64 {
+        self.entries.last().map(|e| e.time as f64).unwrap_or(0.0)
+    }
+}
+
+impl Remote for ReplayRemote {
+    fn close(&mut self) { }
+
+    fn alive(&self) -> bool {
+        true
+    }
+
+    fn send(&mut self, _: &str) -> ::std::result::Result<(), mirror::Error> {
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        let mut playback = self.playback.borrow_mut();
+        if playback.due() {
+            playback.position += 1;
+            Some(playback.entries[playback.position - 1].message.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Replay {
+    pub fn new(path: String, data: Persistent)
+        -> Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>
+    {
+        let font = Font::load("font.ttf");
+
+        Box::new(load_file(path).join(font).and_then(move |(bytes, font)|
+            -> Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>
+        {
+            let parsed = String::from_utf8(bytes)
+                .map_err(|e| e.to_string())
+                .and_then(|text| parse(text.as_str()).map_err(|e| e.to_string()));
+
+            let entries = match parsed {
+                Ok((_, entries)) => entries,
+                Err(e) => {
+                    let e = IoError::new(ErrorKind::InvalidData, e);
+                    return Box::new(err(quicksilver::Error::IOError(e)));
+                },
+            };
+
+            let playback = Rc::new(RefCell::new(Playback { entries, position: 0, clock: 0.0 }));
+            let start = playback.borrow().start();
+            playback.borrow_mut().clock = start;
+
+            let client = Client::new(ReplayRemote { playback: playback.clone() });
+
+            Box::new(Game::spectate(client, 0, data).map(move |game| {
+                Box::new(Self {
+                    game,
+                    playback,
+                    seeking: None,
+                    speed: 1.0,
+                    paused: false,
+                    return_to_menu: false,
+                    font,
+                    status_style: FontStyle::new(32.0, Color::WHITE),
+                    status: None,
+                }) as Box<Scene>
+            }))
+        }))
+    }
+
+    /// Moves the playback clock to `time`. Seeking backwards replays the recording from the
+    /// initial state sync, since mirrored commands can't be undone.
+    fn seek(&mut self, time: f64) {
+        let mut playback = self.playback.borrow_mut();
+        let time = time.max(playback.start()).min(playback.end());
+
+        if time < playback.clock {
+            playback.position = 0;
+            playback.clock = time;
+            self.seeking = Some(Box::new(Client::new(ReplayRemote {
+                playback: self.playback.clone(),
+            })));
+        } else {
+            playback.clock = time;
+        }
+    }
+
+    fn status_text(&self) -> String {
+        let playback = self.playback.borrow();
+        let clock = ((playback.clock - playback.start()) / 1000.0) as u64;
+        let end = ((playback.end() - playback.start()) / 1000.0) as u64;
+        let state = if self.paused { "Paused".to_string() } else { format!("x{}", self.speed) };
+
+        format!("{}  {:02}:{:02} / {:02}:{:02}", state, clock / 60, clock % 60, end / 60, end % 60)
+    }
+}
+
+impl Scene for Replay {
+    fn update(&mut self, window: &mut Window) -> Result<()> {
+        if let Some(mut seeking) = self.seeking.take() {
+            match seeking.poll() {
+                Ok(Async::Ready(client)) => self.game.set_client(client),
+                Ok(Async::NotReady) => self.seeking = Some(seeking),
+                Err(_) => self.return_to_menu = true,
+            }
+        } else if !self.paused {
+            let mut playback = self.playback.borrow_mut();
+            let end = playback.end();
+            playback.clock = (playback.clock + window.update_rate() * self.speed).min(end);
+        }
+
+        if self.seeking.is_none() {
+            // apply everything up to the playback clock in one go
+            while self.playback.borrow().due() {
+                self.game.client_mut().update();
+            }
+            self.game.update(window)?;
+        }
+
+        let text = self.status_text();
+        if self.status.as_ref().map(|(_, s)| s.as_str() != text.as_str()).unwrap_or(true) {
+            let image = self.font.render(text.as_str(), &self.status_style)?;
+            self.status = Some((image, text));
+        }
+
+        Ok(())
+    }
+
+    fn event(&mut self, event: &Event, window: &mut Window) -> Result<()> {
+        let clock = self.playback.borrow().clock;
+
+        match event {
+            &Event::Key(Key::Space, ButtonState::Pressed) => self.paused = !self.paused,
+            &Event::Key(Key::Left, ButtonState::Pressed) => self.seek(clock - 10_000.0),
+            &Event::Key(Key::Right, ButtonState::Pressed) => self.seek(clock + 10_000.0),
+            &Event::Key(Key::Up, ButtonState::Pressed) => self.speed = (self.speed * 2.0).min(16.0),
+            &Event::Key(Key::Down, ButtonState::Pressed) => self.speed = (self.speed * 0.5).max(0.25),
+            &Event::Key(Key::Tab, ButtonState::Pressed) => {
+                let next = (self.game.player_id() + 1) % self.game.client().games.len();
+                self.game.follow(next);
+            },
+            &Event::Key(Key::Escape, ButtonState::Pressed) => self.return_to_menu = true,
+            _ => (),
+        }
+
+        self.game.event(event, window)
+    }
+
+    fn draw(&mut self, window: &mut Window) -> Result<()> {
+        self.game.draw(window)?;
+
+        if let Some((image, _)) = self.status.as_ref() {
+            let size = image.area().size;
+            window.draw_ex(&Rectangle::new(Vector::new(320.0 - size.x * 0.25, 336.0), size * 0.5),
+                           Img(image), Transform::IDENTITY, 2);
+        }
+
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Option<Box<Future<Item=Box<Scene>, Error=quicksilver::Error>>> {
+        if self.return_to_menu {
+            Some(super::menu::Menu::new())
+        } else {
+            self.game.advance()
+        }
+    }
+}
@@ -12,6 +12,12 @@ use stdweb::web::window;
 
 #[cfg(not(target_arch="wasm32"))]
 use std::env::args;
+#[cfg(not(target_arch="wasm32"))]
+use std::path::PathBuf;
+
+use tetris_net::connection::TlsOptions;
+
+pub use tetris_net::local_millis;
 
 pub fn add_seconds(duration: &mut Duration, seconds: f64) {
     let secs = Duration::from_secs(seconds as u64);
@@ -19,37 +25,90 @@ pub fn add_seconds(duration: &mut Duration, seconds: f64) {
     *duration += secs + nanos;
 }
 
-#[cfg(target_arch="wasm32")]
-pub fn get_protocol() -> String {
-    let proto = window().location().unwrap().protocol().unwrap();
-    if proto == "http:" {
-        String::from("ws:")
-    } else {
-        String::from("wss:")
+/// The port the server listens on by default, assumed for `ws://` addresses without a port.
+const DEFAULT_PORT: u16 = 3000;
+
+/// Turns what the player typed into the address of a server, like `ws://localhost:3000`.
+/// The protocol defaults to `ws://`, and so does the port to the server's default for `ws://`.
+/// `wss://` addresses without a port use the standard one. Returns `None` if it's not an address.
+pub fn normalize_address(address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    let (protocol, rest) = match address.find("//") {
+        Some(i) => (&address[..i], &address[i + 2..]),
+        None => ("ws:", address.as_str()),
+    };
+    if protocol != "ws:" && protocol != "wss:" {
+        return None;
     }
+
+    let host = rest.split('/').next().unwrap_or("");
+    let (name, port) = match host.rfind(':') {
+        Some(i) if !host.ends_with(']') => (&host[..i], Some(host[i + 1..].parse::<u16>().ok()?)),
+        _ => (host, None),
+    };
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace()) {
+        return None;
+    }
+
+    match (protocol, port) {
+        (_, Some(port)) => Some(format!("{}//{}:{}", protocol, name, port)),
+        ("ws:", None) => Some(format!("{}//{}:{}", protocol, name, DEFAULT_PORT)),
+        (_, None) => Some(format!("{}//{}", protocol, name)),
+    }
+}
+
+/// Returns the server the game was loaded from.
+#[cfg(target_arch="wasm32")]
+pub fn default_server() -> String {
+    let location = window().location().unwrap();
+    let protocol = if location.protocol().unwrap() == "http:" { "ws:" } else { "wss:" };
+    format!("{}//{}", protocol, location.host().unwrap())
 }
 
+/// Returns the server given on the command line, or one running on this machine.
 #[cfg(not(target_arch="wasm32"))]
-pub fn get_protocol() -> String {
-    args()
-        .skip(1)
-        .next()
-        .and_then(|arg| arg.split("//").next().map(|s| s.to_string()))
-        .unwrap_or("ws:".to_string())
+pub fn default_server() -> String {
+    server_arg().unwrap_or(format!("ws://localhost:{}", DEFAULT_PORT))
 }
 
 #[cfg(target_arch="wasm32")]
-pub fn get_host() -> String {
-    window().location().unwrap().host().unwrap()
+pub fn server_arg() -> Option<String> {
+    None
 }
 
+/// Returns the server given on the command line, either as `--server=ADDRESS` or as the first
+/// argument that isn't an option.
 #[cfg(not(target_arch="wasm32"))]
-pub fn get_host() -> String {
-    args()
-        .skip(1)
-        .next()
-        .and_then(|arg| arg.split("//").skip(1).next().map(|s| s.to_string()))
-        .unwrap_or("localhost".to_string())
+pub fn server_arg() -> Option<String> {
+    let arg = args().skip(1).find(|arg| arg.starts_with("--server="))
+        .map(|arg| arg["--server=".len()..].to_string())
+        .or_else(|| args().skip(1).find(|arg| !arg.starts_with("--")))?;
+
+    let address = normalize_address(arg.as_str());
+    if address.is_none() {
+        println!("Ignoring invalid server address {}", arg);
+    }
+    address
+}
+
+#[cfg(target_arch="wasm32")]
+pub fn tls_args() -> TlsOptions {
+    TlsOptions::default()
+}
+
+/// Returns how `wss://` servers are checked, `--ca=FILE` trusts another certificate authority
+/// and `--accept-invalid-certs` trusts anything.
+#[cfg(not(target_arch="wasm32"))]
+pub fn tls_args() -> TlsOptions {
+    let mut options = TlsOptions::default();
+    for arg in args().skip(1) {
+        if arg.starts_with("--ca=") {
+            options.ca = Some(PathBuf::from(&arg["--ca=".len()..]));
+        } else if arg == "--accept-invalid-certs" {
+            options.accept_invalid_certs = true;
+        }
+    }
+    options
 }
 
 pub fn draw_pattern(timer: f32, pattern: &Image, view: Rectangle, window: &mut Window) {
@@ -1,7 +1,7 @@
 use serde::*;
 use mirror::*;
 use std::iter::{repeat, repeat_with};
-use std::time::{Instant, Duration};
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
 use std::mem::replace;
 use rand::{SeedableRng, random, thread_rng};
 use rand::rngs::StdRng;
@@ -9,28 +9,77 @@ use rand::seq::IteratorRandom;
 use rand::seq::SliceRandom;
 use serde_json::Value;
 
+/// How long the countdown before the start of a match takes. Once the start is this close, it
+/// isn't moved anymore when players arrive.
+pub const COUNTDOWN: Duration = Duration::from_secs(3);
+
+/// Extra time players get to place a piece before the server places it for them, on top of the
+/// time it takes gravity to lock it, so a slow connection doesn't cost anyone a piece.
+pub const LATENCY_ALLOWANCE: Duration = Duration::from_secs(1);
+
+/// The longest nickname that is accepted, in characters.
+pub const MAX_NICKNAME_LEN: usize = 16;
+
+/// Cleans up a nickname picked by a player. Nicknames are passed as call arguments, which are
+/// separated by spaces, so whitespace is replaced by underscores. Apart from that only ascii
+/// letters, digits and a few punctuation marks are kept and the result is cut to
+/// `MAX_NICKNAME_LEN` characters. May return an empty string.
+pub fn sanitize_nickname(nickname: &str) -> String {
+    nickname
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || "_-.".contains(*c))
+        .take(MAX_NICKNAME_LEN)
+        .collect()
+}
+
 pub struct ServerState {
     pub players: Vec<String>,
     pub awaiting: Vec<String>,
     pub deadline: Instant,
+    /// The number of the last drop or hold of every player, whether it had any effect or not.
+    pub inputs: Vec<usize>,
+    /// When the current tetrimino of every player came into play.
+    pub piece_since: Vec<Instant>,
 }
 
 #[ReflectFn(
     Fn(name="server_update", args="0"),
-    Fn(name="login", args="1"),
-    Fn(name="drop", args="2"),
+    Fn(name="login", args="2"),
+    Fn(name="drop", args="4"),
     Fn(name="target", args="2"),
-    Fn(name="hold", args="1"),
+    Fn(name="hold", args="2"),
+    Fn(name="forfeit", args="2"),
 )]
 #[derive(Serialize, Deserialize, Reflect)]
 pub struct InstanceState {
     pub state: Hidden<ServerState>,
     pub games: Vec<PlayerState>,
     pub games_ko: Vec<usize>,
+    pub games_ko_reasons: Vec<String>,
     pub status: String,
     pub started: bool,
     pub done: bool,
     pub speed: u64,
+    pub replay: String,
+    /// When the match starts, in milliseconds since the unix epoch on the server's clock.
+    #[serde(default)]
+    pub start_at: u64,
+    /// The answer to the last `clock` call of this connection, the client's time that was sent
+    /// along with the call and the server's time when it was answered, both in milliseconds.
+    /// This is set by the server for every connection separately.
+    #[serde(default)]
+    pub clock: (u64, u64),
+    /// How many times this connection got a full copy of the player's own state after asking
+    /// for one. This is set by the server for every connection separately.
+    #[serde(default)]
+    pub resynced: usize,
+    /// How many seconds a disconnected player keeps their seat before the match goes on without
+    /// them. Clients stop trying to get back in after this.
+    #[serde(default)]
+    pub disconnect_timeout: u64,
 }
 
 #[ReflectFn(
@@ -55,6 +104,15 @@ pub struct PlayerState {
     pub lines_cleared: usize,
     pub garbage_sent: usize,
     pub garbage_received: usize,
+    pub rating: f64,
+    pub nickname: String,
+    /// The number of the last input the server applied and the `state_hash` after applying it.
+    /// Published every now and then, so clients can check their predictions.
+    #[serde(default)]
+    pub checksum: (usize, u32),
+    /// How many tetriminos the server placed because the player took too long to place them.
+    #[serde(default)]
+    pub forced: usize,
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -74,39 +132,217 @@ impl ServerState {
     }
 }
 
+/// Returns the current time in milliseconds since the unix epoch.
+/// This doesn't work in the browser, the web client has to get its time elsewhere.
+pub fn unix_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+/// Converts an instant in the near future to milliseconds since the unix epoch.
+fn instant_millis(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let left = if instant > now { instant - now } else { Duration::from_secs(0) };
+    unix_millis() + left.as_secs() * 1000 + left.subsec_millis() as u64
+}
+
 impl InstanceState {
     pub fn new(players: Vec<String>) -> Self {
+        let deadline = Instant::now() + Duration::from_secs(10);
         Self {
             state: Hidden::new(ServerState {
                 players: players.clone(),
                 awaiting: players.clone(),
-                deadline: Instant::now() + Duration::from_secs(10),
+                deadline,
+                inputs: players.iter().map(|_| 0).collect(),
+                piece_since: players.iter().map(|_| deadline).collect(),
             }),
             games: players
                 .iter()
                 .map(|_| PlayerState::new())
                 .collect(),
             games_ko: Vec::new(),
+            games_ko_reasons: Vec::new(),
             status: String::from("Waiting for players.."),
             started: false,
             done: false,
             speed: 750,
+            replay: String::new(),
+            resynced: 0,
+            start_at: instant_millis(deadline),
+            clock: (0, 0),
+            disconnect_timeout: 30,
         }
     }
 
-    pub fn player_ko<C: Context>(&mut self, context: &mut C, player_key: &str) {
-        let index = self.state.player_index(player_key).unwrap();
+    /// Knocks out the player at `index`. The reason ends up in `games_ko_reasons`, next to the
+    /// player's entry in `games_ko`.
+    pub fn player_ko<C: Context>(&mut self, context: &mut C, index: usize, reason: &str) {
+        let reason: Value = reason.into();
         context.command(self, format!("games/{}/ko/set:true", index).as_str()).unwrap();
         context.command(self, format!("games_ko/push:{}", index).as_str()).unwrap();
+        context.command(self, format!("games_ko_reasons/push:{}", reason).as_str()).unwrap();
+    }
+
+    /// Returns the player indices ordered by their final position, winner first.
+    pub fn standings(&self) -> Vec<usize> {
+        let mut standings: Vec<usize> = (0..self.games.len())
+            .filter(|i| !self.games_ko.contains(i))
+            .collect();
+        standings.extend(self.games_ko.iter().rev());
+        standings
     }
 
     pub fn in_game(&self, player: usize) -> bool {
-        self.started &&
-            !self.done &&
+        self.started && self.playing(player)
+    }
+
+    /// Like `in_game`, but the match counts as started from `start_at` on, `now` being the time
+    /// on the server's clock. This lets every client start at the same moment, instead of
+    /// whenever the news that the match started reaches them.
+    pub fn in_game_at(&self, player: usize, now: u64) -> bool {
+        (self.started || (self.start_at > 0 && now >= self.start_at)) && self.playing(player)
+    }
+
+    fn playing(&self, player: usize) -> bool {
+        !self.done &&
             !self.games[player].ko &&
             self.games_ko.len() != self.games.len() - 1
     }
 
+    /// Returns how long player `id` may take to place their current tetrimino. Gravity moves it
+    /// a row down at every step and locks it at the step after it hits the ground, and the player
+    /// may have steered it to wherever it falls the longest.
+    fn lock_time(&self, id: usize) -> Duration {
+        let spawn = ActiveState::new().y;
+        let deepest = self.games[id].landings().iter().map(|state| state.y).max().unwrap_or(spawn);
+        let steps = (deepest - spawn).max(0) as u32 + 1;
+        Duration::from_millis(self.speed) * steps + LATENCY_ALLOWANCE
+    }
+
+    /// Returns when the server places the current tetrimino of player `id` for them, unless
+    /// they place it first. Returns `None` for players that aren't in the game.
+    pub fn lock_deadline(&self, id: usize) -> Option<Instant> {
+        if self.in_game(id) {
+            Some(self.state.piece_since[id] + self.lock_time(id))
+        } else {
+            None
+        }
+    }
+
+    /// Places the current tetrimino of player `id` at `state` and moves on to the next one.
+    fn place<C: Context>(&mut self, context: &mut C, id: usize, state: ActiveState) {
+        context.command(self, format!("games/{}/moves/set:{}", id,
+                                      self.games[id].moves + 1)).unwrap();
+
+        if self.games[id].held {
+            context.command(self, format!("games/{}/held/set:false", id)).unwrap();
+        }
+
+        // place the tetrimino
+        for y in 0..4 {
+            for x in 0..4 {
+                let shape = self.games[id].current as usize;
+                let rotation = state.rotation as usize;
+                let col = super::shapes::SHAPES[shape][rotation][x + y * 4];
+                if col != 0 && state.y + y as i32 >= 0 {
+                    let index = (state.y + y as i32) * 10 + state.x + x as i32;
+
+                    self.games[id].field[index as usize] = col;
+
+                    context.command(self, format!("games/{}/field/{}/set:{}", id, index,
+                                                  col)).unwrap();
+                }
+            }
+        }
+
+        // check for cleared lines
+        let mut lines = 0;
+        for y in 0..4 {
+            let y = state.y + y;
+            if y >= 0 && y < 21 {
+                let line = (y * 10) as usize;
+                let clear = self.games[id].field[line..line + 10]
+                    .iter()
+                    .fold(true, |clear, &b| clear && b > 0);
+                if clear {
+                    context.command(self, format!("games/{}/call:clear:{}",id,y)).unwrap();
+                    lines += 1;
+                }
+            }
+        }
+        if lines > 0 {
+            context.command(self, format!("games/{}/call:compact:", id)).unwrap();
+            context.command(self, format!("games/{}/combo/set:{}", id,
+                                          self.games[id].combo + 1)).unwrap();
+            context.command(self, format!("games/{}/lines_cleared/set:{}", id,
+                                          self.games[id].lines_cleared + lines)).unwrap();
+
+            let garbage = match lines {
+                2 => 1,
+                3 => 2,
+                4 => 4,
+                _ => 0,
+            };
+
+            if garbage > 0 {
+                let column = random::<u32>() % 10;
+                for i in 0..garbage {
+                    if i < self.games[id].garbage.len() {
+                        context
+                            .command(self, format!("games/{}/garbage/remove:0", id))
+                            .unwrap();
+                    } else {
+                        let tgt = self.games[id].target;
+                        context
+                            .command(self, format!("games/{}/garbage_sent/set:{}", id,
+                                                   self.games[id].garbage_sent + 1))
+                            .unwrap();
+                        context
+                            .command(self, format!("games/{}/garbage_received/set:{}", tgt,
+                                                   self.games[tgt].garbage_received + 1))
+                            .unwrap();
+                        context
+                            .command(self, format!("games/{}/garbage/push:[{},3]", tgt,
+                                                   column))
+                            .unwrap();
+                    }
+                }
+            }
+
+        } else if self.games[id].combo > 0 {
+            context.command(self, format!("games/{}/combo/set:{}", id, 0)).unwrap();
+        }
+
+        if self.games[id].garbage.len() > 0 {
+            context.command(self, format!("games/{}/call:gen_garbage:", id)).unwrap();
+        }
+
+        // check for k.o.
+        if self.games[id].field[..10].iter().find(|&&x| x > 0).is_some() {
+            self.player_ko(context, id, "topped out");
+        }
+
+        // move on to the next piece
+        context.command(self, format!("games/{}/current/set:{}", id,
+                                      self.games[id].next[0])).unwrap();
+        context.command(self, format!("games/{}/next/remove:0", id)).unwrap();
+
+        if self.games[id].next.len() < 14 {
+            let mut i = 0;
+            let mut next: Vec<u8> = repeat_with(|| { i += 1; i % 7 })
+                .take(28)
+                .collect();
+            next.shuffle(self.games[id].random.as_mut().unwrap());
+            self.games[id].next.extend_from_slice(next.as_slice());
+            let val: Value = self.games[id].next.clone().into();
+            context
+                .command(self, format!("games/{}/next/set:{}", id, val.to_string()))
+                .unwrap();
+        }
+    }
+
     fn server_update<C: Context>(&mut self, mut context: C) {
         if Instant::now() > self.state.deadline && self.started == false {
             context.command(self, "started/set:true").unwrap();
@@ -115,10 +351,29 @@ impl InstanceState {
 
             for player in missed {
                 if let Some(index) = self.state.player_index(player.as_str()) {
-                    context.command(self, format!("games/{}/ko/set:true", index).as_str()).unwrap();
-                    context.command(self, format!("games_ko/push:{}", index).as_str()).unwrap();
+                    self.player_ko(&mut context, index, "missed start");
                 }
             }
+
+            let now = Instant::now();
+            for since in self.state.piece_since.iter_mut() {
+                *since = now;
+            }
+        }
+
+        // players that don't place their tetrimino in time get it hard dropped where it spawns
+        let now = Instant::now();
+        for id in 0..self.games.len() {
+            match self.lock_deadline(id) {
+                Some(deadline) if now >= deadline => (),
+                _ => continue,
+            }
+
+            let state = self.games[id].hard_drop(ActiveState::new());
+            context.command(self, format!("games/{}/forced/set:{}", id,
+                                          self.games[id].forced + 1)).unwrap();
+            self.place(&mut context, id, state);
+            self.state.piece_since[id] = now;
         }
 
         if self.started && !self.done && self.games.iter().filter(|g| !g.ko).count() < 2 {
@@ -134,16 +389,29 @@ impl InstanceState {
                         .enumerate()
                         .filter(|&(j, g)| i != j && !g.ko)
                         .map(|(j, _)| j)
-                        .choose(&mut thread_rng())
-                        .unwrap();
+                        .choose(&mut thread_rng());
 
-                    context.command(self, format!("games/{}/target/set:{}", i, new_target)).unwrap();
+                    // there is no one left to target when the match is about to end
+                    if let Some(new_target) = new_target {
+                        context.command(self, format!("games/{}/target/set:{}", i, new_target))
+                            .unwrap();
+                    }
                 }
             }
         }
     }
 
-    fn login<C: Context>(&mut self, mut context: C, player: String) {
+    fn login<C: Context>(&mut self, mut context: C, player: String, nickname: String) {
+        let nickname = sanitize_nickname(nickname.as_str());
+        if let Some(index) = self.state.player_index(player.as_str()) {
+            if !nickname.is_empty() && nickname != self.games[index].nickname {
+                let nickname: Value = nickname.into();
+                context
+                    .command(self, format!("games/{}/nickname/set:{}", index, nickname))
+                    .unwrap();
+            }
+        }
+
         if self.started == false { ;
             self.state.awaiting.retain(|key| key.as_str() != player.as_str());
             let count = self.state.awaiting.len();
@@ -151,136 +419,44 @@ impl InstanceState {
                 .command(self, format!("status/set:\"Waiting for players.. ({})\"", count))
                 .unwrap();
 
-            // update the timer so that we don't have to wait too long
-            let option1 = Instant::now() + Duration::from_secs(5);
-            let option2 = self.state.deadline + Duration::from_secs(1);
-            if option2 < option1 {
-                self.state.deadline = option2;
-            } else {
-                self.state.deadline = option1;
+            // update the timer so that we don't have to wait too long,
+            // unless the countdown already started
+            if self.state.deadline > Instant::now() + COUNTDOWN {
+                let option1 = Instant::now() + Duration::from_secs(5);
+                let option2 = self.state.deadline + Duration::from_secs(1);
+                if option2 < option1 {
+                    self.state.deadline = option2;
+                } else {
+                    self.state.deadline = option1;
+                }
+
+                let start_at = instant_millis(self.state.deadline);
+                context.command(self, format!("start_at/set:{}", start_at)).unwrap();
             }
         }
     }
 
-    fn drop<C: Context>(&mut self, mut context: C, player: String, state: ActiveState) {
-        if let Some(id) = self.state.player_index(player.as_str()) {
-            if self.in_game(id) {
-                context.command(self, format!("games/{}/moves/set:{}", id,
-                                              self.games[id].moves + 1)).unwrap();
-
-                if self.games[id].held {
-                    context.command(self, format!("games/{}/held/set:false", id)).unwrap();
-                }
-
-                // place the tetrimino
-                for y in 0..4 {
-                    for x in 0..4 {
-                        let shape = self.games[id].current as usize;
-                        let rotation = state.rotation as usize;
-                        let col = super::shapes::SHAPES[shape][rotation][x + y * 4];
-                        if col != 0 && state.y + y as i32 >= 0 {
-                            let index = (state.y + y as i32) * 10 + state.x + x as i32;
-
-                            self.games[id].field[index as usize] = col;
-
-                            context.command(self, format!("games/{}/field/{}/set:{}", id, index,
-                                                          col)).unwrap();
-                        }
-                    }
-                }
-
-                // check for cleared lines
-                let mut lines = 0;
-                for y in 0..4 {
-                    let y = state.y + y;
-                    if y >= 0 && y < 21 {
-                        let line = (y * 10) as usize;
-                        let clear = self.games[id].field[line..line + 10]
-                            .iter()
-                            .fold(true, |clear, &b| clear && b > 0);
-                        if clear {
-                            context.command(self, format!("games/{}/call:clear:{}",id,y)).unwrap();
-                            lines += 1;
-                        }
-                    }
-                }
-                if lines > 0 {
-                    context.command(self, format!("games/{}/call:compact:", id)).unwrap();
-                    context.command(self, format!("games/{}/combo/set:{}", id,
-                                                  self.games[id].combo + 1)).unwrap();
-                    context.command(self, format!("games/{}/lines_cleared/set:{}", id,
-                                                  self.games[id].lines_cleared + lines)).unwrap();
-
-                    let garbage = match lines {
-                        2 => 1,
-                        3 => 2,
-                        4 => 4,
-                        _ => 0,
-                    };
-
-                    if garbage > 0 {
-                        let column = random::<u32>() % 10;
-                        for i in 0..garbage {
-                            if i < self.games[id].garbage.len() {
-                                context
-                                    .command(self, format!("games/{}/garbage/remove:0", id))
-                                    .unwrap();
-                            } else {
-                                let tgt = self.games[id].target;
-                                context
-                                    .command(self, format!("games/{}/garbage_sent/set:{}", id,
-                                                           self.games[id].garbage_sent + 1))
-                                    .unwrap();
-                                context
-                                    .command(self, format!("games/{}/garbage_received/set:{}", tgt,
-                                                           self.games[tgt].garbage_received + 1))
-                                    .unwrap();
-                                context
-                                    .command(self, format!("games/{}/garbage/push:[{},3]", tgt,
-                                                           column))
-                                    .unwrap();
-                            }
-                        }
-                    }
-
-                } else if self.games[id].combo > 0 {
-                    context.command(self, format!("games/{}/combo/set:{}", id, 0)).unwrap();
-                }
-
-                if self.games[id].garbage.len() > 0 {
-                    context.command(self, format!("games/{}/call:gen_garbage:", id)).unwrap();
-                }
-
-                // check for k.o.
-                if self.games[id].field[..10].iter().find(|&&x| x > 0).is_some() {
-                    context.command(self, format!("games/{}/ko/set:true", id)).unwrap();
-                    context.command(self, format!("games_ko/push:{}", id)).unwrap();
-                }
+    // Calls from players are accepted from `start_at` on, since that's when their clients start
+    // the match. The server only notices that the match started at its next update.
 
-                // move on to the next piece
-                context.command(self, format!("games/{}/current/set:{}", id,
-                                              self.games[id].next[0])).unwrap();
-                context.command(self, format!("games/{}/next/remove:0", id)).unwrap();
-
-                if self.games[id].next.len() < 14 {
-                    let mut i = 0;
-                    let mut next: Vec<u8> = repeat_with(|| { i += 1; i % 7 })
-                        .take(28)
-                        .collect();
-                    next.shuffle(self.games[id].random.as_mut().unwrap());
-                    self.games[id].next.extend_from_slice(next.as_slice());
-                    let val: Value = self.games[id].next.clone().into();
-                    context
-                        .command(self, format!("games/{}/next/set:{}", id, val.to_string()))
-                        .unwrap();
-                }
+    /// Places the current tetrimino of `player` at `state`. `forced` is the number of tetriminos
+    /// the server had placed for the player when the drop was made, if the server placed another
+    /// one since, the drop was meant for a tetrimino that's gone and is ignored.
+    fn drop<C: Context>(&mut self, mut context: C, player: String, state: ActiveState,
+                        input: usize, forced: usize) {
+        if let Some(id) = self.state.player_index(player.as_str()) {
+            self.state.inputs[id] = self.state.inputs[id].max(input);
+            let current = forced == self.games[id].forced && self.games[id].fits(state);
+            if self.in_game_at(id, unix_millis()) && current {
+                self.place(&mut context, id, state);
+                self.state.piece_since[id] = Instant::now();
             }
         }
     }
 
     fn target<C: Context>(&mut self, mut context: C, player: String, target: usize) {
         if let Some(id) = self.state.player_index(player.as_str()) {
-            if self.in_game(id) {
+            if self.in_game_at(id, unix_millis()) {
                 let mut actual_target = target;
 
                 if actual_target >= self.games.len() || self.games[actual_target].ko ||
@@ -301,9 +477,18 @@ impl InstanceState {
         }
     }
 
-    fn hold<C: Context>(&mut self, mut context: C, player: String) {
+    fn forfeit<C: Context>(&mut self, mut context: C, player: String, reason: String) {
+        if let Some(id) = self.state.player_index(player.as_str()) {
+            if self.in_game_at(id, unix_millis()) {
+                self.player_ko(&mut context, id, reason.as_str());
+            }
+        }
+    }
+
+    fn hold<C: Context>(&mut self, mut context: C, player: String, input: usize) {
         if let Some(id) = self.state.player_index(player.as_str()) {
-            if self.in_game(id) && !self.games[id].held {
+            self.state.inputs[id] = self.state.inputs[id].max(input);
+            if self.in_game_at(id, unix_millis()) && !self.games[id].held {
                 context.command(self, format!("games/{}/held/set:true", id)).unwrap();
 
                 let old = self.games[id].hold;
@@ -318,6 +503,7 @@ impl InstanceState {
                 } else {
                     context.command(self, format!("games/{}/current/set:{}", id, old)).unwrap();
                 }
+                self.state.piece_since[id] = Instant::now();
             }
         }
     }
@@ -351,6 +537,34 @@ impl PlayerState {
             lines_cleared: 0,
             garbage_sent: 0,
             garbage_received: 0,
+            rating: 0.0,
+            nickname: String::new(),
+            checksum: (0, 0),
+            forced: 0,
+        }
+    }
+
+    /// Hashes the parts of the state that clients predict, or that change in ways they don't
+    /// predict. Uses 32 bit FNV-1a, so it's the same on every platform.
+    pub fn state_hash(&self) -> u32 {
+        let flags = [self.current, self.hold, self.held as u8, self.ko as u8];
+        let garbage = self.garbage.iter().flat_map(|&(column, delay)| vec![column, delay]);
+
+        self.field
+            .iter()
+            .chain(flags.iter())
+            .chain(self.next.iter())
+            .cloned()
+            .chain(garbage)
+            .fold(0x811c_9dc5, |hash: u32, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+    }
+
+    /// The name to show for this player, `index` is used when no nickname is known.
+    pub fn display_name(&self, index: usize) -> String {
+        if self.nickname.is_empty() {
+            format!("Player {}", index + 1)
+        } else {
+            self.nickname.clone()
         }
     }
 
@@ -388,6 +602,40 @@ impl PlayerState {
         self.garbage.retain(|(_, delay)| *delay > 0);
     }
 
+    /// Returns true if the current tetrimino can be at `state` without overlapping anything.
+    pub fn fits(&self, state: ActiveState) -> bool {
+        state.rotation >= 0 && state.rotation < 4 && !self.collision(state)
+    }
+
+    /// Returns every place the current tetrimino can be hard dropped to, by turning it where it
+    /// spawns and sliding it sideways.
+    pub fn landings(&self) -> Vec<ActiveState> {
+        let mut landings = Vec::new();
+
+        let mut rotated = ActiveState::new();
+        for _ in 0..4 {
+            let mut state = rotated;
+            loop {
+                let next = self.slide_left(state);
+                if next.x == state.x {
+                    break;
+                }
+                state = next;
+            }
+            loop {
+                landings.push(self.hard_drop(state));
+                let next = self.slide_right(state);
+                if next.x == state.x {
+                    break;
+                }
+                state = next;
+            }
+            rotated = self.rotate_right(rotated);
+        }
+
+        landings
+    }
+
     fn collision(&self, state: ActiveState) -> bool {
         let grid = &super::shapes::SHAPES[self.current as usize][state.rotation as usize];
         let field = &self.field[0..];
@@ -1,5 +1,6 @@
 pub mod shapes;
 pub mod instance;
 pub mod matchmaking;
-
-
+pub mod replay;
+pub mod protocol;
+pub mod wire;
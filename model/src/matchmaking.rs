@@ -1,13 +1,153 @@
 use serde::*;
+use serde_json::Value;
 use mirror::*;
+use rand::random;
+use std::time::Instant;
+use crate::instance::sanitize_nickname;
 
+/// Identities are issued as this many hexadecimal digits. Identities that were issued before
+/// they had a fixed length may be a bit shorter.
+pub const IDENTITY_LEN: usize = 32;
+
+/// Identities shorter than this are too easy to guess.
+const MIN_IDENTITY_LEN: usize = 16;
+
+/// Returns whether `identity` looks like one the server issued. Identities are secrets that
+/// stand in for an account, so anything else is refused rather than taken as is.
+pub fn valid_identity(identity: &str) -> bool {
+    identity.len() >= MIN_IDENTITY_LEN &&
+        identity.len() <= IDENTITY_LEN &&
+        identity.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+#[ReflectFn(
+    Fn(name="identify", args="2"),
+    Fn(name="join", args="1"),
+)]
 #[derive(Serialize, Deserialize, Reflect)]
 pub struct MatchmakingState {
+    pub queued: Hidden<Instant>,
     pub instance_address: String,
     pub player_key: String,
     pub player_id: usize,
     pub players_found: usize,
     pub wait_time: usize,
+    pub estimated_wait: usize,
     pub matched: bool,
     pub done: bool,
-}
\ No newline at end of file
+    /// Ties the rating of the player to them. Anyone who knows it can play as them, so it's only
+    /// ever sent to the player it belongs to.
+    pub identity: String,
+    pub nickname: String,
+    pub rated: bool,
+    pub rating: f64,
+    pub rating_history: Vec<f64>,
+    pub queue: String,
+    pub max_players: usize,
+    /// The queues the server runs, by name and title.
+    pub queues: Vec<(String, String)>,
+    /// Why the matchmaker won't match us, empty while everything is fine.
+    pub error: String,
+}
+
+/// The rules for one of the queues players can be matched in.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QueueRules {
+    /// Identifies the queue when joining it.
+    pub name: String,
+    /// Shown to players when picking a queue.
+    pub title: String,
+    pub min_players: usize,
+    pub max_players: usize,
+    /// Seconds a match keeps waiting for more players after someone joined it.
+    pub fill_time: usize,
+    /// Seconds a match waits before trying again when it didn't find enough players.
+    pub retry_time: usize,
+}
+
+/// The queues that are available when the server doesn't configure any.
+pub fn default_queues() -> Vec<QueueRules> {
+    vec![
+        QueueRules {
+            name: "battle".to_string(),
+            title: "Battle 9".to_string(),
+            min_players: 2,
+            max_players: 9,
+            fill_time: 10,
+            retry_time: 91,
+        },
+        QueueRules {
+            name: "duel".to_string(),
+            title: "1v1 Duel".to_string(),
+            min_players: 2,
+            max_players: 2,
+            fill_time: 3,
+            retry_time: 30,
+        },
+    ]
+}
+
+impl MatchmakingState {
+    /// Tells the matchmaker who we are, so we are matched against players of similar skill
+    /// and our opponents know what to call us. Players without a valid identity are issued one,
+    /// which they should keep to themselves and bring along next time.
+    fn identify<C: Context>(&mut self, mut context: C, identity: String, nickname: String) {
+        if self.identity.is_empty() {
+            let identity: Value = if valid_identity(identity.as_str()) {
+                identity.into()
+            } else {
+                format!("{:016x}{:016x}", random::<u64>(), random::<u64>()).into()
+            };
+            context.command(self, format!("identity/set:{}", identity)).unwrap();
+        }
+
+        let nickname = sanitize_nickname(nickname.as_str());
+        if self.nickname.is_empty() && !nickname.is_empty() {
+            let nickname: Value = nickname.into();
+            context.command(self, format!("nickname/set:{}", nickname)).unwrap();
+        }
+    }
+
+    /// Picks the queue to be matched in. The queue can't be changed once picked, and has to be
+    /// one of the queues the server runs.
+    fn join<C: Context>(&mut self, mut context: C, queue: String) {
+        if !self.queue.is_empty() {
+            return;
+        }
+
+        if self.queues.iter().any(|(name, _)| name.as_str() == queue.as_str()) {
+            self.queued = Hidden::new(Instant::now());
+            let queue: Value = queue.into();
+            context.command(self, format!("queue/set:{}", queue)).unwrap();
+        } else {
+            let error: Value = format!("Unknown queue {}", queue).into();
+            context.command(self, format!("error/set:{}", error)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_identities_are_valid() {
+        for _ in 0..100 {
+            let identity = format!("{:016x}{:016x}", random::<u64>(), random::<u64>());
+            assert_eq!(identity.len(), IDENTITY_LEN);
+            assert!(valid_identity(identity.as_str()));
+        }
+        assert!(valid_identity("3f2a9c01d4e5b6a7c8d9e0f1a2b3c4"));
+    }
+
+    #[test]
+    fn other_identities_are_refused() {
+        assert!(!valid_identity(""));
+        assert!(!valid_identity("1"));
+        assert!(!valid_identity("0123456789abcde"));
+        assert!(!valid_identity("0123456789ABCDEF0123456789abcdef"));
+        assert!(!valid_identity("0123456789abcdef0123456789abcdef0"));
+        assert!(!valid_identity("0123456789abcdef\"0123456789abcd"));
+        assert!(!valid_identity("0123456789abcdef 123456789abcdef"));
+    }
+}
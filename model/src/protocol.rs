@@ -0,0 +1,91 @@
+//! The handshake clients and the server go through before any game state is sent.
+//!
+//! The first message of a client on every connection is `hello:{..}`, describing the protocol
+//! version it speaks, the oldest version it can still talk to and the optional features it
+//! supports. The server answers with its own `hello:{..}` when the two can talk to each other,
+//! after which the connection is handed to the instance. Otherwise it sends `outdated:{reason}`
+//! and closes the connection. Neither message is passed on to mirror.
+
+use serde::*;
+
+/// The version of the protocol spoken by this build. Bump this whenever the state or the calls
+/// change in a way that older builds would misunderstand.
+pub const VERSION: u32 = 2;
+
+/// The oldest version of the protocol this build can still talk to.
+pub const MIN_VERSION: u32 = 2;
+
+/// The client asks for game state in the binary encoding of `wire`.
+pub const FEATURE_BINARY: &str = "binary";
+
+const HELLO: &str = "hello:";
+const OUTDATED: &str = "outdated:";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub min_version: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// Why two sides of a connection can't talk to each other.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mismatch {
+    /// The other side is too old for us.
+    TheirsOutdated,
+    /// We are too old for the other side.
+    OursOutdated,
+}
+
+/// A message that's part of the handshake.
+pub enum Handshake {
+    Hello(Hello),
+    Outdated(String),
+}
+
+impl Hello {
+    /// Describes this build, supporting `features`.
+    pub fn ours(features: &[&str]) -> Self {
+        Self {
+            version: VERSION,
+            min_version: MIN_VERSION,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    /// Checks whether this build can talk to the other side described by `self`.
+    pub fn check(&self) -> Result<(), Mismatch> {
+        if self.version < MIN_VERSION {
+            Err(Mismatch::TheirsOutdated)
+        } else if VERSION < self.min_version {
+            Err(Mismatch::OursOutdated)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f.as_str() == feature)
+    }
+
+    pub fn to_message(&self) -> String {
+        format!("{}{}", HELLO, serde_json::to_string(self).unwrap())
+    }
+}
+
+/// Returns the message that tells the other side it can't be talked to.
+pub fn outdated_message(reason: &str) -> String {
+    format!("{}{}", OUTDATED, reason)
+}
+
+/// Recognizes handshake messages, anything else is left alone.
+pub fn parse(message: &str) -> Option<Handshake> {
+    if message.starts_with(HELLO) {
+        serde_json::from_str(&message[HELLO.len()..]).ok().map(Handshake::Hello)
+    } else if message.starts_with(OUTDATED) {
+        Some(Handshake::Outdated(message[OUTDATED.len()..].to_string()))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,62 @@
+use serde::*;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+
+/// Version of the replay file layout. Bump this whenever the header or entry format changes.
+pub const REPLAY_VERSION: u32 = 1;
+
+/// The first line of a replay file.
+#[derive(Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub created: u64,
+    pub players: usize,
+    pub snapshot: Value,
+}
+
+/// A single message that the game server sent to its clients, `time` milliseconds after the
+/// recording started.
+#[derive(Serialize, Deserialize)]
+pub struct ReplayEntry {
+    pub time: u64,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Empty,
+    Version(u32),
+    Format(serde_json::Error),
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        match self {
+            &ReplayError::Empty => write!(f, "replay file is empty"),
+            &ReplayError::Version(v) => write!(f, "unsupported replay version {}", v),
+            &ReplayError::Format(ref e) => write!(f, "malformed replay: {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ReplayError {
+    fn from(e: serde_json::Error) -> Self {
+        ReplayError::Format(e)
+    }
+}
+
+/// Parses a replay file: one json header line followed by one json entry per line.
+pub fn parse(data: &str) -> Result<(ReplayHeader, Vec<ReplayEntry>), ReplayError> {
+    let mut lines = data.lines().filter(|line| !line.trim().is_empty());
+
+    let header: ReplayHeader = serde_json::from_str(lines.next().ok_or(ReplayError::Empty)?)?;
+    if header.version != REPLAY_VERSION {
+        return Err(ReplayError::Version(header.version));
+    }
+
+    let entries = lines
+        .map(|line| serde_json::from_str(line))
+        .collect::<Result<Vec<ReplayEntry>, _>>()?;
+
+    Ok((header, entries))
+}
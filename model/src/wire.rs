@@ -0,0 +1,350 @@
+//! A compact binary encoding for the commands a game instance sends to its clients.
+//!
+//! Mirror sends every change to the state as a separate text command, so a single hard drop turns
+//! into a command per cell and the full piece queue is resent every few pieces. Connections that
+//! ask for the binary encoding get batches of commands packed into one websocket binary frame:
+//! - consecutive cell changes in the same row of a field are packed into a row bitmask,
+//! - piece queues are only sent in full once, after that only the appended pieces are sent,
+//! - everything else is sent as text.
+//!
+//! Decoding a frame yields the exact same text commands that were encoded, so mirror itself
+//! doesn't need to know about any of this.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Error, ErrorKind, Read, Result};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// The query a client adds to the instance url to ask for the binary encoding.
+pub const BINARY_QUERY: &str = "wire=binary";
+
+const OP_TEXT: u8 = 0;
+const OP_ROW: u8 = 1;
+const OP_NEXT_SET: u8 = 2;
+const OP_NEXT_APPEND: u8 = 3;
+
+/// Width of a field, every row of a field fits in a 16 bit mask.
+const FIELD_WIDTH: usize = 10;
+
+/// The piece queues of every game, as far as they're known to both sides of a connection.
+#[derive(Default)]
+struct Queues {
+    known: HashMap<usize, Vec<u8>>,
+}
+
+/// Packs commands into binary frames. Every connection needs its own encoder.
+#[derive(Default)]
+pub struct Encoder {
+    queues: Queues,
+}
+
+/// Unpacks binary frames into commands. Every connection needs its own decoder.
+#[derive(Default)]
+pub struct Decoder {
+    queues: Queues,
+}
+
+/// A command that can be sent in a more compact form than text.
+enum Packed {
+    /// `games/{game}/field/{index}/set:{color}`
+    Cell { game: u8, row: u8, column: usize, color: u8 },
+    /// `games/{game}/next/set:[..]`
+    Next { game: u8, queue: Vec<u8> },
+}
+
+impl Queues {
+    /// Updates the known queues with a command that's about to be sent or was just received.
+    fn track(&mut self, command: &str) {
+        if let Some(Packed::Next { game, queue }) = parse(command) {
+            self.known.insert(game as usize, queue);
+            return;
+        }
+
+        let (path, args) = split(command);
+        let mut segments: Vec<&str> = path.split('/').collect();
+        let verb = segments.pop().unwrap_or("");
+
+        match segments.as_slice() {
+            ["games", game, "next"] if verb == "remove" && args == "0" => {
+                if let Some(queue) = game.parse().ok().and_then(|g| self.known.get_mut(&g)) {
+                    if !queue.is_empty() {
+                        queue.remove(0);
+                    }
+                }
+            },
+            // anything that may replace a queue as a whole makes it unknown
+            [] | ["games"] => self.known.clear(),
+            ["games", game] | ["games", game, "next"] => {
+                match game.parse::<usize>() {
+                    Ok(game) => { self.known.remove(&game); },
+                    Err(_) => self.known.clear(),
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Packs `commands` into a single frame.
+    pub fn encode<S: AsRef<str>>(&mut self, commands: &[S]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        // the position and contents of the row op that's still open for more cells
+        let mut open_row: Option<(usize, u8, u8, u16)> = None;
+
+        for command in commands.iter().map(|c| c.as_ref()) {
+            match parse(command) {
+                Some(Packed::Cell { game, row, column, color }) => {
+                    // colors are stored in the order of the bits, so a row can only be
+                    // extended with cells to the right of the ones already in it
+                    let extends = match open_row {
+                        Some((_, g, r, mask)) => g == game && r == row && mask >> column == 0,
+                        None => false,
+                    };
+
+                    if !extends {
+                        let at = frame.len();
+                        frame.extend_from_slice(&[OP_ROW, game, row, 0, 0]);
+                        open_row = Some((at, game, row, 0));
+                    }
+
+                    let (at, _, _, mask) = open_row.as_mut().unwrap();
+                    *mask |= 1 << column;
+                    frame.push(color);
+                    (&mut frame[*at + 3..*at + 5]).write_u16::<LittleEndian>(*mask).unwrap();
+                },
+                Some(Packed::Next { game, queue }) => {
+                    open_row = None;
+                    let known = self.queues.known.get(&(game as usize));
+                    match known {
+                        Some(known) if queue.starts_with(known.as_slice()) => {
+                            let appended = &queue[known.len()..];
+                            frame.extend_from_slice(&[OP_NEXT_APPEND, game, appended.len() as u8]);
+                            frame.extend_from_slice(appended);
+                        },
+                        _ => {
+                            frame.extend_from_slice(&[OP_NEXT_SET, game, queue.len() as u8]);
+                            frame.extend_from_slice(queue.as_slice());
+                        },
+                    }
+                },
+                None => {
+                    open_row = None;
+                    frame.push(OP_TEXT);
+                    frame.write_u32::<LittleEndian>(command.len() as u32).unwrap();
+                    frame.extend_from_slice(command.as_bytes());
+                },
+            }
+            self.queues.track(command);
+        }
+
+        frame
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unpacks a frame into the commands it was made from.
+    pub fn decode(&mut self, frame: &[u8]) -> Result<Vec<String>> {
+        let mut commands = Vec::new();
+        let mut reader = Cursor::new(frame);
+
+        while (reader.position() as usize) < frame.len() {
+            match reader.read_u8()? {
+                OP_TEXT => {
+                    // the length comes from the other side, so it's checked before allocating
+                    let len = reader.read_u32::<LittleEndian>()? as usize;
+                    let left = reader.get_ref().len() - reader.position() as usize;
+                    if len > left {
+                        return Err(Error::new(ErrorKind::UnexpectedEof,
+                                              format!("text of {} bytes in a frame with {} left",
+                                                      len, left)));
+                    }
+                    let mut text = vec![0; len];
+                    reader.read_exact(text.as_mut_slice())?;
+                    let text = String::from_utf8(text)
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                    self.queues.track(text.as_str());
+                    commands.push(text);
+                },
+                OP_ROW => {
+                    let game = reader.read_u8()?;
+                    let row = reader.read_u8()? as usize;
+                    let mask = reader.read_u16::<LittleEndian>()?;
+                    for column in (0..FIELD_WIDTH).filter(|c| mask & (1 << c) != 0) {
+                        let color = reader.read_u8()?;
+                        let index = row * FIELD_WIDTH + column;
+                        commands.push(format!("games/{}/field/{}/set:{}", game, index, color));
+                    }
+                },
+                op @ OP_NEXT_SET | op @ OP_NEXT_APPEND => {
+                    let game = reader.read_u8()?;
+                    let mut pieces = vec![0; reader.read_u8()? as usize];
+                    reader.read_exact(pieces.as_mut_slice())?;
+
+                    let queue = if op == OP_NEXT_APPEND {
+                        let mut queue = self.queues.known
+                            .get(&(game as usize))
+                            .cloned()
+                            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown queue"))?;
+                        queue.extend(pieces);
+                        queue
+                    } else {
+                        pieces
+                    };
+
+                    let command = format!("games/{}/next/set:{}", game, to_json(&queue));
+                    self.queues.track(command.as_str());
+                    commands.push(command);
+                },
+                op => {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          format!("unknown op {} in frame", op)));
+                },
+            }
+        }
+
+        Ok(commands)
+    }
+}
+
+/// Splits a command in its path and arguments.
+fn split(command: &str) -> (&str, &str) {
+    match command.find(':') {
+        Some(i) => (&command[..i], &command[i + 1..]),
+        None => (command, ""),
+    }
+}
+
+/// Recognizes the commands that can be packed. The packed form must decode to exactly the same
+/// text, so anything that's formatted differently is left alone.
+fn parse(command: &str) -> Option<Packed> {
+    let (path, args) = split(command);
+    let segments: Vec<&str> = path.split('/').collect();
+
+    match segments.as_slice() {
+        ["games", game, "field", index, "set"] => {
+            let game = number(game)?;
+            let index = number(index)? as usize;
+            let color = number(args)?;
+            Some(Packed::Cell {
+                game,
+                row: (index / FIELD_WIDTH) as u8,
+                column: index % FIELD_WIDTH,
+                color,
+            })
+        },
+        ["games", game, "next", "set"] => {
+            let game = number(game)?;
+            let queue: Vec<u8> = serde_json::from_str(args).ok()?;
+            if queue.len() > 255 || to_json(&queue) != args {
+                return None;
+            }
+            Some(Packed::Next { game, queue })
+        },
+        _ => None,
+    }
+}
+
+/// Parses a number that fits in a byte and is written the way it would be formatted.
+fn number(text: &str) -> Option<u8> {
+    let n = text.parse::<u8>().ok()?;
+    if n.to_string() == text { Some(n) } else { None }
+}
+
+fn to_json(queue: &[u8]) -> String {
+    serde_json::to_string(queue).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes every batch into a frame and decodes it again, checking nothing changed on the
+    /// way. Returns the frames.
+    fn round_trip(batches: &[&[&str]]) -> Vec<Vec<u8>> {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+
+        batches
+            .iter()
+            .map(|batch| {
+                let frame = encoder.encode(batch);
+                assert_eq!(decoder.decode(frame.as_slice()).unwrap(), batch.to_vec());
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn text() {
+        let frames = round_trip(&[&["status/set:\"In game\"", "started/set:true",
+                                    "games/0/field/3/set:\"x\"", "call:clear:4"]]);
+        assert_eq!(frames[0][0], OP_TEXT);
+    }
+
+    #[test]
+    fn rows() {
+        let frames = round_trip(&[&[
+            "games/0/field/15/set:3",
+            "games/0/field/16/set:3",
+            "games/0/field/19/set:3",
+            "games/0/field/14/set:3",
+            "games/1/field/205/set:8",
+        ]]);
+        // the first three cells share a row op, the fourth is to the left so it starts a new one
+        assert_eq!(frames[0].len(), 5 + 3 + 5 + 1 + 5 + 1);
+    }
+
+    #[test]
+    fn piece_lists() {
+        let frames = round_trip(&[
+            &["games/0/next/set:[1,2,3]"],
+            &["games/0/next/remove:0", "games/0/next/set:[2,3,4,5]"],
+            &["games/0/next/set:[6,0]"],
+        ]);
+        assert_eq!(frames[0][0], OP_NEXT_SET);
+        // only the pieces that were appended are sent
+        assert_eq!(frames[1][frames[1].len() - 5..], [OP_NEXT_APPEND, 0, 2, 4, 5]);
+        assert_eq!(frames[2][0], OP_NEXT_SET);
+    }
+
+    #[test]
+    fn queue_reset() {
+        // replacing a game replaces its queue, so the queue is sent in full afterwards
+        let frames = round_trip(&[
+            &["games/0/next/set:[1,2]", "games/1/next/set:[3,4]"],
+            &["games/0/set:{\"next\":[5]}"],
+            &["games/0/next/set:[5,6]", "games/1/next/set:[3,4,5]"],
+        ]);
+        assert_eq!(frames[2][0], OP_NEXT_SET);
+        assert_eq!(frames[2][5], OP_NEXT_APPEND);
+    }
+
+    #[test]
+    fn truncated() {
+        let commands = ["status/set:\"In game\"", "games/0/field/15/set:3",
+                        "games/0/next/set:[1,2,3]"];
+
+        // every op is cut off somewhere in the middle
+        for command in commands.iter() {
+            let frame = Encoder::new().encode(&[command]);
+            for len in 1..frame.len() {
+                assert!(Decoder::new().decode(&frame[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_text() {
+        let frame = [OP_TEXT, 0xff, 0xff, 0xff, 0xff, b'x'];
+        assert!(Decoder::new().decode(&frame).is_err());
+    }
+}
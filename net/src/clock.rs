@@ -0,0 +1,100 @@
+use crate::local_millis;
+
+/// How many samples are taken in quick succession after connecting.
+const BURST: usize = 5;
+
+/// Time between samples during the burst and after it, in milliseconds.
+const BURST_INTERVAL: u64 = 100;
+const INTERVAL: u64 = 2_000;
+
+/// Samples that haven't been answered after this many milliseconds are given up on.
+const TIMEOUT: u64 = 5_000;
+
+/// The connection is considered lost when no sample was answered for this many milliseconds.
+const SILENCE: u64 = 10_000;
+
+/// Estimates the server's clock by sending it our time every now and then. The server answers
+/// with its own time, which is assumed to be taken halfway the round trip. The sample with the
+/// shortest round trip is the most accurate one, so that's the one that's used.
+/// Every sample also measures the round trip time, which is reported to the server with the next.
+pub struct Clock {
+    offset: Option<i64>,
+    best_rtt: u64,
+    rtt: Option<u64>,
+    samples: usize,
+    waiting: Option<u64>,
+    next: u64,
+    answered: u64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            offset: None,
+            best_rtt: 0,
+            rtt: None,
+            samples: 0,
+            waiting: None,
+            next: 0,
+            answered: local_millis(),
+        }
+    }
+
+    /// Starts sampling again on a new connection, the old estimate is kept until then.
+    pub fn reconnected(&mut self) {
+        self.rtt = None;
+        self.samples = 0;
+        self.waiting = None;
+        self.next = 0;
+        self.answered = local_millis();
+    }
+
+    /// Takes in the last answer from the server, `(sent, server time)`.
+    /// Returns a `clock` call that should be sent to the server if a new sample is due.
+    pub fn update(&mut self, answer: (u64, u64)) -> Option<String> {
+        let now = local_millis();
+
+        if let Some(sent) = self.waiting {
+            if answer.0 == sent && now >= sent {
+                let rtt = now - sent;
+                if self.offset.is_none() || self.samples == 0 || rtt <= self.best_rtt {
+                    self.offset = Some(answer.1 as i64 + (rtt / 2) as i64 - now as i64);
+                    self.best_rtt = rtt;
+                }
+                self.rtt = Some(rtt);
+                self.answered = now;
+                self.samples += 1;
+                self.waiting = None;
+                self.next = now + if self.samples < BURST { BURST_INTERVAL } else { INTERVAL };
+            } else if now > sent + TIMEOUT {
+                self.waiting = None;
+                self.rtt = Some(TIMEOUT);
+            }
+        }
+
+        if self.waiting.is_none() && now >= self.next {
+            self.waiting = Some(now);
+            let rtt = self.rtt.map(|rtt| rtt.to_string()).unwrap_or("null".to_string());
+            Some(format!("call:clock:{} {}", now, rtt))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the server hasn't answered for so long that the connection must be dead,
+    /// even though it may not have been closed.
+    pub fn silent(&self) -> bool {
+        local_millis() > self.answered + SILENCE
+    }
+
+    /// Returns the round trip time to the server measured last, in milliseconds.
+    pub fn rtt(&self) -> Option<u64> {
+        self.rtt
+    }
+
+    /// Returns the current time on the server's clock in milliseconds since the unix epoch,
+    /// or `None` if the server hasn't answered yet.
+    pub fn server_now(&self) -> Option<u64> {
+        self.offset.map(|offset| (local_millis() as i64 + offset) as u64)
+    }
+}
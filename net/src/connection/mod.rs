@@ -0,0 +1,179 @@
+use mirror::Remote;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use crate::local_millis;
+use tetris_model::protocol::{self, FEATURE_BINARY, Handshake, Hello};
+use tetris_model::wire::BINARY_QUERY;
+
+/// Set when the server turned out to speak a protocol this client doesn't understand.
+static VERSION_MISMATCH: AtomicBool = AtomicBool::new(false);
+
+/// Probes that didn't get an answer after this many milliseconds are given up on.
+const PROBE_TIMEOUT: u64 = 5_000;
+
+/// Returns true if a server refused this client, or the other way around, because they speak
+/// different versions of the protocol. The page has to be refreshed or the game updated.
+pub fn version_mismatch() -> bool {
+    VERSION_MISMATCH.load(Ordering::SeqCst)
+}
+
+/// How certificates of `wss://` servers are checked. Browsers check them by themselves, so this
+/// only matters to native clients.
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    /// A certificate authority in PEM format to trust on top of the system's.
+    pub ca: Option<PathBuf>,
+    /// Accepts any certificate, including self signed ones. Only meant for testing.
+    pub accept_invalid_certs: bool,
+}
+
+/// The first message sent on every connection.
+fn hello() -> String {
+    Hello::ours(&[FEATURE_BINARY]).to_message()
+}
+
+/// Remembers whether the server of a single connection refused to talk to us. Only refusals of
+/// connections that are actually played on mark the whole game as out of date, probes just tell
+/// the player about it.
+#[derive(Clone)]
+struct Refusal {
+    probe: bool,
+    refused: Arc<AtomicBool>,
+}
+
+impl Refusal {
+    fn new(probe: bool) -> Self {
+        Self { probe, refused: Arc::new(AtomicBool::new(false)) }
+    }
+
+    fn refused(&self) -> bool {
+        self.refused.load(Ordering::SeqCst)
+    }
+
+    /// Looks for the handshake in messages from the server. Returns `None` for messages that
+    /// should be passed on to mirror, otherwise whether the connection can still be used.
+    fn handshake(&self, message: &str) -> Option<bool> {
+        let compatible = match protocol::parse(message)? {
+            Handshake::Hello(hello) => hello.check().is_ok(),
+            Handshake::Outdated(_) => false,
+        };
+        if !compatible {
+            self.refused.store(true, Ordering::SeqCst);
+            if !self.probe {
+                VERSION_MISMATCH.store(true, Ordering::SeqCst);
+            }
+        }
+        Some(compatible)
+    }
+}
+
+/// Asks the server to send game state in the compact binary encoding.
+fn binary_uri(uri: &str) -> String {
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", uri, separator, BINARY_QUERY)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(not(target_arch = "wasm32"))]
+use self::native::WsConnection;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect(uri: &str, tls: &TlsOptions, refusal: Refusal) -> Result<WsConnection, String> {
+    WsConnection::connect(binary_uri(uri).as_str(), tls, refusal)
+}
+
+/// Connecting blocks until the server answers or the attempt times out, so probes connect on
+/// their own thread.
+#[cfg(not(target_arch = "wasm32"))]
+fn in_background<F: 'static + Send + FnOnce()>(f: F) {
+    std::thread::spawn(f);
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+#[cfg(target_arch = "wasm32")]
+use self::wasm::WsConnection;
+
+#[cfg(target_arch = "wasm32")]
+fn connect(uri: &str, _tls: &TlsOptions, refusal: Refusal) -> Result<WsConnection, String> {
+    WsConnection::new(binary_uri(uri).as_str(), refusal)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn in_background<F: 'static + FnOnce()>(f: F) {
+    f();
+}
+
+/// Connects to a game server. Fails with a description of what went wrong that can be shown to
+/// the player.
+pub fn make_connection(uri: &str, tls: &TlsOptions) -> Result<impl Remote, String> {
+    connect(uri, tls, Refusal::new(false))
+}
+
+/// Checks whether a server can be reached and speaks our protocol, without blocking.
+pub struct Probe {
+    started: u64,
+    connecting: Receiver<Result<WsConnection, String>>,
+    connection: Option<WsConnection>,
+    result: Option<Result<u64, String>>,
+}
+
+impl Probe {
+    /// Starts probing the instance at `uri`, the matchmaker is always there.
+    pub fn new(uri: &str, tls: &TlsOptions) -> Self {
+        let (tx, connecting) = channel();
+        let uri = uri.to_string();
+        let tls = tls.clone();
+        in_background(move || {
+            tx.send(connect(uri.as_str(), &tls, Refusal::new(true))).ok();
+        });
+
+        Self {
+            started: local_millis(),
+            connecting,
+            connection: None,
+            result: None,
+        }
+    }
+
+    /// Returns how many milliseconds it took until the server sent its state, or why that
+    /// didn't happen. Returns `None` while waiting.
+    pub fn poll(&mut self) -> Option<&Result<u64, String>> {
+        if self.result.is_none() {
+            self.result = self.check();
+        }
+        self.result.as_ref()
+    }
+
+    fn check(&mut self) -> Option<Result<u64, String>> {
+        let timed_out = local_millis() > self.started + PROBE_TIMEOUT;
+
+        if self.connection.is_none() {
+            match self.connecting.try_recv() {
+                Ok(Ok(connection)) => self.connection = Some(connection),
+                Ok(Err(_)) => return Some(Err("Unreachable".to_string())),
+                Err(TryRecvError::Empty) if timed_out => return Some(Err("Timed out".to_string())),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => return Some(Err("Unreachable".to_string())),
+            }
+        }
+
+        let connection = self.connection.as_mut().unwrap();
+        let result = if connection.recv().is_some() {
+            Ok(local_millis().saturating_sub(self.started))
+        } else if connection.refused() {
+            Err("Incompatible".to_string())
+        } else if !connection.alive() {
+            Err("Unreachable".to_string())
+        } else if timed_out {
+            Err("Timed out".to_string())
+        } else {
+            return None;
+        };
+        connection.close();
+        Some(result)
+    }
+}
@@ -0,0 +1,268 @@
+use mirror::Remote;
+
+use std::fs::read;
+use std::io::{self, ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, Sender, channel, TryRecvError};
+use std::thread::spawn;
+use std::time::Duration;
+
+use native_tls::{Certificate, TlsConnector};
+use websocket::ClientBuilder;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+use websocket::sync::Client;
+use websocket::sync::stream::TlsStream;
+use websocket::ws::{Receiver as WsReceiver, Sender as WsSender};
+
+use tetris_model::wire::Decoder;
+
+use super::{hello, Refusal, TlsOptions};
+
+/// How long the thread of a `wss://` connection waits for something to arrive before it sends
+/// what's waiting. Tls streams can't be split in a reading and a writing half, so a single
+/// thread takes turns.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bytes read from a tls stream at once.
+const CHUNK_SIZE: usize = 4096;
+
+pub struct WsConnection {
+    alive: bool,
+    writer: Sender<OwnedMessage>,
+    reader: Receiver<String>,
+    refusal: Refusal,
+}
+
+/// The bytes the thread that owns a tls stream received, as a blocking reader. Messages are read
+/// from this instead of the stream itself, because the websocket reader can't pick up a message
+/// where it left off when reading the stream times out.
+struct Pipe {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    at: usize,
+}
+
+impl TlsOptions {
+    fn connector(&self) -> Result<TlsConnector, String> {
+        let mut builder = TlsConnector::builder();
+        if let Some(path) = self.ca.as_ref() {
+            let pem = read(path)
+                .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+            let ca = Certificate::from_pem(pem.as_slice())
+                .map_err(|e| format!("Invalid certificate in {}: {}", path.display(), e))?;
+            builder.add_root_certificate(ca);
+        }
+        builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        builder.build().map_err(|e| format!("Unable to set up tls: {}", e))
+    }
+}
+
+impl WsConnection {
+    /// Connects to a `ws://` or `wss://` address.
+    pub fn connect(uri: &str, tls: &TlsOptions, refusal: Refusal) -> Result<Self, String> {
+        let mut builder = ClientBuilder::new(uri)
+            .map_err(|e| format!("Invalid address {}: {}", uri, e))?;
+        let unreachable = |e: WebSocketError| format!("Unable to connect to {}: {}", uri, e);
+
+        if uri.starts_with("wss:") {
+            let client = builder.connect_secure(Some(tls.connector()?)).map_err(unreachable)?;
+            client.stream_ref()
+                .get_ref()
+                .set_read_timeout(Some(POLL_INTERVAL))
+                .map_err(|e| format!("Unable to connect to {}: {}", uri, e))?;
+            Ok(Self::secure(client, refusal))
+        } else {
+            let client = builder.connect_insecure().map_err(unreachable)?;
+            Self::plain(client, refusal).map_err(unreachable)
+        }
+    }
+
+    /// Runs a plain connection on two threads, one reading and one writing.
+    fn plain(client: Client<TcpStream>, refusal: Refusal) -> Result<Self, WebSocketError> {
+        let (mut reader, mut writer) = client.split()?;
+        let (connection, outgoing, incoming) = Self::new(refusal.clone());
+
+        let replies = connection.writer.clone();
+        spawn(move || {
+            let mut decoder = Decoder::new();
+            while let Ok(message) = reader.recv_message() {
+                let mut reply = |message| replies.send(message).is_ok();
+                if !receive(message, &mut decoder, &incoming, &refusal, &mut reply) {
+                    break;
+                }
+            }
+        });
+
+        spawn(move || {
+            for message in outgoing.iter() {
+                let close = match message {
+                    OwnedMessage::Close(_) => true,
+                    _ => false,
+                };
+                if writer.send_message(&message).is_err() || close {
+                    break;
+                }
+            }
+        });
+
+        Ok(connection)
+    }
+
+    /// Runs a tls connection on two threads. One owns the stream, it sends what's waiting and
+    /// passes on the bytes that arrived in turns. The other reads messages from those bytes.
+    fn secure(client: Client<TlsStream<TcpStream>>, refusal: Refusal) -> Self {
+        let (connection, outgoing, incoming) = Self::new(refusal.clone());
+        let (mut stream, buffered) = client.into_stream();
+        let (chunks, pipe) = Pipe::new();
+
+        // the handshake may have read past the response already
+        if let Some((buffer, start, end)) = buffered {
+            if let Some(bytes) = buffer.get(start..end).filter(|bytes| !bytes.is_empty()) {
+                chunks.send(bytes.to_vec()).ok();
+            }
+        }
+
+        spawn(move || {
+            let mut sender = websocket::sender::Sender::new(true);
+            let mut chunk = [0; CHUNK_SIZE];
+            loop {
+                loop {
+                    match outgoing.try_recv() {
+                        Ok(message) => if sender.send_message(&mut stream, &message).is_err() {
+                            return;
+                        },
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+
+                // a read that times out doesn't lose anything, unlike reading a whole message
+                match stream.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(n) => if chunks.send(chunk[..n].to_vec()).is_err() {
+                        return;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                        e.kind() == ErrorKind::TimedOut => (),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        let replies = connection.writer.clone();
+        spawn(move || {
+            let mut pipe = pipe;
+            let mut receiver = websocket::receiver::Receiver::new(false);
+            let mut decoder = Decoder::new();
+            while let Ok(message) = receiver.recv_message(&mut pipe) {
+                let mut reply = |message| replies.send(message).is_ok();
+                if !receive(message, &mut decoder, &incoming, &refusal, &mut reply) {
+                    break;
+                }
+            }
+        });
+
+        connection
+    }
+
+    /// Creates the connection along with the ends of its channels the thread(s) should use.
+    /// The hello is the first message that goes out.
+    fn new(refusal: Refusal) -> (Self, Receiver<OwnedMessage>, Sender<String>) {
+        let (writer, outgoing) = channel();
+        let (incoming, reader) = channel();
+        let alive = writer.send(OwnedMessage::Text(hello())).is_ok();
+        (Self { alive, writer, reader, refusal }, outgoing, incoming)
+    }
+
+    /// Returns true if the server refused us because of our protocol version.
+    pub fn refused(&self) -> bool {
+        self.refusal.refused()
+    }
+}
+
+impl Pipe {
+    /// Creates the pipe along with the end the bytes should be sent to.
+    fn new() -> (Sender<Vec<u8>>, Self) {
+        let (sender, chunks) = channel();
+        (sender, Self { chunks, chunk: Vec::new(), at: 0 })
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.at == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.at = 0;
+                },
+                // the stream was closed
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.at);
+        buf[..n].copy_from_slice(&self.chunk[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+/// Handles a message from the server, `reply` sends a message back. Returns false when the
+/// connection should be closed.
+fn receive<F>(message: OwnedMessage, decoder: &mut Decoder, incoming: &Sender<String>,
+              refusal: &Refusal, reply: &mut F) -> bool where
+    F: FnMut(OwnedMessage) -> bool
+{
+    match message {
+        OwnedMessage::Ping(data) => reply(OwnedMessage::Pong(data)),
+        OwnedMessage::Pong(_) => true,
+        OwnedMessage::Text(data) => match refusal.handshake(data.as_str()) {
+            Some(compatible) => compatible,
+            None => incoming.send(data).is_ok(),
+        },
+        OwnedMessage::Binary(data) => match decoder.decode(data.as_slice()) {
+            Ok(messages) => messages.into_iter().all(|m| incoming.send(m).is_ok()),
+            Err(_) => false,
+        },
+        OwnedMessage::Close(_) => false,
+    }
+}
+
+impl Remote for WsConnection {
+    fn close(&mut self) {
+        if self.alive {
+            self.writer.send(OwnedMessage::Close(None)).ok();
+        }
+        self.alive = false;
+    }
+
+    fn alive(&self) -> bool {
+        self.alive
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), mirror::Error> {
+        Ok(self.alive &= self.writer.send(OwnedMessage::Text(message.to_string())).is_ok())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        match self.reader.try_recv() {
+            Ok(message) => {
+                Some(message)
+            },
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.alive = false;
+                None
+            },
+        }
+    }
+}
+
+impl Drop for WsConnection {
+    fn drop(&mut self) {
+        // lets the server know right away, instead of when it stops hearing from us
+        self.close();
+    }
+}
@@ -0,0 +1,101 @@
+use mirror::Remote;
+use std::rc::Rc;
+use std::cell::RefCell;
+use stdweb::web::{SocketBinaryType, WebSocket};
+use stdweb::web::event::{SocketCloseEvent, SocketErrorEvent, SocketMessageEvent,
+                         SocketMessageData, SocketOpenEvent};
+use stdweb::traits::*;
+use tetris_model::wire::Decoder;
+use super::{hello, Refusal};
+
+struct Inner {
+    socket: WebSocket,
+    messages: Vec<String>,
+    alive: bool,
+}
+
+pub struct WsConnection {
+    inner: Rc<RefCell<Inner>>,
+    refusal: Refusal,
+}
+
+impl WsConnection {
+    pub fn new(uri: &str, refusal: Refusal) -> Result<Self, String> {
+        let ws = WebSocket::new(uri).map_err(|e| format!("Unable to connect to {}: {}", uri, e))?;
+        ws.set_binary_type(SocketBinaryType::ArrayBuffer);
+        let inner = Rc::new(RefCell::new(Inner {
+            socket: ws,
+            messages: Vec::new(),
+            alive: true,
+        }));
+
+        let i = inner.clone();
+        inner.borrow().socket.add_event_listener(move |_: SocketOpenEvent| {
+            let mut inner = i.borrow_mut();
+            inner.alive &= inner.socket.send_text(hello().as_str()).is_ok();
+        });
+
+        let i = inner.clone();
+        inner.borrow().socket.add_event_listener(move |_: SocketErrorEvent| {
+            i.borrow_mut().alive = false;
+        });
+
+        let i = inner.clone();
+        inner.borrow().socket.add_event_listener(move |_: SocketCloseEvent| {
+            i.borrow_mut().alive = false;
+        });
+
+        let i = inner.clone();
+        let r = refusal.clone();
+        let mut decoder = Decoder::new();
+        inner.borrow().socket.add_event_listener(move |event: SocketMessageEvent| {
+            let mut inner = i.borrow_mut();
+            match event.data() {
+                SocketMessageData::Text(text) => match r.handshake(text.as_str()) {
+                    Some(true) => (),
+                    Some(false) => inner.alive = false,
+                    None => inner.messages.push(text),
+                },
+                SocketMessageData::ArrayBuffer(buffer) => {
+                    match decoder.decode(Vec::<u8>::from(buffer).as_slice()) {
+                        Ok(messages) => inner.messages.extend(messages),
+                        Err(_) => inner.alive = false,
+                    }
+                },
+                _ => inner.alive = false,
+            }
+        });
+
+        Ok(WsConnection { inner, refusal })
+    }
+
+    /// Returns true if the server refused us because of our protocol version.
+    pub fn refused(&self) -> bool {
+        self.refusal.refused()
+    }
+}
+
+impl Remote for WsConnection {
+    fn close(&mut self) {
+        self.inner.borrow().socket.close();
+    }
+
+    fn alive(&self) -> bool {
+        self.inner.borrow().alive
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), mirror::Error> {
+        let ok = self.inner.borrow().socket.send_text(message).is_ok();
+        self.inner.borrow_mut().alive &= ok;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        let m = &mut self.inner.borrow_mut().messages;
+        if m.len() > 0 {
+            Some(m.remove(0))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,15 @@
+pub mod connection;
+pub mod clock;
+pub mod prediction;
+
+/// Returns the time in milliseconds since the unix epoch according to the local clock.
+#[cfg(target_arch="wasm32")]
+pub fn local_millis() -> u64 {
+    stdweb::web::Date::now() as u64
+}
+
+/// Returns the time in milliseconds since the unix epoch according to the local clock.
+#[cfg(not(target_arch="wasm32"))]
+pub fn local_millis() -> u64 {
+    tetris_model::instance::unix_millis()
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use tetris_model::instance::{ActiveState, PlayerState};
+
+/// Something the player did that's applied locally before the server confirms it.
+#[derive(Clone, Copy)]
+pub enum Input {
+    Drop(ActiveState),
+    Hold,
+}
+
+/// Keeps track of the inputs that were applied to the local copy of the player's state, but not
+/// yet by the server. Inputs are numbered, the server publishes the number of the last input it
+/// applied along with a checksum of the player's state every now and then. Once it has applied
+/// all inputs, the local copy should match it. When it doesn't, the client asks for a copy of the
+/// server's state and applies the inputs it's still waiting for on top.
+pub struct Prediction {
+    sent: usize,
+    pending: VecDeque<(usize, Input)>,
+    checksum: (usize, u32),
+    resyncs: usize,
+    resyncing: bool,
+}
+
+impl Prediction {
+    pub fn new() -> Self {
+        Self {
+            sent: 0,
+            pending: VecDeque::new(),
+            checksum: (0, 0),
+            resyncs: 0,
+            resyncing: false,
+        }
+    }
+
+    /// Starts over on a new connection, which comes with a fresh copy of the server's state.
+    /// Inputs that were lost along with the old connection show up as a mismatch later on.
+    pub fn reconnected(&mut self, game: &mut PlayerState) {
+        self.resyncs = 0;
+        self.resyncing = false;
+        self.checksum = game.checksum;
+        for &(_, input) in self.pending.iter() {
+            apply(game, input);
+        }
+    }
+
+    /// Applies an input to the local copy of the player's state. Returns the number of the
+    /// input, which should be sent to the server along with it.
+    pub fn input(&mut self, game: &mut PlayerState, input: Input) -> usize {
+        self.sent += 1;
+        self.pending.push_back((self.sent, input));
+        apply(game, input);
+        self.sent
+    }
+
+    /// Compares the local copy of the player's state to the last checksum from the server.
+    /// `resynced` is how many copies of the state the server sent on this connection.
+    /// Returns true if the client should ask the server for a copy of its state.
+    pub fn check(&mut self, game: &mut PlayerState, resynced: usize) -> bool {
+        let checksum = game.checksum;
+
+        // inputs the server has applied aren't predictions anymore
+        let applied = checksum.0;
+        while self.pending.front().map(|&(i, _)| i <= applied).unwrap_or(false) {
+            self.pending.pop_front();
+        }
+
+        if self.resyncing {
+            if resynced >= self.resyncs {
+                // the copy replaced everything that was predicted, so predict it again
+                self.resyncing = false;
+                self.checksum = checksum;
+                for &(_, input) in self.pending.iter() {
+                    apply(game, input);
+                }
+            }
+            return false;
+        }
+
+        if checksum == self.checksum {
+            return false;
+        }
+        self.checksum = checksum;
+
+        if applied == self.sent && game.state_hash() != checksum.1 {
+            self.resyncs += 1;
+            self.resyncing = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Updates the local copy of the player's state the same way the server will.
+fn apply(game: &mut PlayerState, input: Input) {
+    match input {
+        Input::Drop(state) => {
+            // update the field in advance
+            for y in 0..4 {
+                for x in 0..4 {
+                    let shape = game.current as usize;
+                    let rotation = state.rotation as usize;
+                    let col = tetris_model::shapes::SHAPES[shape][rotation][x + y * 4];
+                    if col != 0 && state.y + y as i32 >= 0 {
+                        let index = ((state.y + y as i32) * 10 + state.x + x as i32) as usize;
+                        game.field[index] = col;
+                    }
+                }
+            }
+
+            // update the current tetrimino in advance
+            game.current = game.next[0];
+        },
+        Input::Hold => {
+            game.held = true;
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_matching_checksum_is_accepted() {
+        let mut game = PlayerState::new();
+        let mut prediction = Prediction::new();
+
+        assert_eq!(prediction.input(&mut game, Input::Hold), 1);
+        game.checksum = (1, game.state_hash());
+        assert!(!prediction.check(&mut game, 0));
+        assert!(prediction.pending.is_empty());
+    }
+
+    #[test]
+    fn a_mismatching_checksum_asks_for_a_copy_once() {
+        let mut game = PlayerState::new();
+        let mut prediction = Prediction::new();
+
+        prediction.input(&mut game, Input::Hold);
+        game.checksum = (1, game.state_hash() ^ 1);
+        assert!(prediction.check(&mut game, 0));
+
+        // wait for the copy instead of asking again
+        assert!(!prediction.check(&mut game, 0));
+
+        // the copy comes with the inputs the server applied, nothing is predicted on top
+        game.held = false;
+        assert!(!prediction.check(&mut game, 1));
+        assert!(!game.held);
+
+        // a later mismatch asks for another copy
+        prediction.input(&mut game, Input::Hold);
+        game.checksum = (2, game.state_hash() ^ 1);
+        assert!(prediction.check(&mut game, 1));
+    }
+
+    #[test]
+    fn a_stale_checksum_is_ignored() {
+        let mut game = PlayerState::new();
+        let mut prediction = Prediction::new();
+
+        prediction.input(&mut game, Input::Hold);
+        prediction.input(&mut game, Input::Hold);
+
+        // the server hasn't seen the second input yet, so the states can't be compared
+        game.checksum = (1, game.state_hash() ^ 1);
+        assert!(!prediction.check(&mut game, 0));
+        assert_eq!(prediction.pending.len(), 1);
+
+        // and the same checksum isn't looked at again
+        assert!(!prediction.check(&mut game, 0));
+    }
+}
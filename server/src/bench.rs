@@ -0,0 +1,319 @@
+use crate::config::Config;
+use crate::game::{Entrant, GameInstance};
+use crate::instance::{InstanceContainer, Lookup};
+use crate::rating::RatingStore;
+use crate::scheduler::{Scheduler, SchedulerStats, Waker};
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io::ErrorKind;
+use std::mem::replace;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TryRecvError, channel, sync_channel};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use futures::{Async, Future};
+use mirror::{Client, Error, Remote};
+use rand::random;
+use tetris_model::instance::InstanceState;
+
+/// Players in every benchmark match.
+const BOTS: usize = 9;
+
+/// The connection of a bot, as seen by its game instance.
+pub struct BenchConnection {
+    rx: Receiver<String>,
+    tx: Sender<String>,
+    alive: bool,
+    received: Arc<AtomicUsize>,
+}
+
+/// The connection of a bot, as seen by the bot.
+struct BotRemote {
+    tx: SyncSender<String>,
+    rx: Receiver<String>,
+    alive: bool,
+    sent: Arc<AtomicUsize>,
+}
+
+type Connecting = Box<Future<Item=Client<InstanceState, BotRemote>, Error=String>>;
+
+enum Link {
+    /// The instance didn't take the connection yet.
+    Waiting,
+    /// The instance took the connection, the bot waits for the state of the match.
+    Connecting(Connecting),
+    Connected(Client<InstanceState, BotRemote>),
+    Lost,
+}
+
+/// A bot that logs in and then drops pieces at random, a bit faster than most people play.
+struct Bot {
+    key: String,
+    index: usize,
+    link: Link,
+    next: Instant,
+    inputs: usize,
+}
+
+struct BenchMatch {
+    address: String,
+    bots: Vec<Bot>,
+    waker: Option<Waker>,
+}
+
+impl Remote for BenchConnection {
+    fn close(&mut self) {
+        self.alive = false;
+    }
+
+    fn alive(&self) -> bool {
+        self.alive
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), Error> {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        // the bot may have given up on the match already
+        self.tx.send(message.to_string()).ok();
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        match self.rx.try_recv() {
+            Ok(message) => Some(message),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.alive = false;
+                None
+            },
+        }
+    }
+}
+
+impl Remote for BotRemote {
+    fn close(&mut self) {
+        self.alive = false;
+    }
+
+    fn alive(&self) -> bool {
+        self.alive
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), Error> {
+        // messages that don't fit in the queue are lost, like with a congested connection
+        if self.tx.try_send(message.to_string()).is_ok() {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        match self.rx.try_recv() {
+            Ok(message) => Some(message),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.alive = false;
+                None
+            },
+        }
+    }
+}
+
+impl Bot {
+    /// Keeps the bot's copy of the match up to date, and makes its next move if it's time.
+    fn update(&mut self, now: Instant) {
+        self.link = match replace(&mut self.link, Link::Lost) {
+            Link::Connecting(mut connecting) => match connecting.poll() {
+                Ok(Async::Ready(mut client)) => {
+                    let login = format!("call:login:\"{}\" \"bot{}\"", self.key, self.index);
+                    client.command(login.as_str()).ok();
+                    Link::Connected(client)
+                },
+                Ok(Async::NotReady) => Link::Connecting(connecting),
+                Err(_) => Link::Lost,
+            },
+            Link::Connected(mut client) => {
+                client.update();
+                if let Some(message) = self.message(&client, now) {
+                    client.command(message.as_str()).ok();
+                }
+                Link::Connected(client)
+            },
+            link => link,
+        };
+    }
+
+    /// Returns the next message the bot wants to send, if it's time for one.
+    fn message(&mut self, state: &InstanceState, now: Instant) -> Option<String> {
+        if now < self.next || !state.in_game(self.index) {
+            return None;
+        }
+        self.next = now + Duration::from_millis(200 + random::<u64>() % 200);
+        self.inputs += 1;
+
+        let game = &state.games[self.index];
+        if random::<u32>() % 8 == 0 {
+            Some(format!("call:hold:{}", self.inputs))
+        } else {
+            let landings = game.landings();
+            let landing = landings[random::<usize>() % landings.len()];
+            let landing = serde_json::to_string(&landing).unwrap();
+            Some(format!("call:drop:{} {} {}", landing, self.inputs, game.forced))
+        }
+    }
+}
+
+/// Keeps `matches` bot matches running for `seconds` and reports how well the scheduler keeps
+/// up. Ratings and replays are written to a temporary directory that's removed afterwards.
+pub fn run(config: &Config, matches: usize, seconds: u64) -> ::std::io::Result<()> {
+    let dir = ::std::env::temp_dir().join(format!("tutris-bench-{:08x}", random::<u32>()));
+    create_dir_all(&dir)?;
+
+    let mut config = config.clone();
+    config.replay_dir = dir.join("replays");
+    let config = Arc::new(config);
+    let ratings = Arc::new(Mutex::new(RatingStore::open(dir.join("ratings.json"))?));
+
+    let scheduler = Scheduler::new(config.workers);
+    let container = Arc::new(Mutex::new(InstanceContainer::new(matches, scheduler.clone())));
+    let received = Arc::new(AtomicUsize::new(0));
+    let sent = Arc::new(AtomicUsize::new(0));
+
+    println!("Benchmarking {} matches of {} bots on {} workers for {} seconds",
+             matches, BOTS, config.workers, seconds);
+
+    let mut running: Vec<BenchMatch> = Vec::new();
+    let mut finished = 0;
+
+    let started = Instant::now();
+    let initial = scheduler.stats();
+    let mut report = (started, initial, 0, 0);
+
+    while started.elapsed() < Duration::from_secs(seconds) {
+        let now = Instant::now();
+
+        // replace matches that are over
+        let before = running.len();
+        running.retain(|m| {
+            container.lock().unwrap().lookup(m.address.as_str()) == Lookup::Running
+        });
+        finished += before - running.len();
+
+        while running.len() < matches {
+            let bots: Vec<Bot> = (0..BOTS)
+                .enumerate()
+                .map(|(index, _)| Bot {
+                    key: format!("{:x}-{:x}", random::<u64>(), random::<u64>()),
+                    index,
+                    link: Link::Waiting,
+                    next: now,
+                    inputs: 0,
+                })
+                .collect();
+            let entrants = bots
+                .iter()
+                .enumerate()
+                .map(|(i, bot)| Entrant {
+                    key: bot.key.clone(),
+                    identity: String::new(),
+                    nickname: format!("bot{}", i),
+                })
+                .collect();
+
+            let game_config = config.clone();
+            let game_ratings = ratings.clone();
+            let interval = config.game.tick();
+            let latency = container.lock().unwrap().latency();
+            let address = InstanceContainer::create(&container, move |listener, _| {
+                Box::new(GameInstance::new(listener, entrants, game_config, game_ratings, latency))
+            }, interval)?;
+
+            running.push(BenchMatch { address, bots, waker: None });
+        }
+
+        for m in running.iter_mut() {
+            for bot in m.bots.iter_mut() {
+                // instances only take a few connections at a time, try again later if it's busy
+                if let Link::Waiting = bot.link {
+                    let (tx, rx) = sync_channel(64);
+                    let (bot_tx, bot_rx) = channel();
+                    let connection = BenchConnection {
+                        rx,
+                        tx: bot_tx,
+                        alive: true,
+                        received: received.clone(),
+                    };
+                    let result = container
+                        .lock()
+                        .unwrap()
+                        .submit(m.address.as_str(), connection);
+                    match result {
+                        Ok(waker) => {
+                            let remote = BotRemote {
+                                tx,
+                                rx: bot_rx,
+                                alive: true,
+                                sent: sent.clone(),
+                            };
+                            let connecting = Client::new(remote).map_err(|e| format!("{:?}", e));
+                            bot.link = Link::Connecting(Box::new(connecting));
+                            m.waker = Some(waker);
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                        Err(_) => break,
+                    }
+                }
+
+                bot.update(now);
+            }
+
+            if let Some(waker) = m.waker.as_ref() {
+                waker.wake();
+            }
+        }
+
+        if report.0.elapsed() >= Duration::from_secs(1) {
+            let stats = scheduler.stats();
+            let sent = sent.load(Ordering::Relaxed);
+            let received = received.load(Ordering::Relaxed);
+            print_stats("", report.0.elapsed(), &report.1, &stats,
+                        sent - report.2, received - report.3, config.workers);
+            report = (Instant::now(), stats, sent, received);
+        }
+
+        sleep(Duration::from_millis(10));
+    }
+
+    println!("Finished {} matches", finished);
+    print_stats("Total: ", started.elapsed(), &initial, &scheduler.stats(),
+                sent.load(Ordering::Relaxed), received.load(Ordering::Relaxed), config.workers);
+
+    remove_dir_all(&dir).ok();
+
+    Ok(())
+}
+
+fn print_stats(prefix: &str,
+               elapsed: Duration,
+               before: &SchedulerStats,
+               after: &SchedulerStats,
+               sent: usize,
+               received: usize,
+               workers: usize) {
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    let millis = |d: Duration| d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 * 1e-6;
+
+    let polls = after.polls - before.polls;
+    let lag = millis(after.lag) - millis(before.lag);
+    let busy = millis(after.busy) - millis(before.busy);
+
+    println!("{}{} tasks, {:.0} polls/s, {:.0} messages in/s, {:.0} messages out/s, \
+              lag {:.2}ms avg {:.2}ms max, workers {:.0}% busy",
+             prefix,
+             after.tasks,
+             polls as f64 / seconds,
+             sent as f64 / seconds,
+             received as f64 / seconds,
+             if polls > 0 { lag / polls as f64 } else { 0.0 },
+             millis(after.max_lag),
+             busy / (seconds * 10.0 * workers as f64));
+}
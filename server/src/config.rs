@@ -0,0 +1,546 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs::{metadata, read_to_string};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use serde::*;
+use tetris_model::matchmaking::{QueueRules, default_queues};
+
+/// Whether the server should serve over TLS.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Use TLS only if both the certificate and the key file exist.
+    Auto,
+    On,
+    Off,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub mode: TlsMode,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// Seconds between two pings the server sends on every websocket.
+    pub ping_interval: u64,
+    /// Seconds a websocket may go without sending anything before it's closed.
+    pub timeout: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    /// Shortest time in milliseconds between two updates of a game instance.
+    pub tick_ms: u64,
+    /// Seconds a disconnected player may take to rejoin a match before forfeiting. Every match
+    /// publishes this to its clients, so they know when to stop trying.
+    pub disconnect_timeout: u64,
+    /// Seconds a player may go without input before forfeiting a match.
+    pub idle_timeout: u64,
+    /// Milliseconds between snapshots of the boards a player isn't following closely.
+    pub snapshot_ms: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// Shortest time in milliseconds between two updates of the matchmaker.
+    pub tick_ms: u64,
+    /// Seconds to wait for a client to identify itself, after which it's matched with a default
+    /// rating.
+    pub identify_timeout: u64,
+    /// Rating difference that is always accepted between a player and the match they join.
+    pub band_base: f64,
+    /// How much the accepted rating difference grows for every second a player has been waiting.
+    pub band_growth: f64,
+    pub queues: Vec<QueueRules>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<String>,
+    pub static_dir: PathBuf,
+    pub replay_dir: PathBuf,
+    pub ratings: PathBuf,
+    /// The maximum number of game instances that may run at the same time.
+    pub max_instances: usize,
+    /// The number of threads instances are run on.
+    pub workers: usize,
+    pub tls: TlsConfig,
+    pub connection: ConnectionConfig,
+    pub game: GameConfig,
+    pub matchmaking: MatchmakingConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, ::std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Argument(String),
+    Invalid(Vec<String>),
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            mode: TlsMode::Auto,
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+        }
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 5,
+            timeout: 15,
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            tick_ms: 15,
+            disconnect_timeout: 30,
+            idle_timeout: 60,
+            snapshot_ms: 250,
+        }
+    }
+}
+
+impl Default for MatchmakingConfig {
+    fn default() -> Self {
+        Self {
+            tick_ms: 500,
+            identify_timeout: 2,
+            band_base: 100.0,
+            band_growth: 10.0,
+            queues: default_queues(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1:3000".to_string()],
+            static_dir: PathBuf::from("static"),
+            replay_dir: PathBuf::from("static/replays"),
+            ratings: PathBuf::from("ratings.json"),
+            max_instances: 256,
+            workers: 4,
+            tls: TlsConfig::default(),
+            connection: ConnectionConfig::default(),
+            game: GameConfig::default(),
+            matchmaking: MatchmakingConfig::default(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        match self {
+            &ConfigError::Io(ref path, ref e) => {
+                write!(f, "unable to read {}: {}", path.display(), e)
+            },
+            &ConfigError::Parse(ref path, ref e) => {
+                write!(f, "error in {}: {}", path.display(), e)
+            },
+            &ConfigError::Argument(ref e) => write!(f, "{}", e),
+            &ConfigError::Invalid(ref errors) => {
+                write!(f, "invalid configuration:")?;
+                for error in errors.iter() {
+                    write!(f, "\n  - {}", error)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Parses the numeric command line flag `name`, if it was given.
+fn number<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, ConfigError> {
+    match matches.value_of(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            ConfigError::Argument(format!("--{} expects a number, got {}", name, value))
+        }),
+        None => Ok(None),
+    }
+}
+
+impl ConnectionConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl GameConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    pub fn disconnect_timeout(&self) -> Duration {
+        Duration::from_secs(self.disconnect_timeout)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_millis(self.snapshot_ms)
+    }
+}
+
+impl MatchmakingConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    pub fn identify_timeout(&self) -> Duration {
+        Duration::from_secs(self.identify_timeout)
+    }
+}
+
+impl Config {
+    /// Loads the configuration file given with `--config`, if any, and applies the
+    /// command line flags on top of it.
+    pub fn from_args(matches: &ArgMatches) -> Result<Self, ConfigError> {
+        let mut config = match matches.value_of("config") {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+
+        if let Some(bind) = matches.values_of("bind-to") {
+            config.bind = bind.map(|b| b.to_string()).collect();
+        }
+        if let Some(dir) = matches.value_of("static-dir") {
+            config.static_dir = PathBuf::from(dir);
+        }
+        if let Some(dir) = matches.value_of("replay-dir") {
+            config.replay_dir = PathBuf::from(dir);
+        }
+        if let Some(path) = matches.value_of("ratings") {
+            config.ratings = PathBuf::from(path);
+        }
+        if let Some(mode) = matches.value_of("tls") {
+            config.tls.mode = match mode {
+                "auto" => TlsMode::Auto,
+                "on" => TlsMode::On,
+                "off" => TlsMode::Off,
+                other => {
+                    let error = format!("--tls expects auto, on or off, got {}", other);
+                    return Err(ConfigError::Argument(error));
+                },
+            };
+        }
+        if let Some(path) = matches.value_of("tls-cert") {
+            config.tls.cert = PathBuf::from(path);
+        }
+        if let Some(path) = matches.value_of("tls-key") {
+            config.tls.key = PathBuf::from(path);
+        }
+        if let Some(n) = number(matches, "max-instances")? {
+            config.max_instances = n;
+        }
+        if let Some(n) = number(matches, "workers")? {
+            config.workers = n;
+        }
+        if let Some(n) = number(matches, "connection-timeout")? {
+            config.connection.timeout = n;
+        }
+        if let Some(n) = number(matches, "tick")? {
+            config.game.tick_ms = n;
+        }
+        if let Some(n) = number(matches, "disconnect-timeout")? {
+            config.game.disconnect_timeout = n;
+        }
+        if let Some(n) = number(matches, "idle-timeout")? {
+            config.game.idle_timeout = n;
+        }
+        if let Some(n) = number(matches, "matchmaking-tick")? {
+            config.matchmaking.tick_ms = n;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(text.as_str()).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Returns true if the server should serve over TLS.
+    pub fn use_tls(&self) -> bool {
+        match self.tls.mode {
+            TlsMode::Auto => metadata(&self.tls.cert).is_ok() && metadata(&self.tls.key).is_ok(),
+            TlsMode::On => true,
+            TlsMode::Off => false,
+        }
+    }
+
+    /// Loads the TLS certificate chain and private key, and checks that they belong together.
+    pub fn tls_acceptor(&self) -> Result<SslAcceptorBuilder, String> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+            .map_err(|e| format!("unable to set up tls: {}", e))?;
+        builder.set_private_key_file(&self.tls.key, SslFiletype::PEM)
+            .map_err(|e| format!("unable to load tls key {}: {}", self.tls.key.display(), e))?;
+        builder.set_certificate_chain_file(&self.tls.cert)
+            .map_err(|e| format!("unable to load tls certificate {}: {}", self.tls.cert.display(),
+                                 e))?;
+        builder.check_private_key()
+            .map_err(|e| format!("tls key {} doesn't belong to certificate {}: {}",
+                                 self.tls.key.display(), self.tls.cert.display(), e))?;
+        Ok(builder)
+    }
+
+    /// Checks the configuration for values the server can't work with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.bind.is_empty() {
+            errors.push("at least one bind address is required".to_string());
+        }
+        for bind in self.bind.iter() {
+            if bind.to_socket_addrs().map(|mut a| a.next().is_none()).unwrap_or(true) {
+                errors.push(format!("bind address {} is not a valid host:port", bind));
+            }
+        }
+
+        if !self.static_dir.is_dir() {
+            errors.push(format!("static directory {} does not exist", self.static_dir.display()));
+        }
+
+        if self.tls.mode == TlsMode::On {
+            for file in [&self.tls.cert, &self.tls.key].iter() {
+                if metadata(file).is_err() {
+                    errors.push(format!("tls is on, but {} does not exist", file.display()));
+                }
+            }
+        }
+        if self.use_tls() && metadata(&self.tls.cert).is_ok() && metadata(&self.tls.key).is_ok() {
+            if let Err(e) = self.tls_acceptor() {
+                errors.push(e);
+            }
+        }
+
+        if self.max_instances == 0 {
+            errors.push("max_instances must be at least 1".to_string());
+        }
+        if self.workers == 0 {
+            errors.push("workers must be at least 1".to_string());
+        }
+        if self.connection.ping_interval == 0 {
+            errors.push("connection.ping_interval must be at least 1 second".to_string());
+        }
+        if self.connection.timeout <= self.connection.ping_interval {
+            errors.push("connection.timeout must be longer than connection.ping_interval"
+                .to_string());
+        }
+        if self.game.tick_ms == 0 || self.game.tick_ms > 1000 {
+            errors.push("game.tick_ms must be between 1 and 1000".to_string());
+        }
+        if self.game.disconnect_timeout == 0 {
+            errors.push("game.disconnect_timeout must be at least 1 second".to_string());
+        }
+        if self.game.idle_timeout == 0 {
+            errors.push("game.idle_timeout must be at least 1 second".to_string());
+        }
+        if self.game.snapshot_ms == 0 || self.game.snapshot_ms > 10000 {
+            errors.push("game.snapshot_ms must be between 1 and 10000".to_string());
+        }
+        if self.matchmaking.tick_ms == 0 || self.matchmaking.tick_ms > 1000 {
+            errors.push("matchmaking.tick_ms must be between 1 and 1000".to_string());
+        }
+        if self.matchmaking.band_base < 0.0 || self.matchmaking.band_growth < 0.0 {
+            errors.push("matchmaking rating bands can't be negative".to_string());
+        }
+
+        if self.matchmaking.queues.is_empty() {
+            errors.push("at least one matchmaking queue is required".to_string());
+        }
+        let mut names = HashSet::new();
+        for queue in self.matchmaking.queues.iter() {
+            if !names.insert(queue.name.as_str()) {
+                errors.push(format!("queue {} is defined more than once", queue.name));
+            }
+            if queue.name.is_empty() {
+                errors.push("queue names can't be empty".to_string());
+            }
+            if queue.min_players < 2 {
+                errors.push(format!("queue {} needs a min_players of at least 2", queue.name));
+            }
+            if queue.min_players > queue.max_players {
+                errors.push(format!("queue {} has min_players above max_players", queue.name));
+            }
+            if queue.max_players > 9 {
+                errors.push(format!("queue {} allows more than 9 players", queue.name));
+            }
+            if queue.retry_time == 0 {
+                errors.push(format!("queue {} needs a retry_time of at least 1", queue.name));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+    use rand::random;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    /// A directory to keep configuration files in, and to serve static files from.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new() -> Self {
+            let name = format!("tutris-config-{:08x}", random::<u32>());
+            let path = ::std::env::temp_dir().join(name);
+            create_dir_all(&path).unwrap();
+            Dir(path)
+        }
+
+        /// Writes a configuration file and returns its path.
+        fn config(&self, text: &str) -> String {
+            let path = self.0.join("config.toml");
+            write(&path, text).unwrap();
+            path.display().to_string()
+        }
+
+        fn path(&self) -> String {
+            self.0.display().to_string()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// Parses a command line with the flags the server reads its configuration from.
+    fn from_args(args: &[&str]) -> Result<Config, ConfigError> {
+        let flags = ["config", "static-dir", "tls", "max-instances", "workers", "tick",
+                     "idle-timeout"];
+        let app = flags
+            .iter()
+            .fold(App::new("test"), |app, &flag| app.arg(Arg::with_name(flag)
+                .long(flag)
+                .takes_value(true)));
+        let args = Some("test").into_iter().chain(args.iter().cloned());
+        Config::from_args(&app.get_matches_from_safe(args).unwrap())
+    }
+
+    fn invalid(result: Result<Config, ConfigError>) -> Vec<String> {
+        match result {
+            Err(ConfigError::Invalid(errors)) => errors,
+            Err(e) => panic!("expected invalid values, got {}", e),
+            Ok(_) => panic!("expected invalid values"),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let dir = Dir::new();
+        let config = from_args(&["--static-dir", dir.path().as_str(), "--tls", "off"]).unwrap();
+
+        assert_eq!(config.bind, vec!["127.0.0.1:3000".to_string()]);
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.game.tick_ms, 15);
+        assert_eq!(config.matchmaking.queues.len(), default_queues().len());
+    }
+
+    #[test]
+    fn files_override_defaults() {
+        let dir = Dir::new();
+        let path = dir.config("workers = 2\n\n[game]\ntick_ms = 20\n");
+        let config = Config::load(path).unwrap();
+
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.game.tick_ms, 20);
+        assert_eq!(config.game.idle_timeout, 60);
+        assert_eq!(config.max_instances, 256);
+    }
+
+    #[test]
+    fn flags_override_files() {
+        let dir = Dir::new();
+        let path = dir.config("workers = 2\nmax_instances = 10\n\n[game]\ntick_ms = 20\n");
+        let config = from_args(&["--config", path.as_str(), "--static-dir", dir.path().as_str(),
+                                 "--tls", "off", "--workers", "8", "--idle-timeout", "90"])
+            .unwrap();
+
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.max_instances, 10);
+        assert_eq!(config.game.tick_ms, 20);
+        assert_eq!(config.game.idle_timeout, 90);
+    }
+
+    #[test]
+    fn malformed_values_are_rejected() {
+        let dir = Dir::new();
+
+        match Config::load(dir.config("wokrers = 2\n")) {
+            Err(ConfigError::Parse(..)) => (),
+            _ => panic!("unknown fields should be rejected"),
+        }
+        match Config::load(dir.config("workers = \"many\"\n")) {
+            Err(ConfigError::Parse(..)) => (),
+            _ => panic!("values of the wrong type should be rejected"),
+        }
+        match from_args(&["--workers", "many"]) {
+            Err(ConfigError::Argument(..)) => (),
+            _ => panic!("flags that aren't numbers should be rejected"),
+        }
+        match from_args(&["--tls", "maybe"]) {
+            Err(ConfigError::Argument(..)) => (),
+            _ => panic!("unknown tls modes should be rejected"),
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let dir = Dir::new();
+        let path = dir.config("[connection]\nping_interval = 10\ntimeout = 5\n");
+        let static_dir = dir.path();
+
+        let errors = invalid(from_args(&["--config", path.as_str(), "--static-dir",
+                                         static_dir.as_str(), "--workers", "0", "--tick", "0"]));
+        assert_eq!(errors, vec![
+            "workers must be at least 1".to_string(),
+            "connection.timeout must be longer than connection.ping_interval".to_string(),
+            "game.tick_ms must be between 1 and 1000".to_string(),
+        ]);
+
+        let errors = invalid(from_args(&["--static-dir", "does/not/exist"]));
+        assert_eq!(errors, vec!["static directory does/not/exist does not exist".to_string()]);
+    }
+}
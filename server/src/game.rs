@@ -1,31 +1,255 @@
-use std::sync::mpsc::Receiver;
-use std::time::Duration;
-use std::thread::sleep;
+use crate::config::Config;
+use crate::rating::RatingStore;
+use crate::replay::ReplayRecorder;
+use crate::scheduler::{Poll, Task};
+use crate::session::{Latency, Roster, Session};
+use crate::view::Boards;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::{Duration, Instant};
 use mirror::*;
 
-pub fn run_game_server<R>(listener: Receiver<R>,
-                          users: Vec<String>) -> Result<(), Error> where
-    R: Remote
-{
-    let instance = tetris_model::instance::InstanceState::new(users);
+/// A player that was matched into a game.
+#[derive(Clone)]
+pub struct Entrant {
+    pub key: String,
+    pub identity: String,
+    pub nickname: String,
+}
 
-    let mut server = SharedServer::new(instance, listener);
+/// Everything that can be connected to a game instance.
+pub enum Endpoint<R: Remote> {
+    Player(Session<R>),
+    Recorder(ReplayRecorder),
+}
 
-    println!("Game instance started");
+impl<R: Remote> Remote for Endpoint<R> {
+    fn close(&mut self) {
+        match self {
+            Endpoint::Player(remote) => remote.close(),
+            Endpoint::Recorder(recorder) => recorder.close(),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        match self {
+            Endpoint::Player(remote) => remote.alive(),
+            Endpoint::Recorder(recorder) => recorder.alive(),
+        }
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), Error> {
+        match self {
+            Endpoint::Player(remote) => remote.send(message),
+            Endpoint::Recorder(recorder) => recorder.send(message),
+        }
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        match self {
+            Endpoint::Player(remote) => remote.recv(),
+            Endpoint::Recorder(recorder) => recorder.recv(),
+        }
+    }
+}
+
+/// How often clients get to check their copy of their own state against the server's.
+const CHECKSUM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A running match. It's polled whenever a player sends something, and at least once a second
+/// to run timers and check on players that went quiet.
+pub struct GameInstance<R: Remote> {
+    listener: Receiver<R>,
+    entrants: Vec<Entrant>,
+    users: Vec<String>,
+    roster: Roster,
+    boards: Boards,
+    endpoints: Sender<Endpoint<R>>,
+    server: SharedServer<tetris_model::instance::InstanceState, Endpoint<R>>,
+    /// Whether the replay recorder is still connected, if there is one.
+    recording: Option<Arc<AtomicBool>>,
+    config: Arc<Config>,
+    ratings: Arc<Mutex<RatingStore>>,
+    started_at: Option<Instant>,
+    ending_at: Option<Instant>,
+    checksum_at: Instant,
+}
+
+impl<R: Remote> GameInstance<R> {
+    pub fn new(listener: Receiver<R>,
+               entrants: Vec<Entrant>,
+               config: Arc<Config>,
+               ratings: Arc<Mutex<RatingStore>>,
+               latency: Latency) -> Self {
+        let users: Vec<String> = entrants.iter().map(|e| e.key.clone()).collect();
+        let roster = Roster::new(users.as_slice(), latency);
+        let mut instance = tetris_model::instance::InstanceState::new(users.clone());
+        instance.disconnect_timeout = config.game.disconnect_timeout;
+
+        for (game, entrant) in instance.games.iter_mut().zip(entrants.iter()) {
+            game.rating = ratings.lock().unwrap().get(entrant.identity.as_str()).rating;
+            game.nickname = entrant.nickname.clone();
+        }
+
+        let (endpoints, endpoint_listener) = channel();
+
+        // the recorder is connected first, so it captures the full initial state
+        let recording = match ReplayRecorder::create(&config.replay_dir, &instance) {
+            Ok((recorder, name)) => {
+                println!("Recording replay to {}", name);
+                instance.replay = name;
+                let recording = recorder.recording();
+                endpoints.send(Endpoint::Recorder(recorder)).ok();
+                Some(recording)
+            },
+            Err(e) => {
+                println!("Unable to record replay: {}", e);
+                None
+            },
+        };
+
+        println!("Game instance started");
+
+        Self {
+            listener,
+            entrants,
+            users,
+            roster,
+            boards: Boards::new(instance.games.as_slice()),
+            endpoints,
+            server: SharedServer::new(instance, endpoint_listener),
+            recording,
+            config,
+            ratings,
+            started_at: None,
+            ending_at: None,
+            checksum_at: Instant::now(),
+        }
+    }
+
+    fn step(&mut self) -> Result<Poll, Error> {
+        let now = Instant::now();
+        if let Some(ending_at) = self.ending_at {
+            // give the clients a moment to receive the final state
+            if now < ending_at {
+                return Ok(Poll::Wait(ending_at));
+            }
+            println!("Game instance terminating");
+            return Ok(Poll::Done);
+        }
+
+        let disconnect_timeout = self.config.game.disconnect_timeout();
+        let idle_timeout = self.config.game.idle_timeout();
+        let server = &mut self.server;
+
+        while let Ok(remote) = self.listener.try_recv() {
+            let session = Session::new(remote, self.roster.clone(), self.boards.clone(),
+                                       self.config.game.snapshot_interval());
+            self.endpoints.send(Endpoint::Player(session)).ok();
+        }
 
-    loop {
         server.update();
         server.local_command("call:server_update:")?;
 
-        if server.done || (server.started && server.clients() == 0) {
-            ::std::thread::sleep(Duration::from_secs(1));
-            break;
+        // players that are gone or stopped playing forfeit their seat
+        self.roster.refresh();
+        if server.started && !server.done {
+            let playing_for = self.started_at.get_or_insert(now).elapsed();
+
+            for (index, key) in self.users.iter().enumerate() {
+                if server.games[index].ko {
+                    continue;
+                }
+
+                let reason = if self.roster.disconnected_for(key.as_str())
+                    .map(|away| away > disconnect_timeout)
+                    .unwrap_or(false) {
+                    Some("disconnected")
+                } else if self.roster.idle_for(key.as_str()).min(playing_for) > idle_timeout {
+                    Some("idle")
+                } else {
+                    None
+                };
+
+                if let Some(reason) = reason {
+                    println!("Player {} forfeits: {}", index, reason);
+                    server.local_command(format!("call:forfeit:\"{}\" \"{}\"", key, reason)
+                        .as_str())?;
+                }
+            }
         }
 
-        sleep(Duration::from_millis(15));
-    }
+        if server.done {
+            let standings = server.standings();
+            let identities: Vec<String> = standings
+                .iter()
+                .map(|&i| self.entrants[i].identity.clone())
+                .collect();
+            let new_ratings = self.ratings.lock().unwrap().record_match(identities.as_slice());
 
-    println!("Game instance terminating");
+            for (&index, rating) in standings.iter().zip(new_ratings.iter()) {
+                server.local_command(format!("games/{}/rating/set:{}", index, rating.rating)
+                    .as_str())?;
+            }
+            server.update();
+        }
 
-    Ok(())
-}
\ No newline at end of file
+        // let clients check their copy of their own state every now and then
+        if server.started && now >= self.checksum_at {
+            self.checksum_at = now + CHECKSUM_INTERVAL;
+            for index in 0..server.games.len() {
+                let checksum = (server.state.inputs[index], server.games[index].state_hash());
+                if !server.games[index].ko && server.games[index].checksum != checksum {
+                    server.local_command(format!("games/{}/checksum/set:[{},{}]", index,
+                                                 checksum.0, checksum.1).as_str())?;
+                }
+            }
+        }
+
+        // nothing else is sent during this update, so this is what the boards look like to
+        // everyone that's connected
+        self.boards.publish(server.games.as_slice(), server.state.inputs.as_slice());
+
+        // the recorder is the only client that isn't a player, unless it stopped recording
+        let recorders = self.recording
+            .as_ref()
+            .filter(|recording| recording.load(Ordering::SeqCst))
+            .map_or(0, |_| 1);
+        let abandoned = server.clients() <= recorders &&
+            self.roster.abandoned(disconnect_timeout);
+        if server.done || (server.started && abandoned) {
+            for (index, key) in self.users.iter().enumerate() {
+                if let Some((last, max)) = self.roster.rtt(key.as_str()) {
+                    println!("Player {} round trip time: {} ms, at most {} ms", index, last, max);
+                }
+            }
+
+            let ending_at = now + Duration::from_secs(1);
+            self.ending_at = Some(ending_at);
+            return Ok(Poll::Wait(ending_at));
+        }
+
+        // nothing happens by itself before the start, other than the countdown running out
+        let mut wake_at = now + Duration::from_secs(1);
+        if !server.started {
+            wake_at = wake_at.min(server.state.deadline.max(now));
+        } else {
+            // snapshots of boards that changed are sent out on the next update, and tetriminos
+            // players take too long with are placed as soon as their time is up
+            wake_at = wake_at.min(now + self.config.game.snapshot_interval())
+                .min(self.checksum_at);
+            let locks = (0..server.games.len()).filter_map(|id| server.lock_deadline(id));
+            if let Some(lock) = locks.min() {
+                wake_at = wake_at.min(lock.max(now));
+            }
+        }
+        Ok(Poll::Wait(wake_at))
+    }
+}
+
+impl<R: Remote + Send> Task for GameInstance<R> {
+    fn poll(&mut self) -> Poll {
+        self.step().expect("Game server failed")
+    }
+}
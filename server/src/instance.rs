@@ -1,29 +1,243 @@
-use std::thread::*;
+use std::cell::Cell;
 use std::io::{Error, ErrorKind};
-use std::sync::{Arc, Weak, Mutex};
-use std::sync::mpsc::{SyncSender, Receiver, sync_channel};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Weak, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{SyncSender, Receiver, TrySendError, sync_channel};
+use std::time::{Duration, Instant};
 
+use crate::scheduler::{panic_message, Poll, Scheduler, Task, Waker};
+use crate::session::{Latency, LatencyStats};
 use mirror::Remote;
+use rand::random;
+use serde::*;
+
+thread_local! {
+    static CRASHING: Cell<bool> = Cell::new(false);
+}
+
+/// Returns true while an instance that crashed is being torn down on this thread.
+pub fn crashing() -> bool {
+    ::std::thread::panicking() || CRASHING.with(|c| c.get())
+}
+
+type Restart<R> = Box<FnMut(Receiver<R>) -> Box<Task> + Send>;
 
 pub struct Instance<R: Remote + Send + 'static> {
+    address: String,
     control: Weak<usize>,
     sender: SyncSender<R>,
+    waker: Waker,
 }
 
 pub struct InstanceContainer<R: Remote + Send + 'static> {
     instances: Vec<Instance<R>>,
+    scheduler: Scheduler,
+    limit: usize,
+    generation: u64,
+    crashes: Arc<AtomicUsize>,
+    restarts: Arc<AtomicUsize>,
+    latency: Latency,
+}
+
+/// A summary of the instances, for monitoring.
+#[derive(Serialize)]
+pub struct InstanceStats {
+    pub running: usize,
+    pub crashes: usize,
+    pub restarts: usize,
+    /// How many times instances were polled by the scheduler.
+    pub polls: u64,
+    /// The longest an instance had to wait for a worker, in milliseconds.
+    pub max_lag_ms: u64,
+    /// The round trip times players reported recently.
+    pub rtt: LatencyStats,
+}
+
+/// What an instance address refers to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Lookup {
+    Running,
+    /// The address was handed out once, but the instance has stopped since.
+    Expired,
+    Unknown,
+}
+
+/// Runs the task of an instance. Panics are caught, so a crashing instance doesn't take down the
+/// worker it runs on. Connections held by the instance are closed with an error when it's torn
+/// down after crashing.
+struct Supervised<R: Remote + Send + 'static> {
+    name: String,
+    task: Option<Box<Task>>,
+    restart: Option<Restart<R>>,
+    restart_at: Option<Instant>,
+    container: Weak<Mutex<InstanceContainer<R>>>,
+    crashes: Arc<AtomicUsize>,
+    restarts: Arc<AtomicUsize>,
+    _alive: Arc<usize>,
+}
+
+impl<R: Remote + Send + 'static> Task for Supervised<R> {
+    fn poll(&mut self) -> Poll {
+        if let Some(at) = self.restart_at {
+            if Instant::now() < at {
+                return Poll::Wait(at);
+            }
+            self.restart_at = None;
+
+            // hand the instance a new listener, the old one went down with it
+            let (tx, rx) = sync_channel(8);
+            if let Some(container) = self.container.upgrade() {
+                let mut container = InstanceContainer::lock(&container);
+                let name = self.name.as_str();
+                if let Some(i) = container.instances.iter_mut().find(|i| i.address == name) {
+                    i.sender = tx;
+                }
+            }
+            self.task = self.restart.as_mut().map(|restart| restart(rx));
+
+            self.restarts.fetch_add(1, Ordering::SeqCst);
+            println!("Restarted instance {}", self.name);
+        }
+
+        let panic = match self.task.as_mut() {
+            Some(task) => match catch_unwind(AssertUnwindSafe(|| task.poll())) {
+                Ok(poll) => return poll,
+                Err(panic) => panic,
+            },
+            None => return Poll::Done,
+        };
+
+        self.crashes.fetch_add(1, Ordering::SeqCst);
+        println!("Instance {} crashed: {}", self.name, panic_message(&panic));
+
+        CRASHING.with(|c| c.set(true));
+        self.task = None;
+        CRASHING.with(|c| c.set(false));
+
+        if self.restart.is_some() {
+            let at = Instant::now() + Duration::from_secs(1);
+            self.restart_at = Some(at);
+            Poll::Wait(at)
+        } else {
+            Poll::Done
+        }
+    }
 }
 
 impl<R: Remote + Send + 'static> InstanceContainer<R> {
-    pub fn new() -> Self {
+    /// Creates a container that runs at most `limit` instances at the same time on `scheduler`.
+    pub fn new(limit: usize, scheduler: Scheduler) -> Self {
         Self {
             instances: Vec::new(),
+            scheduler,
+            limit,
+            generation: 0,
+            crashes: Arc::new(AtomicUsize::new(0)),
+            restarts: Arc::new(AtomicUsize::new(0)),
+            latency: Latency::new(),
         }
     }
 
-    pub fn create<F>(&mut self, f: F, c: Arc<Mutex<Self>>) -> usize where
-        F: FnOnce(Receiver<R>, Arc<Mutex<Self>>) + Send + 'static
+    /// Locks the container. Nothing that can panic runs while it's locked, but if something does
+    /// anyway the container is still usable.
+    pub fn lock(c: &Arc<Mutex<Self>>) -> MutexGuard<Self> {
+        c.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts a new instance in `c` and returns its address. Addresses are made up of the
+    /// generation of the instance and a random token, so they can't be guessed and are never
+    /// reused. The instance isn't polled more often than once every `interval`.
+    /// The task is made by `f` while the container isn't locked, so a panic in there only fails
+    /// this call.
+    pub fn create<F>(c: &Arc<Mutex<Self>>, f: F, interval: Duration)
+        -> ::std::io::Result<String> where
+        F: FnOnce(Receiver<R>, Arc<Mutex<Self>>) -> Box<Task>
+    {
+        let address = {
+            let mut container = Self::lock(c);
+            if container.full() {
+                return Err(Error::new(ErrorKind::Other, "instance limit reached"));
+            }
+
+            container.generation += 1;
+            let token = (random::<u64>(), random::<u64>());
+            format!("{}-{:016x}{:016x}", container.generation, token.0, token.1)
+        };
+
+        // instances started this way only run once, even if they crash
+        let (tx, rx) = sync_channel(8);
+        let task = f(rx, c.clone());
+
+        // the container may have filled up in the meantime, the task is only dropped after the
+        // container is unlocked again
+        let mut container = Self::lock(c);
+        if container.full() {
+            return Err(Error::new(ErrorKind::Other, "instance limit reached"));
+        }
+        Ok(container.spawn(address, tx, task, None, c, interval))
+    }
+
+    /// Starts a new instance at a well known address in `c`, like the matchmaker.
+    /// The instance is started again with a fresh listener whenever it crashes.
+    pub fn create_named<F>(c: &Arc<Mutex<Self>>, name: &str, mut f: F, interval: Duration)
+        -> ::std::io::Result<String> where
+        F: FnMut(Receiver<R>, Arc<Mutex<Self>>) -> Box<Task> + Send + 'static
     {
+        Self::lock(c).available(name)?;
+
+        let (tx, rx) = sync_channel(8);
+        let task = f(rx, c.clone());
+        let container = Arc::downgrade(c);
+        let restart: Restart<R> = Box::new(move |rx| {
+            f(rx, container.upgrade().expect("The instance container is gone"))
+        });
+        let mut container = Self::lock(c);
+        container.available(name)?;
+        Ok(container.spawn(name.to_string(), tx, task, Some(restart), c, interval))
+    }
+
+    /// Checks whether an instance named `name` can be started.
+    fn available(&self, name: &str) -> ::std::io::Result<()> {
+        if self.lookup(name) == Lookup::Running {
+            return Err(Error::new(ErrorKind::AlreadyExists, "instance name is taken"));
+        }
+        if self.full() {
+            return Err(Error::new(ErrorKind::Other, "instance limit reached"));
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> InstanceStats {
+        let scheduler = self.scheduler.stats();
+        InstanceStats {
+            running: self.instances.iter().filter(|i| i.control.upgrade().is_some()).count(),
+            crashes: self.crashes.load(Ordering::SeqCst),
+            restarts: self.restarts.load(Ordering::SeqCst),
+            polls: scheduler.polls,
+            max_lag_ms: scheduler.max_lag.as_secs() * 1000 +
+                scheduler.max_lag.subsec_millis() as u64,
+            rtt: self.latency.stats(),
+        }
+    }
+
+    /// Returns where game instances should report the round trip times of their players.
+    pub fn latency(&self) -> Latency {
+        self.latency.clone()
+    }
+
+    fn full(&self) -> bool {
+        self.instances.iter().filter(|i| i.control.upgrade().is_some()).count() >= self.limit
+    }
+
+    /// Schedules the task of a new instance, which listens for connections on `sender`.
+    fn spawn(&mut self,
+             address: String,
+             sender: SyncSender<R>,
+             task: Box<Task>,
+             restart: Option<Restart<R>>,
+             c: &Arc<Mutex<Self>>,
+             interval: Duration) -> String {
         let id = self.instances
             .iter()
             .enumerate()
@@ -31,16 +245,24 @@ impl<R: Remote + Send + 'static> InstanceContainer<R> {
             .next()
             .unwrap_or(self.instances.len());
 
-        let (tx, rx) = sync_channel(8);
         let alive = Arc::new(0);
         let control = Arc::downgrade(&alive);
-        spawn(move || {
-            f(rx, c);
-            let _ = *alive;
-        });
+        let waker = self.scheduler.spawn(Box::new(Supervised {
+            name: address.clone(),
+            task: Some(task),
+            restart,
+            restart_at: None,
+            container: Arc::downgrade(c),
+            crashes: self.crashes.clone(),
+            restarts: self.restarts.clone(),
+            _alive: alive,
+        }), interval);
+
         let i = Instance {
+            address: address.clone(),
             control,
-            sender: tx,
+            sender,
+            waker,
         };
 
         if id == self.instances.len() {
@@ -49,17 +271,168 @@ impl<R: Remote + Send + 'static> InstanceContainer<R> {
             self.instances[id] = i;
         }
 
-        id
+        address
+    }
+
+    pub fn lookup(&self, address: &str) -> Lookup {
+        match self.instances.iter().find(|i| i.address == address) {
+            Some(i) if i.control.upgrade().is_some() => Lookup::Running,
+            Some(_) => Lookup::Expired,
+            None => {
+                // the slot of an old instance may have been reused already
+                let generation = address
+                    .split('-')
+                    .next()
+                    .and_then(|g| g.parse::<u64>().ok());
+                match generation {
+                    Some(g) if g > 0 && g <= self.generation => Lookup::Expired,
+                    _ => Lookup::Unknown,
+                }
+            },
+        }
+    }
+
+    /// Hands a connection to an instance. The returned waker should be used to wake the instance
+    /// whenever something arrives on the connection.
+    pub fn submit(&mut self, address: &str, remote: R) -> ::std::io::Result<Waker> {
+        let instance = self.instances
+            .iter_mut()
+            .find(|i| i.address == address && i.control.upgrade().is_some());
+
+        // never block here, this runs on the http server's threads
+        match instance.map(|inst| (inst.sender.try_send(remote), &inst.waker)) {
+            Some((Ok(()), waker)) => {
+                waker.wake();
+                Ok(waker.clone())
+            },
+            Some((Err(TrySendError::Full(_)), _)) => {
+                Err(Error::new(ErrorKind::WouldBlock, "The server is busy, try again later"))
+            },
+            Some((Err(TrySendError::Disconnected(_)), _)) => {
+                Err(Error::new(ErrorKind::BrokenPipe, "The match has ended"))
+            },
+            None => Err(Error::new(ErrorKind::NotFound, "No such match")),
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Sender};
+
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    struct Nobody;
+
+    impl Remote for Nobody {
+        fn close(&mut self) { }
 
-    pub fn submit(&mut self, instance: usize, remote: R) -> ::std::io::Result<()> {
-        if let Some(inst) = self.instances.get_mut(instance) {
-            inst.sender
-                .send(remote)
-                .map_err(|_e| Error::from(ErrorKind::BrokenPipe))?;
+        fn alive(&self) -> bool {
+            false
+        }
+
+        fn send(&mut self, _: &str) -> Result<(), mirror::Error> {
             Ok(())
-        } else {
-            Err(Error::from(ErrorKind::NotFound))
         }
+
+        fn recv(&mut self) -> Option<String> {
+            None
+        }
+    }
+
+    /// Stands in for a connection held by an instance, reports whether it was dropped while
+    /// its instance crashed.
+    struct Held(Sender<bool>);
+
+    impl Drop for Held {
+        fn drop(&mut self) {
+            self.0.send(crashing()).ok();
+        }
+    }
+
+    fn container() -> Arc<Mutex<InstanceContainer<Nobody>>> {
+        Arc::new(Mutex::new(InstanceContainer::new(4, Scheduler::new(1))))
+    }
+
+    /// Waits for the workers to get `c` into the state checked by `f`.
+    fn wait_for<F>(c: &Arc<Mutex<InstanceContainer<Nobody>>>, f: F) where
+        F: Fn(&InstanceContainer<Nobody>) -> bool
+    {
+        let deadline = Instant::now() + PATIENCE;
+        while !f(&InstanceContainer::lock(c)) {
+            assert!(Instant::now() < deadline, "gave up waiting for the instances");
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn crashed_instances_are_torn_down() {
+        let c = container();
+        let (tx, rx) = channel();
+
+        let address = InstanceContainer::create(&c, move |_, _| {
+            let held = Held(tx);
+            Box::new(move || -> Poll {
+                let _ = &held;
+                panic!("crashed")
+            })
+        }, Duration::from_millis(0)).unwrap();
+
+        assert_eq!(rx.recv_timeout(PATIENCE), Ok(true));
+        wait_for(&c, |c| c.lookup(address.as_str()) == Lookup::Expired);
+
+        let stats = InstanceContainer::lock(&c).stats();
+        assert_eq!((stats.running, stats.crashes, stats.restarts), (0, 1, 0));
+    }
+
+    #[test]
+    fn named_instances_are_restarted() {
+        let c = container();
+        let (tx, rx) = channel();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        InstanceContainer::create_named(&c, "named", move |_, _| {
+            let tx = tx.clone();
+            let start = started.fetch_add(1, Ordering::SeqCst);
+            Box::new(move || -> Poll {
+                if start == 0 {
+                    panic!("crashed");
+                }
+                tx.send(start).ok();
+                Poll::Wait(Instant::now() + Duration::from_secs(3600))
+            })
+        }, Duration::from_millis(0)).unwrap();
+
+        assert_eq!(rx.recv_timeout(PATIENCE), Ok(1));
+        assert!(InstanceContainer::lock(&c).lookup("named") == Lookup::Running);
+
+        let stats = InstanceContainer::lock(&c).stats();
+        assert_eq!((stats.running, stats.crashes, stats.restarts), (1, 1, 1));
+    }
+
+    #[test]
+    fn restarted_instances_take_connections_again() {
+        let c = container();
+        let (tx, rx) = channel();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        InstanceContainer::create_named(&c, "named", move |listener, _| {
+            let tx = tx.clone();
+            let start = started.fetch_add(1, Ordering::SeqCst);
+            Box::new(move || -> Poll {
+                if start == 0 {
+                    panic!("crashed");
+                }
+                while listener.try_recv().is_ok() {
+                    tx.send(()).ok();
+                }
+                Poll::Wait(Instant::now() + Duration::from_secs(3600))
+            })
+        }, Duration::from_millis(0)).unwrap();
+
+        wait_for(&c, |c| c.stats().restarts == 1);
+        assert!(InstanceContainer::lock(&c).submit("named", Nobody).is_ok());
+        assert!(rx.recv_timeout(PATIENCE).is_ok());
     }
 }
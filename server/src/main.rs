@@ -1,11 +1,18 @@
+mod bench;
+mod config;
 mod game;
 mod matchmaking;
 mod instance;
+mod rating;
+mod replay;
+mod scheduler;
+mod session;
+mod view;
 
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, SyncSender, sync_channel, TryRecvError};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel, TryRecvError, TrySendError};
 use std::str::FromStr;
-use std::fs::metadata;
+use std::time::{Duration, Instant};
 
 use actix::*;
 use actix_web::server::HttpServer;
@@ -15,19 +22,52 @@ use actix_web::fs::NamedFile;
 use clap::App as ClapApp;
 use clap::Arg;
 
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use tetris_model::protocol::{self, Handshake, Hello, Mismatch};
+use tetris_model::wire;
+
+use crate::instance::InstanceContainer;
+use crate::scheduler::{Scheduler, Waker};
 
 #[derive(Message)]
 struct WsMessage(pub String);
 
+/// Closes the websocket, an error is sent along as the reason if there is one.
 #[derive(Message)]
-struct WsClose;
+struct WsClose(pub Option<String>);
+
+/// How many incoming messages a connection may have queued before it's closed.
+const CONNECTION_QUEUE: usize = 64;
+
+/// How long messages for a binary connection are collected before they're sent as one frame.
+/// Instances send everything for an update at once, so this only needs to be short.
+const BATCH_DELAY: Duration = Duration::from_millis(1);
+
+/// The address of the matchmaker.
+const MATCHMAKING: &str = "matchmaking";
+
+/// Where the matchmaker lived before it got its name. Clients that still connect there get
+/// routed to the matchmaker, whose handshake tells them to update.
+const LEGACY_MATCHMAKING: &str = "0";
 
 struct WsServerState {
-    instances: Arc<Mutex<instance::InstanceContainer<WsConnection>>>,
+    instances: Arc<Mutex<InstanceContainer<WsConnection>>>,
+    config: Arc<config::Config>,
 }
 
-struct Ws { id: usize, addr: String, tx: Option<SyncSender<String>> }
+struct Ws {
+    id: String,
+    addr: String,
+    tx: Option<SyncSender<String>>,
+    waker: Option<Waker>,
+    /// Set when the client asked for the binary encoding.
+    wire: Option<(wire::Encoder, wire::Decoder)>,
+    /// Messages waiting to be encoded into the next binary frame.
+    pending: Vec<String>,
+    /// When the client last sent anything, including pongs.
+    heartbeat: Instant,
+    /// Set once the client said hello, nothing is forwarded before that.
+    hello: Option<Hello>,
+}
 
 struct WsConnection {
     rx: Receiver<String>,
@@ -39,18 +79,125 @@ impl Actor for Ws {
     type Context = ws::WebsocketContext<Self, WsServerState>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let (tx, rx) = sync_channel(8);
+        // half open connections never close by themselves, so clients that go quiet are closed
+        let connection = ctx.state().config.connection.clone();
+        ctx.run_interval(connection.ping_interval(), move |act, ctx| {
+            if act.heartbeat.elapsed() > connection.timeout() {
+                println!("Client {} timed out", act.addr);
+                act.close_with(ctx, ws::CloseCode::Away, "Timed out".to_string());
+            } else {
+                ctx.ping("");
+            }
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        // let the instance notice the connection is gone
+        self.tx = None;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Ws {
+    /// Hands the connection to its instance, which sends the full state right away.
+    fn submit(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let (tx, rx) = sync_channel(CONNECTION_QUEUE);
         let addr = ctx.address();
-        if ctx.state().instances.lock().unwrap().submit(self.id, WsConnection {
-            rx,
-            addr,
-            alive: true,
-        }).is_err() {
-            println!("Unable to forward {} to instance {}", self.addr, self.id);
-            ctx.stop();
+        let remote = WsConnection { rx, addr, alive: true };
+        let result = InstanceContainer::lock(&ctx.state().instances)
+            .submit(self.id.as_str(), remote);
+
+        match result {
+            Ok(waker) => {
+                self.tx = Some(tx);
+                self.waker = Some(waker);
+            },
+            Err(e) => {
+                println!("Unable to forward {} to instance {}: {}", self.addr, self.id, e);
+                self.close_with(ctx, ws::CloseCode::Again, e.to_string());
+            },
+        }
+    }
+
+    /// Handles the first message of the client, which has to be its hello. Clients that speak
+    /// a protocol version the server understands are connected to their instance, all others
+    /// are told to update.
+    fn handshake(&mut self, ctx: &mut <Self as Actor>::Context, text: String) {
+        let hello = match protocol::parse(text.as_str()) {
+            Some(Handshake::Hello(hello)) => hello,
+            _ => {
+                // clients from before the handshake existed don't understand today's state
+                println!("Client {} didn't say hello", self.addr);
+                let reason = "Please refresh the page or update the game".to_string();
+                self.close_with(ctx, ws::CloseCode::Policy, reason);
+                return;
+            },
+        };
+
+        let reason = match hello.check() {
+            Ok(()) => {
+                ctx.text(Hello::ours(&[protocol::FEATURE_BINARY]).to_message());
+                self.hello = Some(hello);
+                self.submit(ctx);
+                return;
+            },
+            Err(Mismatch::TheirsOutdated) => "Please refresh the page or update the game",
+            Err(Mismatch::OursOutdated) => "The server is out of date, please try again later",
+        };
+
+        println!("Client {} speaks protocol version {}, which is incompatible",
+                 self.addr, hello.version);
+        ctx.text(protocol::outdated_message(reason));
+        self.close_with(ctx, ws::CloseCode::Policy, reason.to_string());
+    }
+
+    /// Closes the connection, telling the client why.
+    fn close_with(&mut self, ctx: &mut <Self as Actor>::Context, code: ws::CloseCode,
+                  reason: String) {
+        self.flush(ctx);
+        self.tx = None;
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(reason),
+        }));
+        ctx.stop();
+    }
+
+    /// Sends the pending messages of a binary connection as a single frame.
+    fn flush(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some((encoder, _)) = self.wire.as_mut() {
+            if !self.pending.is_empty() {
+                ctx.binary(encoder.encode(self.pending.as_slice()));
+                self.pending.clear();
+            }
         }
+    }
 
-        self.tx = Some(tx);
+    /// Hands a message from the client to the instance.
+    fn forward(&mut self, ctx: &mut <Self as Actor>::Context, text: String) {
+        let result = match self.tx.as_ref() {
+            Some(tx) => tx.try_send(text),
+            None => return,
+        };
+
+        match result {
+            Ok(()) => {
+                if let Some(waker) = self.waker.as_ref() {
+                    waker.wake();
+                }
+            },
+            Err(TrySendError::Full(_)) => {
+                println!("Client {} is sending faster than its instance can keep up", self.addr);
+                let reason = "Too many messages".to_string();
+                self.close_with(ctx, ws::CloseCode::Policy, reason);
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                let reason = "The match is over".to_string();
+                self.close_with(ctx, ws::CloseCode::Normal, reason);
+            },
+        }
     }
 }
 
@@ -58,21 +205,34 @@ impl Handler<WsMessage> for Ws {
     type Result = ();
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        if self.wire.is_none() {
+            ctx.text(msg.0);
+            return;
+        }
+
+        if self.pending.is_empty() {
+            ctx.run_later(BATCH_DELAY, |ws, ctx| ws.flush(ctx));
+        }
+        self.pending.push(msg.0);
     }
 }
 
 impl Handler<WsClose> for Ws {
     type Result = ();
 
-    fn handle(&mut self, _: WsClose, ctx: &mut Self::Context) {
-        ctx.close(None);
+    fn handle(&mut self, msg: WsClose, ctx: &mut Self::Context) {
+        self.flush(ctx);
+        ctx.close(msg.0.map(|error| ws::CloseReason {
+            code: ws::CloseCode::Error,
+            description: Some(error),
+        }));
         ctx.stop();
     }
 }
 
 impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        self.heartbeat = Instant::now();
         match msg {
             ws::Message::Ping(msg) => {
                 ctx.pong(&msg);
@@ -81,9 +241,35 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
                 //
             },
             ws::Message::Text(text) => {
-                self.tx.as_ref().unwrap().send(text).unwrap();
+                if self.hello.is_none() {
+                    self.handshake(ctx, text);
+                } else {
+                    self.forward(ctx, text);
+                }
+            },
+            ws::Message::Binary(data) => {
+                let said_hello = self.hello.is_some();
+                let decoded = match self.wire.as_mut().filter(|_| said_hello) {
+                    Some((_, decoder)) => decoder.decode(data.as_ref()),
+                    None => {
+                        // binary frames are only understood after asking for them and saying hello
+                        ctx.stop();
+                        return;
+                    },
+                };
+
+                match decoded {
+                    Ok(messages) => {
+                        for text in messages {
+                            self.forward(ctx, text);
+                        }
+                    },
+                    Err(e) => {
+                        println!("Client {} sent a malformed frame: {}", self.addr, e);
+                        self.close_with(ctx, ws::CloseCode::Invalid, e.to_string());
+                    },
+                }
             },
-            ws::Message::Binary(_) |
             ws::Message::Close(_) => {
                 ctx.stop();
             },
@@ -99,7 +285,7 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
 impl mirror::Remote for WsConnection {
     fn close(&mut self) {
         if self.alive {
-            self.addr.do_send(WsClose);
+            self.addr.do_send(WsClose(None));
         }
         self.alive = false;
     }
@@ -124,74 +310,242 @@ impl mirror::Remote for WsConnection {
     }
 }
 
+impl Drop for WsConnection {
+    fn drop(&mut self) {
+        // connections are dropped while unwinding when their instance crashes
+        if self.alive && instance::crashing() {
+            self.addr.do_send(WsClose(Some("The server ran into an error".to_string())));
+            self.alive = false;
+        }
+    }
+}
+
+fn status_route(req: &HttpRequest<WsServerState>) -> HttpResponse {
+    let stats = InstanceContainer::lock(&req.state().instances).stats();
+    HttpResponse::Ok().json(stats)
+}
+
 fn instance_route(req: &HttpRequest<WsServerState>) -> Result<HttpResponse, Error> {
-    if let Ok(id) = req.path().split_at("/instance/".len()).1.parse::<usize>() {
-        let addr = req.connection_info().remote().unwrap_or("<unknown>").to_string();
-        return ws::start(req, Ws { id, addr, tx: None });
-    } else {
-        Ok(HttpResponse::BadRequest().finish())
+    let id = match req.path().split_at("/instance/".len()).1 {
+        LEGACY_MATCHMAKING => MATCHMAKING.to_string(),
+        id => id.to_string(),
+    };
+    let lookup = InstanceContainer::lock(&req.state().instances).lookup(id.as_str());
+    match lookup {
+        instance::Lookup::Running => {
+            let addr = req.connection_info().remote().unwrap_or("<unknown>").to_string();
+            let wire = if req.query_string().split('&').any(|q| q == wire::BINARY_QUERY) {
+                Some((wire::Encoder::new(), wire::Decoder::new()))
+            } else {
+                None
+            };
+            ws::start(req, Ws {
+                id,
+                addr,
+                tx: None,
+                waker: None,
+                wire,
+                pending: Vec::new(),
+                heartbeat: Instant::now(),
+                hello: None,
+            })
+        },
+        instance::Lookup::Expired => Ok(HttpResponse::Gone().body("This match has ended")),
+        instance::Lookup::Unknown => Ok(HttpResponse::NotFound().body("No such match")),
     }
 }
 
+fn static_route(req: &HttpRequest<WsServerState>) -> Result<NamedFile, Error> {
+    let path = req.path().split_at("/static/".len()).1;
+    if path.split('/').any(|part| part == "..") {
+        return Err(Error::from(::std::io::Error::from(::std::io::ErrorKind::NotFound)));
+    }
+
+    let path = req.state().config.static_dir.join(path);
+    let file = if path.extension().map(|e| e == "wasm").unwrap_or(false) {
+        let mime = FromStr::from_str("application/wasm").unwrap();
+        NamedFile::open(path)
+            .map_err(|io| Error::from(io))
+            .map(|f| f.set_content_type(mime))
+    } else {
+        NamedFile::open(path)
+            .map_err(|io| Error::from(io))
+    };
+
+    file
+}
+
 fn main() {
     let matches = ClapApp::new("tutris-server")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .help("A TOML file to read the server configuration from")
+            .takes_value(true))
         .arg(Arg::with_name("bind-to")
             .short("b")
             .long("bind-to")
-            .help("The address to bind the sever to")
-            .default_value("127.0.0.1:3000")
+            .help("An address to bind the server to, may be given more than once \
+                   [default: 127.0.0.1:3000]")
             .takes_value(true)
-            .required(true))
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("static-dir")
+            .long("static-dir")
+            .help("The directory static files are served from [default: static]")
+            .takes_value(true))
+        .arg(Arg::with_name("replay-dir")
+            .long("replay-dir")
+            .help("The directory replays are recorded to [default: static/replays]")
+            .takes_value(true))
+        .arg(Arg::with_name("ratings")
+            .long("ratings")
+            .help("The file player ratings are stored in [default: ratings.json]")
+            .takes_value(true))
+        .arg(Arg::with_name("tls")
+            .long("tls")
+            .help("Whether to serve over TLS, auto only does so if the certificate and key exist \
+                   [default: auto]")
+            .possible_values(&["auto", "on", "off"])
+            .takes_value(true))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("The TLS certificate chain [default: cert.pem]")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("The TLS private key [default: key.pem]")
+            .takes_value(true))
+        .arg(Arg::with_name("max-instances")
+            .long("max-instances")
+            .help("The maximum number of matches that may run at the same time [default: 256]")
+            .takes_value(true))
+        .arg(Arg::with_name("workers")
+            .long("workers")
+            .help("The number of threads matches are run on [default: 4]")
+            .takes_value(true))
+        .arg(Arg::with_name("connection-timeout")
+            .long("connection-timeout")
+            .help("Seconds a connection may go without sending anything before it's closed \
+                   [default: 15]")
+            .takes_value(true))
+        .arg(Arg::with_name("tick")
+            .long("tick")
+            .help("Shortest time in milliseconds between updates of a match [default: 15]")
+            .takes_value(true))
+        .arg(Arg::with_name("matchmaking-tick")
+            .long("matchmaking-tick")
+            .help("Shortest time in milliseconds between updates of the matchmaker \
+                   [default: 500]")
+            .takes_value(true))
+        .arg(Arg::with_name("disconnect-timeout")
+            .long("disconnect-timeout")
+            .help("Seconds a disconnected player may take to rejoin a match before forfeiting \
+                   [default: 30]")
+            .takes_value(true))
+        .arg(Arg::with_name("idle-timeout")
+            .long("idle-timeout")
+            .help("Seconds a player may go without input before forfeiting a match [default: 60]")
+            .takes_value(true))
+        .arg(Arg::with_name("bench")
+            .long("bench")
+            .help("Runs the given number of bot matches instead of serving, to measure how many \
+                   matches the server can handle")
+            .takes_value(true))
+        .arg(Arg::with_name("bench-seconds")
+            .long("bench-seconds")
+            .help("How long the benchmark runs [default: 30]")
+            .takes_value(true))
         .get_matches();
 
-    let bind = matches.value_of("bind-to").unwrap_or("127.0.0.1:3000".into());
+    let config = match config::Config::from_args(&matches) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        },
+    };
+
+    if let Some(bench) = matches.value_of("bench") {
+        let seconds = matches.value_of("bench-seconds").unwrap_or("30");
+        let (bench, seconds) = match (bench.parse::<usize>(), seconds.parse::<u64>()) {
+            (Ok(bench), Ok(seconds)) if bench > 0 => (bench, seconds),
+            _ => {
+                eprintln!("--bench and --bench-seconds must be positive numbers");
+                ::std::process::exit(1);
+            },
+        };
+        if let Err(e) = bench::run(&config, bench, seconds) {
+            eprintln!("Benchmark failed: {}", e);
+            ::std::process::exit(1);
+        }
+        return;
+    }
 
     println!("Tutris-9 server starting..");
-    println!("Server will listen on {}", bind);
+    println!("Server will listen on {}", config.bind.join(", "));
 
     let sys = actix::System::new("Tutris 9");
 
-    let instances = Arc::new(Mutex::new(instance::InstanceContainer::new()));
-
-    let use_ssl = metadata("key.pem").is_ok() && metadata("cert.pem").is_ok();
-
-    instances.lock().unwrap().create(move |listener, container| {
-        matchmaking::run_matchmaking_server(listener, container).expect("matchmaker failed");
-    }, instances.clone());
-
-    let server = HttpServer::new(move || {
-        App::with_state(WsServerState { instances: instances.clone() })
-            .handler("/static/", |req: &HttpRequest<WsServerState>| -> Result<NamedFile, Error> {
-                let path = req.path().trim_start_matches("/");
-                let file = if path.ends_with(".wasm") {
-                    let mime = FromStr::from_str("application/wasm").unwrap();
-                    NamedFile::open(path)
-                        .map_err(|io| Error::from(io))
-                        .map(|f| f.set_content_type(mime))
-                } else {
-                    NamedFile::open(path)
-                        .map_err(|io| Error::from(io))
-                };
-
-                file
-            })
+    let ratings = match rating::RatingStore::open(config.ratings.clone()) {
+        Ok(ratings) => ratings,
+        Err(e) => {
+            eprintln!("Unable to load ratings from {}: {}", config.ratings.display(), e);
+            ::std::process::exit(1);
+        },
+    };
+    let ratings = Arc::new(Mutex::new(ratings));
+
+    // the matchmaker takes up an instance as well
+    let limit = config.max_instances + 1;
+    let scheduler = Scheduler::new(config.workers);
+    let instances = Arc::new(Mutex::new(InstanceContainer::new(limit, scheduler)));
+
+    let matchmaking_config = config.clone();
+    InstanceContainer::create_named(&instances, MATCHMAKING, move |listener, container| {
+        let config = matchmaking_config.clone();
+        Box::new(matchmaking::matchmaking_task(listener, container, config, ratings.clone()))
+    }, config.matchmaking.tick()).expect("Unable to start the matchmaker");
+
+    let server_config = config.clone();
+    let mut server = HttpServer::new(move || {
+        App::with_state(WsServerState {
+            instances: instances.clone(),
+            config: server_config.clone(),
+        })
+            .handler("/static/", static_route)
             .resource("/", |r| r.method(http::Method::GET).f(|_| {
                 HttpResponse::Found()
                     .header("LOCATION", "/static/index.html")
                     .finish()
             }))
+            .resource("/status", |r| r.method(http::Method::GET).f(status_route))
             .resource("/instance/{id}", |r| r.f(instance_route))
     });
 
-    if use_ssl {
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-        builder.set_private_key_file("key.pem", SslFiletype::PEM).unwrap();
-        builder.set_certificate_chain_file("cert.pem").unwrap();
-        server.bind_ssl(bind, builder).unwrap().start();
+    if config.use_tls() {
+        for bind in config.bind.iter() {
+            // the files were checked along with the rest of the configuration
+            let builder = match config.tls_acceptor() {
+                Ok(builder) => builder,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    ::std::process::exit(1);
+                },
+            };
+            server = server.bind_ssl(bind.as_str(), builder)
+                .unwrap_or_else(|e| panic!("Unable to bind to {}: {}", bind, e));
+        }
+        server.start();
         println!("Server started using SSL");
     } else {
-        server.bind(bind).unwrap().start();
-        println!("SSL key and certificiate not found while looking for cert.pem and key.pem.");
+        for bind in config.bind.iter() {
+            server = server.bind(bind.as_str())
+                .unwrap_or_else(|e| panic!("Unable to bind to {}: {}", bind, e));
+        }
+        server.start();
+        println!("SSL disabled, or key and certificate not found at {} and {}.",
+                 config.tls.key.display(), config.tls.cert.display());
         println!("Server started without SSL");
     }
 
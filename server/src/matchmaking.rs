@@ -1,23 +1,132 @@
+use crate::config::{Config, MatchmakingConfig};
 use crate::instance::InstanceContainer;
-use crate::game::run_game_server;
+use crate::game::{GameInstance, Entrant};
+use crate::rating::RatingStore;
+use crate::scheduler::{Poll, Task};
+use crate::session::{Allowlist, MATCHMAKING_CALLS};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, channel};
 use std::time::{Duration, Instant};
-use std::thread::sleep;
 use rand::random;
+use serde_json::Value;
+use tetris_model::matchmaking::QueueRules;
 use mirror::*;
 
 struct Match {
     users: Vec<String>,
+    rating: f64,
     wait_time: usize,
 }
 
-pub fn run_matchmaking_server<R>(listener: Receiver<R>,
-                                 container: Arc<Mutex<InstanceContainer<R>>>) -> Result<(), Error>
+struct Queue {
+    rules: QueueRules,
+    matches: Vec<Match>,
+    /// Moving average of how long players waited before their match started, in seconds.
+    average_wait: f64,
+}
+
+struct Queued {
+    key: String,
+    queue: usize,
+    rating: f64,
+    waited: Duration,
+}
+
+impl Match {
+    fn accepts(&self, config: &MatchmakingConfig, rules: &QueueRules,
+               rating: f64, waited: Duration) -> bool {
+        let band = config.band_base + config.band_growth * waited.as_secs() as f64;
+        self.users.len() < rules.max_players && (rating - self.rating).abs() <= band
+    }
+}
+
+impl Queue {
+    fn new(rules: QueueRules) -> Self {
+        let average_wait = rules.fill_time as f64;
+        Self { rules, matches: Vec::new(), average_wait }
+    }
+
+    /// Puts everyone in a match with players of similar rating,
+    /// the longer a player waits the wider the accepted rating band gets.
+    fn assign(&mut self, config: &MatchmakingConfig, queued: &[&Queued]) {
+        let rules = &self.rules;
+
+        for q in queued.iter() {
+            if self.matches.iter().any(|m| m.users.contains(&q.key)) {
+                continue;
+            }
+
+            let found = self.matches
+                .iter_mut()
+                .find(|m| m.accepts(config, rules, q.rating, q.waited));
+
+            if let Some(m) = found {
+                m.users.push(q.key.clone());
+                m.wait_time = rules.fill_time;
+            } else {
+                self.matches.push(Match {
+                    users: vec![q.key.clone()],
+                    rating: q.rating,
+                    wait_time: rules.fill_time,
+                });
+            }
+        }
+
+        // players that are alone in their match move over once their band is wide enough
+        for i in 0..self.matches.len() {
+            if self.matches[i].users.len() != 1 {
+                continue;
+            }
+
+            let key = self.matches[i].users[0].clone();
+            if let Some(q) = queued.iter().find(|q| q.key == key) {
+                let matches = &self.matches;
+                let other = (0..matches.len()).find(|&j| {
+                    j != i && !matches[j].users.is_empty() &&
+                        matches[j].accepts(config, rules, q.rating, q.waited)
+                });
+
+                if let Some(j) = other {
+                    self.matches[i].users.clear();
+                    self.matches[j].users.push(key);
+                    self.matches[j].wait_time = rules.fill_time;
+                }
+            }
+        }
+        self.matches.retain(|m| !m.users.is_empty());
+
+        for m in self.matches.iter_mut() {
+            if m.wait_time == 0 && m.users.len() < rules.min_players {
+                m.wait_time = rules.retry_time;
+            }
+        }
+    }
+
+    /// Estimates how long a player in `m` still has to wait.
+    fn estimate(&self, m: &Match, waited: Duration) -> usize {
+        if m.users.len() >= self.rules.min_players {
+            m.wait_time
+        } else {
+            (self.average_wait - waited.as_secs() as f64).max(m.wait_time as f64) as usize
+        }
+    }
+}
+
+/// Creates the matchmaker. It's polled whenever a client sends something, and once a second to
+/// form and start matches.
+pub fn matchmaking_task<R>(listener: Receiver<R>,
+                           container: Arc<Mutex<InstanceContainer<R>>>,
+                           config: Arc<Config>,
+                           ratings: Arc<Mutex<RatingStore>>) -> impl Task
     where
         R: Remote + Send + 'static
 {
-    let factory = || tetris_model::matchmaking::MatchmakingState {
+    let queue_list: Vec<(String, String)> = config.matchmaking.queues
+        .iter()
+        .map(|q| (q.name.clone(), q.title.clone()))
+        .collect();
+    let factory = move || tetris_model::matchmaking::MatchmakingState {
+        queued: Hidden::new(Instant::now()),
         done: false,
         matched: false,
         player_key: String::new(),
@@ -25,50 +134,164 @@ pub fn run_matchmaking_server<R>(listener: Receiver<R>,
         players_found: 0,
         instance_address: String::new(),
         wait_time: 91,
+        estimated_wait: 91,
+        identity: String::new(),
+        nickname: String::new(),
+        rated: false,
+        rating: 0.0,
+        rating_history: Vec::new(),
+        queue: String::new(),
+        max_players: 0,
+        queues: queue_list.clone(),
+        error: String::new(),
     };
 
-    let mut server = PrivateServer::new(factory, listener);
+    // clients only get to identify themselves and pick a queue
+    let (remotes, remote_listener) = channel();
+    let mut server = PrivateServer::new(factory, remote_listener);
     let mut last_match = Instant::now();
 
-    let mut current_match = Match {
-        users: Vec::new(),
-        wait_time: 4,
-    };
+    let mut queues: Vec<Queue> = config.matchmaking.queues
+        .iter()
+        .cloned()
+        .map(Queue::new)
+        .collect();
+
+    let mut step = move || -> Result<Poll, Error> {
+        while let Ok(remote) = listener.try_recv() {
+            remotes.send(Allowlist::new(remote, &MATCHMAKING_CALLS)).ok();
+        }
 
-    loop {
         server.update();
 
         let check = Instant::now();
-        if check.duration_since(last_match).as_secs() >= 1 {
+        if check.duration_since(last_match) >= Duration::from_secs(1) {
             last_match = check;
 
-            current_match.wait_time -= 1;
-
-            current_match.users.retain(|client_key| {
-                server.clients().find(|c| c.player_key.as_str() == client_key).is_some()
-            });
+            for m in queues.iter_mut().flat_map(|q| q.matches.iter_mut()) {
+                m.wait_time = m.wait_time.saturating_sub(1);
+                m.users.retain(|client_key| {
+                    server.clients().find(|c| c.player_key.as_str() == client_key).is_some()
+                });
+            }
+            for queue in queues.iter_mut() {
+                queue.matches.retain(|m| !m.users.is_empty());
+            }
 
-            // try to fill the current match
+            // hand out keys, queue rules and ratings to new clients. Players that haven't
+            // identified themselves in time are matched with a default rating.
+            let identify_timeout = config.matchmaking.identify_timeout();
             for client in server.clients() {
-                if client.matched == false && current_match.users.len() < 9 {
+                if client.matched == false {
                     let key = format!("{:x}-{:x}", random::<u64>(), random::<u64>());
 
                     client.command("matched/set:true")?;
                     client.command(format!("player_key/set:\"{}\"", key).as_str())?;
+                }
 
-                    current_match.users.push(key);
-                    current_match.wait_time = 10;
+                if client.max_players == 0 {
+                    // clients only get to join queues that exist, so they're not matched until
+                    // they picked one
+                    if let Some(queue) = queues.iter().find(|q| q.rules.name == client.queue) {
+                        client.command(format!("max_players/set:{}", queue.rules.max_players)
+                            .as_str())?;
+                    }
                 }
 
-                client.command(format!("wait_time/set:{}", current_match.wait_time).as_str())?;
-                client.command(format!("players_found/set:{}", current_match.users.len()).as_str())?;
+                if client.rated == false && !client.identity.is_empty() {
+                    let (rating, history) = {
+                        let store = ratings.lock().unwrap();
+                        let identity = client.identity.as_str();
+                        (store.get(identity).rating, Value::from(store.history(identity)))
+                    };
+
+                    client.command("rated/set:true")?;
+                    client.command(format!("rating/set:{}", rating).as_str())?;
+                    client.command(format!("rating_history/set:{}", history).as_str())?;
+                }
             }
 
-            if current_match.wait_time == 0 {
-                if current_match.users.len() < 2 {
-                    current_match.wait_time = 91;
-                } else {
-                    let users = current_match.users.clone();
+            let queued: Vec<Queued> = server.clients()
+                .filter(|c| c.matched && c.max_players > 0)
+                .filter(|c| c.rated || c.queued.elapsed() > identify_timeout)
+                .filter_map(|c| {
+                    let queue = queues.iter().position(|q| q.rules.name == c.queue)?;
+                    Some(Queued {
+                        key: c.player_key.clone(),
+                        queue,
+                        rating: if c.rated { c.rating } else { 1500.0 },
+                        waited: c.queued.elapsed(),
+                    })
+                })
+                .collect();
+
+            for (i, queue) in queues.iter_mut().enumerate() {
+                let members: Vec<&Queued> = queued.iter().filter(|q| q.queue == i).collect();
+                queue.assign(&config.matchmaking, members.as_slice());
+            }
+
+            for client in server.clients() {
+                let waited = client.queued.elapsed();
+                for queue in queues.iter() {
+                    if let Some(m) = queue.matches.iter().find(|m| m.users.contains(&client.player_key)) {
+                        let estimate = queue.estimate(m, waited);
+                        client.command(format!("wait_time/set:{}", m.wait_time).as_str())?;
+                        client.command(format!("estimated_wait/set:{}", estimate).as_str())?;
+                        client.command(format!("players_found/set:{}", m.users.len()).as_str())?;
+                    }
+                }
+            }
+
+            for queue in queues.iter_mut() {
+                let (ready, forming): (Vec<Match>, Vec<Match>) = queue.matches
+                    .drain(..)
+                    .partition(|m| m.wait_time == 0);
+                queue.matches = forming;
+
+                for m in ready {
+                    let entrants: Vec<Entrant> = m.users
+                        .iter()
+                        .map(|key| {
+                            let client = server.clients().find(|c| c.player_key.as_str() == key);
+                            Entrant {
+                                key: key.clone(),
+                                identity: client.as_ref()
+                                    .map(|c| c.identity.clone())
+                                    .unwrap_or(String::new()),
+                                nickname: client.as_ref()
+                                    .map(|c| c.nickname.clone())
+                                    .unwrap_or(String::new()),
+                            }
+                        })
+                        .collect();
+
+                    // create a new instance server to host the match
+                    let ratings = ratings.clone();
+                    let game_config = config.clone();
+                    let interval = game_config.game.tick();
+                    let latency = InstanceContainer::lock(&container).latency();
+                    let address = InstanceContainer::create(&container, move |listener, _| {
+                        let game = GameInstance::new(listener, entrants, game_config, ratings,
+                                                     latency);
+                        Box::new(game)
+                    }, interval);
+
+                    let address = match address {
+                        Ok(address) => address,
+                        Err(e) => {
+                            // keep the match around, so it can start once an instance frees up
+                            println!("Unable to start a {} match: {}", queue.rules.title, e);
+                            queue.matches.push(Match { wait_time: 1, ..m });
+                            continue;
+                        },
+                    };
+
+                    let users = m.users;
+
+                    // keep track of how long it takes to get a match in this queue
+                    for q in queued.iter().filter(|q| users.contains(&q.key)) {
+                        queue.average_wait = queue.average_wait * 0.9 + q.waited.as_secs() as f64 * 0.1;
+                    }
 
                     // make sure everyone has the correct player id
                     for client in server.clients() {
@@ -79,41 +302,27 @@ pub fn run_matchmaking_server<R>(listener: Receiver<R>,
                         }
                     }
 
-                    // create a new instance server to host the match
-                    let c = container.clone();
-                    let slot = container
-                        .lock()
-                        .map(move |mut i| {
-                            let users = users;
-                            i.create(move |listener, _| {
-                                run_game_server(listener, users).expect("Game server failed");
-                            }, c)
-                        })
-                        .expect("Failed to create game instance");
+                    println!("Started a {} match with {} players", queue.rules.title, users.len());
 
                     // report the existence of the new host to the users that should connect to it.
                     let commands = [
-                        format!("instance_address/set:\"{}\"", slot),
+                        format!("instance_address/set:{}", Value::from(address)),
                         format!("done/set:true"),
                     ];
                     for client in server.clients() {
-                        if client.matched {
+                        if users.contains(&client.player_key) {
                             for command in commands.iter() {
                                 client.command(command.as_str()).ok();
                             }
                             client.kick();
                         }
                     }
-
-                    // reset matchmaking
-                    current_match = Match {
-                        users: Vec::new(),
-                        wait_time: 91,
-                    };
                 }
             }
         }
 
-        sleep(Duration::from_millis(500));
-    }
-}
\ No newline at end of file
+        Ok(Poll::Wait(last_match + Duration::from_secs(1)))
+    };
+
+    move || step().expect("matchmaker failed")
+}
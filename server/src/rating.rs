@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::{rename, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::{spawn, JoinHandle};
+
+use serde::*;
+
+/// Conversion factor between the glicko and glicko-2 scales.
+const SCALE: f64 = 173.7178;
+
+/// Constrains how fast the volatility may change between matches.
+const TAU: f64 = 0.5;
+
+/// Number of past ratings remembered per player.
+const HISTORY: usize = 50;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Record {
+    rating: Rating,
+    history: Vec<f64>,
+}
+
+/// Glicko-2 ratings of all players that ever finished a match, keyed by their identity.
+/// Changes are written to disk on a thread of its own, so the store isn't locked while the
+/// disk is busy.
+pub struct RatingStore {
+    players: HashMap<String, Record>,
+    snapshots: Option<Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: 1500.0,
+            deviation: 350.0,
+            volatility: 0.06,
+        }
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+}
+
+fn expected(mu: f64, mu_j: f64, phi_j: f64) -> f64 {
+    1.0 / (1.0 + (-g(phi_j) * (mu - mu_j)).exp())
+}
+
+impl Rating {
+    /// Applies a glicko-2 rating period in which this player played against every player in
+    /// `results`, each with a score of 1.0 (win), 0.5 (draw) or 0.0 (loss).
+    pub fn update(&self, results: &[(Rating, f64)]) -> Rating {
+        let mu = (self.rating - 1500.0) / SCALE;
+        let phi = self.deviation / SCALE;
+        let sigma = self.volatility;
+
+        if results.is_empty() {
+            let phi = (phi * phi + sigma * sigma).sqrt();
+            return Rating { deviation: phi * SCALE, ..*self };
+        }
+
+        let mut v_inv = 0.0;
+        let mut sum = 0.0;
+        for (opponent, score) in results.iter() {
+            let mu_j = (opponent.rating - 1500.0) / SCALE;
+            let phi_j = opponent.deviation / SCALE;
+            let e = expected(mu, mu_j, phi_j);
+            v_inv += g(phi_j) * g(phi_j) * e * (1.0 - e);
+            sum += g(phi_j) * (score - e);
+        }
+        let v = 1.0 / v_inv;
+        let delta = v * sum;
+
+        // find the new volatility using the illinois algorithm
+        let a = (sigma * sigma).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            let d = phi * phi + v + ex;
+            ex * (delta * delta - d) / (2.0 * d * d) - (x - a) / (TAU * TAU)
+        };
+        let mut big_a = a;
+        let mut big_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * TAU) < 0.0 {
+                k += 1.0;
+            }
+            a - k * TAU
+        };
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        while (big_b - big_a).abs() > 0.000001 {
+            let c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(c);
+            if f_c * f_b <= 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = c;
+            f_b = f_c;
+        }
+        let sigma = (big_a / 2.0).exp();
+
+        let phi_star = (phi * phi + sigma * sigma).sqrt();
+        let phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let mu = mu + phi * phi * sum;
+
+        Rating {
+            rating: mu * SCALE + 1500.0,
+            deviation: phi * SCALE,
+            volatility: sigma,
+        }
+    }
+}
+
+impl RatingStore {
+    /// Opens the rating store at `path`. A missing file is treated as an empty store.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let players = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let (snapshots, rx) = channel();
+        let writer = spawn(move || write(path, rx));
+
+        Ok(Self { players, snapshots: Some(snapshots), writer: Some(writer) })
+    }
+
+    pub fn get(&self, identity: &str) -> Rating {
+        self.players.get(identity).map(|r| r.rating).unwrap_or(Rating::default())
+    }
+
+    pub fn history(&self, identity: &str) -> Vec<f64> {
+        self.players.get(identity).map(|r| r.history.clone()).unwrap_or(Vec::new())
+    }
+
+    /// Updates the ratings of everyone that took part in a match. `standings` contains the
+    /// identities of the players, ordered from first to last place. Every player is treated as
+    /// having won from everyone below them and lost from everyone above them.
+    /// Players without an identity still count as opponents, but aren't stored.
+    pub fn record_match(&mut self, standings: &[String]) -> Vec<Rating> {
+        let before: Vec<Rating> = standings.iter().map(|id| self.get(id.as_str())).collect();
+
+        let after: Vec<Rating> = before
+            .iter()
+            .enumerate()
+            .map(|(i, rating)| {
+                let results: Vec<(Rating, f64)> = before
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, _)| i != j)
+                    .map(|(j, &opponent)| (opponent, if i < j { 1.0 } else { 0.0 }))
+                    .collect();
+                rating.update(results.as_slice())
+            })
+            .collect();
+
+        for (identity, rating) in standings.iter().zip(after.iter()) {
+            if !identity.is_empty() {
+                let record = self.players.entry(identity.clone()).or_insert(Record::default());
+                record.rating = *rating;
+                record.history.push(rating.rating);
+                if record.history.len() > HISTORY {
+                    record.history.remove(0);
+                }
+            }
+        }
+
+        match serde_json::to_vec(&self.players) {
+            Ok(snapshot) => {
+                if let Some(snapshots) = self.snapshots.as_ref() {
+                    snapshots.send(snapshot).ok();
+                }
+            },
+            Err(e) => println!("Unable to save ratings: {}", e),
+        }
+
+        after
+    }
+}
+
+impl Drop for RatingStore {
+    /// Waits for the changes that weren't written yet.
+    fn drop(&mut self) {
+        self.snapshots = None;
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+/// Writes the snapshots of a store to `path` until the store is dropped. Only the latest
+/// snapshot is written when the disk falls behind.
+fn write(path: PathBuf, snapshots: Receiver<Vec<u8>>) {
+    while let Ok(mut snapshot) = snapshots.recv() {
+        while let Ok(newer) = snapshots.try_recv() {
+            snapshot = newer;
+        }
+        if let Err(e) = save(&path, snapshot.as_slice()) {
+            println!("Unable to save ratings to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Writes the ratings next to the store first and then moves them over it, so a crash or a
+/// full disk leaves the previous ratings intact.
+fn save(path: &Path, snapshot: &[u8]) -> Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    writer.write_all(snapshot)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "expected {}, got {}", expected, actual);
+    }
+
+    /// The example worked out in Glickman's description of the glicko-2 system.
+    #[test]
+    fn glickman_example() {
+        let player = Rating { rating: 1500.0, deviation: 200.0, volatility: 0.06 };
+        let opponent = |rating, deviation| Rating { rating, deviation, volatility: 0.06 };
+
+        let updated = player.update(&[
+            (opponent(1400.0, 30.0), 1.0),
+            (opponent(1550.0, 100.0), 0.0),
+            (opponent(1700.0, 300.0), 0.0),
+        ]);
+
+        assert_close(updated.rating, 1464.06, 0.01);
+        assert_close(updated.deviation, 151.52, 0.01);
+        assert_close(updated.volatility, 0.05999, 0.00001);
+    }
+
+    #[test]
+    fn volatility_grows_on_surprises() {
+        let player = Rating { rating: 1500.0, deviation: 50.0, volatility: 0.06 };
+        let opponent = Rating { rating: 2200.0, deviation: 50.0, volatility: 0.06 };
+
+        let updated = player.update(&[(opponent, 1.0); 5]);
+
+        assert!(updated.volatility > player.volatility);
+        assert!(updated.rating > player.rating);
+    }
+
+    #[test]
+    fn no_games_only_widen_the_deviation() {
+        let player = Rating { rating: 1600.0, deviation: 100.0, volatility: 0.06 };
+
+        let updated = player.update(&[]);
+
+        assert_close(updated.rating, 1600.0, 0.000001);
+        assert_close(updated.deviation, (100.0f64.powi(2) + (0.06 * SCALE).powi(2)).sqrt(),
+                     0.000001);
+    }
+
+    #[test]
+    fn record_match_saves_the_standings() {
+        let path = std::env::temp_dir().join(format!("ratings-{}.json", std::process::id()));
+        let mut store = RatingStore::open(&path).unwrap();
+
+        let standings = vec!["first".to_string(), String::new(), "last".to_string()];
+        let ratings = store.record_match(standings.as_slice());
+
+        assert!(ratings[0].rating > 1500.0);
+        assert!(ratings[2].rating < 1500.0);
+
+        // dropping the store waits for the ratings to be written
+        drop(store);
+        let reopened = RatingStore::open(&path).unwrap();
+        assert_close(reopened.get("first").rating, ratings[0].rating, 0.000001);
+        assert_eq!(reopened.history("last"), vec![ratings[2].rating]);
+        assert_eq!(reopened.players.len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
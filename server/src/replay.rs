@@ -0,0 +1,122 @@
+use std::fs::{File, create_dir_all};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rand::random;
+use tetris_model::instance::InstanceState;
+use tetris_model::replay::*;
+
+/// A remote that never sends anything and writes everything it receives to a replay file.
+/// It is connected to a game instance like any other client, so it sees the exact message stream
+/// that players see, starting with the initial state sync.
+pub struct ReplayRecorder {
+    file: BufWriter<File>,
+    started: Instant,
+    alive: Arc<AtomicBool>,
+}
+
+impl ReplayRecorder {
+    /// Creates a new replay file in `dir` and writes the header for `instance` to it.
+    /// Returns the recorder together with the name of the created file.
+    pub fn create<P: AsRef<Path>>(dir: P, instance: &InstanceState)
+        -> ::std::io::Result<(Self, String)>
+    {
+        create_dir_all(dir.as_ref())?;
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let name = format!("{}-{:08x}.replay", created, random::<u32>());
+
+        let mut file = BufWriter::new(File::create(dir.as_ref().join(name.as_str()))?);
+
+        let header = ReplayHeader {
+            version: REPLAY_VERSION,
+            created,
+            players: instance.games.len(),
+            snapshot: serde_json::to_value(instance)?,
+        };
+        serde_json::to_writer(&mut file, &header)?;
+        file.write_all(b"\n")?;
+
+        let alive = Arc::new(AtomicBool::new(true));
+        Ok((Self { file, started: Instant::now(), alive }, name))
+    }
+
+    /// Returns a flag that stays true for as long as the recorder is still recording.
+    pub fn recording(&self) -> Arc<AtomicBool> {
+        self.alive.clone()
+    }
+}
+
+impl Drop for ReplayRecorder {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+impl mirror::Remote for ReplayRecorder {
+    fn close(&mut self) {
+        self.file.flush().ok();
+        self.alive.store(false, Ordering::SeqCst);
+    }
+
+    fn alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), mirror::Error> {
+        // calls to the instance itself name players by their keys, and replays are served to
+        // anyone. Playback doesn't need them, the changes they make are recorded separately.
+        if self.alive() && !message.starts_with("call:") {
+            let elapsed = self.started.elapsed();
+            let entry = ReplayEntry {
+                time: elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64,
+                message: message.to_string(),
+            };
+
+            let written = serde_json::to_writer(&mut self.file, &entry)
+                .map_err(|e| e.into())
+                .and_then(|_| self.file.write_all(b"\n"));
+
+            if let Err(e) = written {
+                println!("Replay recording stopped: {}", e);
+                self.alive.store(false, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mirror::Remote;
+    use std::fs::{read_to_string, remove_dir_all};
+
+    #[test]
+    fn player_keys_stay_out_of_the_recording() {
+        let dir = std::env::temp_dir().join(format!("replays-{}", std::process::id()));
+        let instance = InstanceState::new(vec!["secret-key".to_string()]);
+        let (mut recorder, name) = ReplayRecorder::create(&dir, &instance).unwrap();
+
+        recorder.send("call:login:\"secret-key\" \"nickname\"").unwrap();
+        recorder.send("games/0/nickname/set:\"nickname\"").unwrap();
+        recorder.close();
+
+        let recorded = read_to_string(dir.join(name)).unwrap();
+        remove_dir_all(&dir).ok();
+
+        assert!(!recorded.contains("secret-key"));
+        assert_eq!(recorded.lines().count(), 2);
+        assert!(recorded.contains("nickname/set"));
+    }
+}
@@ -0,0 +1,351 @@
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::mem::replace;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+/// What a task wants to happen after it was polled.
+pub enum Poll {
+    /// Poll again at the given time, or earlier when the task is woken.
+    Wait(Instant),
+    Done,
+}
+
+/// Something that runs on the scheduler, like a game instance. Tasks should do whatever work is
+/// available when polled and return quickly.
+pub trait Task: Send {
+    fn poll(&mut self) -> Poll;
+}
+
+impl<F: FnMut() -> Poll + Send> Task for F {
+    fn poll(&mut self) -> Poll {
+        self()
+    }
+}
+
+/// Wakes up a task, so it's polled again soon. Waking a task that has finished does nothing.
+#[derive(Clone)]
+pub struct Waker {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+/// Runs tasks on a fixed number of worker threads.
+#[derive(Clone)]
+pub struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+/// Counters that show how well the scheduler keeps up.
+#[derive(Clone, Copy, Default)]
+pub struct SchedulerStats {
+    pub tasks: usize,
+    pub polls: u64,
+    /// Total time tasks spent being polled.
+    pub busy: Duration,
+    /// Total time tasks were polled later than they asked for.
+    pub lag: Duration,
+    pub max_lag: Duration,
+}
+
+struct Slot {
+    task: Option<Box<Task>>,
+    queued: bool,
+    woken: bool,
+    deadline: Option<Instant>,
+    last_poll: Option<Instant>,
+    interval: Duration,
+}
+
+struct State {
+    slots: HashMap<usize, Slot>,
+    ready: VecDeque<(usize, Instant)>,
+    timers: BinaryHeap<Reverse<(Instant, usize)>>,
+    next_id: usize,
+    stats: SchedulerStats,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    signal: Condvar,
+}
+
+impl State {
+    /// Queues the task with `id` to be polled, but no sooner than its interval allows.
+    fn wake(&mut self, id: usize, now: Instant) {
+        if let Some(slot) = self.slots.get_mut(&id) {
+            if slot.task.is_none() {
+                // it's being polled right now, poll it again when it's done
+                slot.woken = true;
+                return;
+            }
+            if slot.queued {
+                return;
+            }
+
+            let earliest = slot.last_poll.map(|t| t + slot.interval).unwrap_or(now);
+            if earliest <= now {
+                slot.queued = true;
+                slot.deadline = None;
+                self.ready.push_back((id, now));
+            } else if slot.deadline.map(|d| d > earliest).unwrap_or(true) {
+                slot.deadline = Some(earliest);
+                self.timers.push(Reverse((earliest, id)));
+            }
+        }
+    }
+
+    /// Moves every task with an expired timer to the ready queue.
+    /// Returns how long to wait for the next timer if nothing is ready.
+    fn expire(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(&Reverse((deadline, id))) = self.timers.peek() {
+            if deadline > now {
+                return Some(deadline - now);
+            }
+            self.timers.pop();
+
+            if let Some(slot) = self.slots.get_mut(&id) {
+                // timers are never removed from the heap, skip the ones that were replaced
+                if slot.deadline == Some(deadline) && slot.task.is_some() && !slot.queued {
+                    slot.queued = true;
+                    slot.deadline = None;
+                    self.ready.push_back((id, deadline));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.wake(self.id, Instant::now());
+        self.shared.signal.notify_one();
+    }
+}
+
+impl Scheduler {
+    pub fn new(workers: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                slots: HashMap::new(),
+                ready: VecDeque::new(),
+                timers: BinaryHeap::new(),
+                next_id: 0,
+                stats: SchedulerStats::default(),
+            }),
+            signal: Condvar::new(),
+        });
+
+        for _ in 0..workers {
+            let shared = shared.clone();
+            spawn(move || work(shared));
+        }
+
+        Self { shared }
+    }
+
+    /// Adds a task, which is polled right away. The task isn't polled more often than once
+    /// every `interval`, no matter how often it's woken.
+    pub fn spawn(&self, task: Box<Task>, interval: Duration) -> Waker {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.slots.insert(id, Slot {
+            task: Some(task),
+            queued: false,
+            woken: false,
+            deadline: None,
+            last_poll: None,
+            interval,
+        });
+        state.wake(id, Instant::now());
+        self.shared.signal.notify_one();
+
+        Waker { id, shared: self.shared.clone() }
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        let state = self.shared.state.lock().unwrap();
+        SchedulerStats { tasks: state.slots.len(), ..state.stats }
+    }
+}
+
+/// Returns the message a panic was started with.
+pub fn panic_message(panic: &Box<Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs tasks until the program ends. A task that panics, while being polled or while being
+/// dropped after it finished, is gone, but the worker carries on with the others.
+fn work(shared: Arc<Shared>) {
+    loop {
+        // wait for a task to become ready
+        let (id, mut task, due) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                let timeout = state.expire(Instant::now());
+                if let Some((id, due)) = state.ready.pop_front() {
+                    let slot = state.slots.get_mut(&id).unwrap();
+                    slot.queued = false;
+                    slot.woken = false;
+                    slot.last_poll = Some(Instant::now());
+                    break (id, slot.task.take().unwrap(), due);
+                }
+
+                state = match timeout {
+                    Some(timeout) => shared.signal.wait_timeout(state, timeout).unwrap().0,
+                    None => shared.signal.wait(state).unwrap(),
+                };
+            }
+        };
+
+        let started = Instant::now();
+        let result = catch_unwind(AssertUnwindSafe(|| task.poll())).unwrap_or_else(|panic| {
+            println!("Task {} panicked: {}", id, panic_message(&panic));
+            Poll::Done
+        });
+        let finished = Instant::now();
+
+        let mut state = shared.state.lock().unwrap();
+        let lag = started.duration_since(due);
+        state.stats.polls += 1;
+        state.stats.busy += finished.duration_since(started);
+        state.stats.lag += lag;
+        state.stats.max_lag = state.stats.max_lag.max(lag);
+
+        match result {
+            Poll::Wait(deadline) => {
+                let woken = {
+                    let slot = state.slots.get_mut(&id).unwrap();
+                    slot.task = Some(task);
+                    replace(&mut slot.woken, false)
+                };
+
+                if woken {
+                    state.wake(id, finished);
+                } else {
+                    state.slots.get_mut(&id).unwrap().deadline = Some(deadline);
+                    state.timers.push(Reverse((deadline, id)));
+                }
+                shared.signal.notify_one();
+            },
+            Poll::Done => {
+                state.slots.remove(&id);
+                drop(state);
+                if let Err(panic) = catch_unwind(AssertUnwindSafe(move || drop(task))) {
+                    println!("Task {} panicked while finishing: {}", id, panic_message(&panic));
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Sender};
+
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    /// A task that waits until `delay` after its first poll and then reports `name`.
+    fn timer(name: &'static str, delay: Duration, tx: Sender<&'static str>) -> Box<Task> {
+        let mut at = None;
+        Box::new(move || match at {
+            None => {
+                let deadline = Instant::now() + delay;
+                at = Some(deadline);
+                Poll::Wait(deadline)
+            },
+            Some(_) => {
+                tx.send(name).unwrap();
+                Poll::Done
+            },
+        })
+    }
+
+    /// A task that finishes right away, and panics when it's dropped.
+    struct Finish;
+
+    impl Task for Finish {
+        fn poll(&mut self) -> Poll {
+            Poll::Done
+        }
+    }
+
+    impl Drop for Finish {
+        fn drop(&mut self) {
+            panic!("dropped");
+        }
+    }
+
+    #[test]
+    fn timers_run_in_order() {
+        let scheduler = Scheduler::new(1);
+        let (tx, rx) = channel();
+
+        let late = timer("late", Duration::from_millis(80), tx.clone());
+        let early = timer("early", Duration::from_millis(20), tx);
+        scheduler.spawn(late, Duration::from_millis(0));
+        scheduler.spawn(early, Duration::from_millis(0));
+
+        assert_eq!(rx.recv_timeout(PATIENCE), Ok("early"));
+        assert_eq!(rx.recv_timeout(PATIENCE), Ok("late"));
+    }
+
+    #[test]
+    fn woken_tasks_are_polled_before_their_timer() {
+        let scheduler = Scheduler::new(1);
+        let (tx, rx) = channel();
+
+        let waker = scheduler.spawn(Box::new(move || {
+            tx.send(()).unwrap();
+            Poll::Wait(Instant::now() + Duration::from_secs(3600))
+        }), Duration::from_millis(0));
+
+        assert!(rx.recv_timeout(PATIENCE).is_ok());
+        waker.wake();
+        assert!(rx.recv_timeout(PATIENCE).is_ok());
+    }
+
+    #[test]
+    fn wakes_are_held_back_by_the_interval() {
+        let scheduler = Scheduler::new(1);
+        let (tx, rx) = channel();
+
+        let waker = scheduler.spawn(Box::new(move || {
+            tx.send(Instant::now()).unwrap();
+            Poll::Wait(Instant::now() + Duration::from_secs(3600))
+        }), Duration::from_millis(100));
+
+        let first = rx.recv_timeout(PATIENCE).unwrap();
+        waker.wake();
+        let second = rx.recv_timeout(PATIENCE).unwrap();
+        assert!(second.duration_since(first) >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn workers_survive_panicking_tasks() {
+        let scheduler = Scheduler::new(1);
+        let (tx, rx) = channel();
+
+        scheduler.spawn(Box::new(|| -> Poll { panic!("polled") }), Duration::from_millis(0));
+        scheduler.spawn(Box::new(Finish), Duration::from_millis(0));
+        scheduler.spawn(Box::new(move || {
+            tx.send(()).unwrap();
+            Poll::Done
+        }), Duration::from_millis(0));
+
+        assert!(rx.recv_timeout(PATIENCE).is_ok());
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::view::{Boards, View};
+use mirror::*;
+use serde::Serialize;
+use serde_json::Value;
+use tetris_model::instance::unix_millis;
+
+struct Seat {
+    links: Vec<Arc<AtomicBool>>,
+    seen: Instant,
+    input: Instant,
+    /// When the live connection of the seat last sent anything.
+    heard: Instant,
+    /// The last and the longest round trip time the player reported, in milliseconds.
+    rtt: Option<(u64, u64)>,
+}
+
+/// Keeps track of which player keys currently have a live connection to a game instance.
+#[derive(Clone)]
+pub struct Roster {
+    players: Arc<Vec<String>>,
+    seats: Arc<Mutex<HashMap<String, Seat>>>,
+    latency: Latency,
+}
+
+/// The round trip times most recently reported by players in any match, for monitoring.
+#[derive(Clone)]
+pub struct Latency {
+    samples: Arc<Mutex<VecDeque<u64>>>,
+}
+
+/// A summary of the recent round trip times, in milliseconds.
+#[derive(Serialize)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub median_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Only lets through calls to a fixed set of functions. Raw path commands and anything else a
+/// client could use to change state directly are dropped, and connections that keep sending
+/// them are closed.
+pub struct Allowlist<R: Remote> {
+    remote: R,
+    calls: &'static [(&'static str, usize)],
+    rejected: usize,
+}
+
+/// A connection to a game instance. Sessions look at the calls passing through them, so the
+/// instance knows which player is behind which connection and when they last did something.
+/// Players may only make calls for their own seat, and only one connection at a time can be
+/// logged in to a seat. Once logged in, messages from the instance are sent through the player's
+/// view, so boards of other players are sent at a reduced rate.
+pub struct Session<R: Remote> {
+    remote: Allowlist<R>,
+    roster: Roster,
+    player: Option<String>,
+    link: Option<Arc<AtomicBool>>,
+    boards: Boards,
+    snapshot_interval: Duration,
+    view: Option<View>,
+}
+
+/// The calls players may make in a game and how many arguments they send. Only `login` names
+/// the player's seat, the session adds it to every other call as the first argument.
+pub const GAME_CALLS: [(&str, usize); 6] = [
+    ("login", 2),
+    ("drop", 3),
+    ("target", 1),
+    ("hold", 1),
+    ("resync", 0),
+    ("clock", 2),
+];
+
+/// The calls clients may make while they are being matched.
+pub const MATCHMAKING_CALLS: [(&str, usize); 2] = [("identify", 2), ("join", 1)];
+
+/// How many round trip times are kept for monitoring.
+const LATENCY_SAMPLES: usize = 1000;
+
+/// Round trip times longer than this are made up and ignored, in milliseconds.
+const MAX_RTT: u64 = 60_000;
+
+/// Connections are closed after this many messages were rejected.
+const MAX_REJECTED: usize = 8;
+
+/// Clients compare clocks every 2 seconds, a connection that hasn't sent anything for this long
+/// is most likely lost and may be replaced by a new login to the same seat.
+const QUIET_LINK: Duration = Duration::from_secs(5);
+
+/// The calls that count as a player being active.
+const INPUT_CALLS: [&str; 3] = ["drop", "hold", "target"];
+
+/// Returns the arguments of `message` if it is a call to the function `name`.
+pub fn call_args(message: &str, name: &str) -> Option<Vec<Value>> {
+    let prefix = format!("call:{}:", name);
+    if message.starts_with(prefix.as_str()) {
+        serde_json::Deserializer::from_str(&message[prefix.len()..])
+            .into_iter::<Value>()
+            .collect::<Result<Vec<Value>, _>>()
+            .ok()
+    } else {
+        None
+    }
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self { samples: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    fn record(&self, rtt: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() >= LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        let mut samples: Vec<u64> = self.samples.lock().unwrap().iter().cloned().collect();
+        samples.sort();
+        let percentile = |p: usize| samples.get(samples.len() * p / 100).cloned().unwrap_or(0);
+
+        LatencyStats {
+            samples: samples.len(),
+            median_ms: percentile(50),
+            p95_ms: percentile(95),
+            max_ms: samples.last().cloned().unwrap_or(0),
+        }
+    }
+}
+
+impl Roster {
+    /// Creates the roster of a match. Round trip times reported by the players also go to
+    /// `latency`.
+    pub fn new(players: &[String], latency: Latency) -> Self {
+        let now = Instant::now();
+        Self {
+            players: Arc::new(players.to_vec()),
+            seats: Arc::new(Mutex::new(players
+                .iter()
+                .map(|key| {
+                    let seat = Seat {
+                        links: Vec::new(),
+                        seen: now,
+                        input: now,
+                        heard: now,
+                        rtt: None,
+                    };
+                    (key.clone(), seat)
+                })
+                .collect())),
+            latency,
+        }
+    }
+
+    /// Ties a new connection to the seat of `key`. Fails for unknown keys and for seats that are
+    /// held by a connection that is still alive and talking. A connection that went quiet is
+    /// most likely one the player lost without the server noticing yet, it makes way for the new
+    /// one so the player can reconnect right away.
+    fn bind(&self, key: &str) -> Option<Arc<AtomicBool>> {
+        let mut seats = self.seats.lock().unwrap();
+        seats.get_mut(key).and_then(|seat| {
+            seat.links.retain(|link| link.load(Ordering::SeqCst));
+            if !seat.links.is_empty() && seat.heard.elapsed() < QUIET_LINK {
+                return None;
+            }
+            for link in seat.links.drain(..) {
+                println!("Player {} reconnected, closing the connection that went quiet", key);
+                link.store(false, Ordering::SeqCst);
+            }
+
+            let now = Instant::now();
+            let link = Arc::new(AtomicBool::new(true));
+            seat.links.push(link.clone());
+            seat.seen = now;
+            seat.input = now;
+            seat.heard = now;
+            Some(link)
+        })
+    }
+
+    /// Returns the index of the seat of `key` in the game.
+    fn index(&self, key: &str) -> Option<usize> {
+        self.players.iter().position(|p| p.as_str() == key)
+    }
+
+    fn hear(&self, key: &str) {
+        if let Some(seat) = self.seats.lock().unwrap().get_mut(key) {
+            seat.heard = Instant::now();
+        }
+    }
+
+    fn touch(&self, key: &str) {
+        if let Some(seat) = self.seats.lock().unwrap().get_mut(key) {
+            seat.input = Instant::now();
+        }
+    }
+
+    fn report_rtt(&self, key: &str, rtt: u64) {
+        if let Some(seat) = self.seats.lock().unwrap().get_mut(key) {
+            let max = seat.rtt.map(|(_, max)| max).unwrap_or(0).max(rtt);
+            seat.rtt = Some((rtt, max));
+            self.latency.record(rtt);
+        }
+    }
+
+    /// Returns the last and the longest round trip time the player with `key` reported,
+    /// in milliseconds.
+    pub fn rtt(&self, key: &str) -> Option<(u64, u64)> {
+        self.seats.lock().unwrap().get(key).and_then(|seat| seat.rtt)
+    }
+
+    /// Forgets about closed connections and marks seats with a live connection as seen.
+    pub fn refresh(&self) {
+        let now = Instant::now();
+        for seat in self.seats.lock().unwrap().values_mut() {
+            seat.links.retain(|link| link.load(Ordering::SeqCst));
+            if seat.links.len() > 0 {
+                seat.seen = now;
+            }
+        }
+    }
+
+    /// Returns how long the player with `key` has been without a connection,
+    /// or `None` if the player is currently connected.
+    pub fn disconnected_for(&self, key: &str) -> Option<Duration> {
+        self.seats
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|seat| seat.links.is_empty())
+            .map(|seat| seat.seen.elapsed())
+    }
+
+    /// Returns how long ago the player with `key` sent any input.
+    pub fn idle_for(&self, key: &str) -> Duration {
+        self.seats
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|seat| seat.input.elapsed())
+            .unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Returns true if every player has been gone for longer than `grace`.
+    pub fn abandoned(&self, grace: Duration) -> bool {
+        self.seats
+            .lock()
+            .unwrap()
+            .values()
+            .all(|seat| seat.links.is_empty() && seat.seen.elapsed() > grace)
+    }
+}
+
+impl<R: Remote> Allowlist<R> {
+    pub fn new(remote: R, calls: &'static [(&'static str, usize)]) -> Self {
+        Self { remote, calls, rejected: 0 }
+    }
+
+    /// Drops a message, closing the connection if it has been misbehaving for too long.
+    fn reject(&mut self, message: &str, reason: &str) {
+        self.rejected += 1;
+        let shown: String = message.chars().take(80).collect();
+        println!("Rejected message ({}/{}), {}: {}", self.rejected, MAX_REJECTED, reason, shown);
+
+        if self.rejected >= MAX_REJECTED {
+            println!("Closing connection after {} rejected messages", self.rejected);
+            self.remote.close();
+        }
+    }
+}
+
+impl<R: Remote> Remote for Allowlist<R> {
+    fn close(&mut self) {
+        self.remote.close();
+    }
+
+    fn alive(&self) -> bool {
+        self.remote.alive()
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), Error> {
+        self.remote.send(message)
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        loop {
+            if self.rejected >= MAX_REJECTED {
+                return None;
+            }
+
+            let message = self.remote.recv()?;
+            let allowed = self.calls
+                .iter()
+                .any(|&(name, args)| {
+                    call_args(message.as_str(), name).map(|a| a.len() == args).unwrap_or(false)
+                });
+
+            if allowed {
+                return Some(message);
+            }
+            self.reject(message.as_str(), "not an allowed call");
+        }
+    }
+}
+
+impl<R: Remote> Session<R> {
+    /// Creates a session for a new connection. Boards the player isn't following closely are
+    /// sent as snapshots taken from `boards`, no more than once every `snapshot_interval`.
+    pub fn new(remote: R, roster: Roster, boards: Boards, snapshot_interval: Duration) -> Self {
+        Self {
+            remote: Allowlist::new(remote, &GAME_CALLS),
+            roster,
+            player: None,
+            link: None,
+            boards,
+            snapshot_interval,
+            view: None,
+        }
+    }
+
+    /// Sends the snapshots that are due in the player's view.
+    fn sync(&mut self) -> Result<(), Error> {
+        if let Some(view) = self.view.as_mut() {
+            for message in view.sync() {
+                self.remote.send(message.as_str())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if another connection has taken over the seat of this one.
+    fn replaced(&self) -> bool {
+        self.link.as_ref().map(|link| !link.load(Ordering::SeqCst)).unwrap_or(false)
+    }
+
+    fn unlink(&mut self) {
+        if let Some(link) = self.link.take() {
+            link.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<R: Remote> Drop for Session<R> {
+    fn drop(&mut self) {
+        self.unlink();
+    }
+}
+
+impl<R: Remote> Remote for Session<R> {
+    fn close(&mut self) {
+        self.remote.close();
+        self.unlink();
+    }
+
+    fn alive(&self) -> bool {
+        if self.replaced() {
+            return false;
+        }
+
+        let alive = self.remote.alive();
+        if !alive {
+            if let Some(link) = self.link.as_ref() {
+                link.store(false, Ordering::SeqCst);
+            }
+        }
+        alive
+    }
+
+    fn send(&mut self, message: &str) -> Result<(), Error> {
+        if self.replaced() {
+            return Err(Error::ConnectionDropped);
+        }
+
+        self.sync()?;
+        if self.view.as_mut().map(|view| view.filter(message)).unwrap_or(true) {
+            self.remote.send(message)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        // a connection that lost its seat to a newer one has nothing left to do
+        if self.replaced() {
+            self.remote.close();
+            return None;
+        }
+
+        // snapshots are also sent while nothing else happens to the boards being followed
+        self.sync().ok();
+
+        loop {
+            let message = self.remote.recv()?;
+            if let Some(player) = self.player.as_ref() {
+                self.roster.hear(player.as_str());
+            }
+
+            // clients compare their clock to the server's, whether they're logged in or not,
+            // the instance isn't involved so the answer goes out as soon as the call is read.
+            // Along with it they report the round trip time of the previous call, if any.
+            if let Some(args) = call_args(message.as_str(), "clock") {
+                match args.get(0).and_then(|sent| sent.as_u64()) {
+                    Some(sent) => {
+                        let answer = format!("clock/set:[{},{}]", sent, unix_millis());
+                        self.remote.send(answer.as_str()).ok();
+                    },
+                    None => self.remote.reject(message.as_str(), "no time given"),
+                }
+
+                let rtt = args.get(1).and_then(|rtt| rtt.as_u64()).filter(|&rtt| rtt <= MAX_RTT);
+                if let (Some(player), Some(rtt)) = (self.player.as_ref(), rtt) {
+                    self.roster.report_rtt(player.as_str(), rtt);
+                }
+                continue;
+            }
+
+            // logging in ties this connection to the player's seat
+            if let Some(args) = call_args(message.as_str(), "login") {
+                let key = match args.get(0) {
+                    Some(Value::String(key)) => key.clone(),
+                    _ => {
+                        self.remote.reject(message.as_str(), "no seat given");
+                        continue;
+                    },
+                };
+
+                if self.player.as_ref() == Some(&key) {
+                    return Some(message);
+                } else if self.player.is_some() {
+                    self.remote.reject(message.as_str(), "already logged in to another seat");
+                    continue;
+                }
+
+                self.link = self.roster.bind(key.as_str());
+                if self.link.is_none() {
+                    self.remote.reject(message.as_str(), "seat unknown or taken");
+                    continue;
+                }
+
+                self.view = self.roster.index(key.as_str()).map(|seat| {
+                    View::new(self.boards.clone(), seat, self.snapshot_interval)
+                });
+                self.player = Some(key);
+                return Some(message);
+            }
+
+            // every other call is made for the seat this connection is logged in to
+            let player = match self.player.as_ref() {
+                Some(player) => player,
+                None => {
+                    self.remote.reject(message.as_str(), "not logged in to a seat");
+                    continue;
+                },
+            };
+
+            let call = GAME_CALLS
+                .iter()
+                .map(|&(name, _)| name)
+                .find(|name| call_args(message.as_str(), name).is_some());
+            if let Some(name) = call {
+                // asking for a copy of the player's state is handled by the session itself
+                if name == "resync" {
+                    if let Some(view) = self.view.as_mut() {
+                        view.reset();
+                    }
+                    continue;
+                }
+
+                if INPUT_CALLS.contains(&name) {
+                    self.roster.touch(player.as_str());
+                }
+
+                let prefix = format!("call:{}:", name);
+                let args = message[prefix.len()..].trim();
+                let key = Value::from(player.as_str());
+                return Some(if args.is_empty() {
+                    format!("{}{}", prefix, key)
+                } else {
+                    format!("{}{} {}", prefix, key, args)
+                });
+            }
+            self.remote.reject(message.as_str(), "not an allowed call");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tetris_model::instance::PlayerState;
+
+    /// A connection the test talks to through a clone of it.
+    #[derive(Clone)]
+    struct Mock {
+        incoming: Arc<Mutex<VecDeque<String>>>,
+        sent: Arc<Mutex<Vec<String>>>,
+        alive: Arc<AtomicBool>,
+    }
+
+    impl Mock {
+        fn new() -> Self {
+            Self {
+                incoming: Arc::new(Mutex::new(VecDeque::new())),
+                sent: Arc::new(Mutex::new(Vec::new())),
+                alive: Arc::new(AtomicBool::new(true)),
+            }
+        }
+
+        fn push(&self, message: &str) {
+            self.incoming.lock().unwrap().push_back(message.to_string());
+        }
+    }
+
+    impl Remote for Mock {
+        fn close(&mut self) {
+            self.alive.store(false, Ordering::SeqCst);
+        }
+
+        fn alive(&self) -> bool {
+            self.alive.load(Ordering::SeqCst)
+        }
+
+        fn send(&mut self, message: &str) -> Result<(), Error> {
+            self.sent.lock().unwrap().push(message.to_string());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Option<String> {
+            self.incoming.lock().unwrap().pop_front()
+        }
+    }
+
+    fn roster() -> Roster {
+        Roster::new(&["a".to_string(), "b".to_string()], Latency::new())
+    }
+
+    fn session(roster: &Roster) -> (Session<Mock>, Mock) {
+        let remote = Mock::new();
+        let boards = Boards::new(&[PlayerState::new(), PlayerState::new()]);
+        let session = Session::new(remote.clone(), roster.clone(), boards, Duration::from_secs(1));
+        (session, remote)
+    }
+
+    fn go_quiet(roster: &Roster, key: &str) {
+        let mut seats = roster.seats.lock().unwrap();
+        let seat = seats.get_mut(key).unwrap();
+        seat.heard = Instant::now() - QUIET_LINK - Duration::from_secs(1);
+    }
+
+    #[test]
+    fn a_seat_takes_one_live_connection() {
+        let roster = roster();
+        assert!(roster.bind("a").is_some());
+        assert!(roster.bind("a").is_none());
+        assert!(roster.bind("b").is_some());
+        assert!(roster.bind("c").is_none());
+    }
+
+    #[test]
+    fn a_closed_connection_makes_way() {
+        let roster = roster();
+        let first = roster.bind("a").unwrap();
+        first.store(false, Ordering::SeqCst);
+        assert!(roster.bind("a").is_some());
+    }
+
+    #[test]
+    fn a_quiet_connection_is_replaced() {
+        let roster = roster();
+        let first = roster.bind("a").unwrap();
+        go_quiet(&roster, "a");
+
+        let second = roster.bind("a").unwrap();
+        assert!(!first.load(Ordering::SeqCst));
+        assert!(second.load(Ordering::SeqCst));
+        assert!(roster.bind("a").is_none());
+    }
+
+    #[test]
+    fn a_second_login_is_rejected_while_the_first_talks() {
+        let roster = roster();
+        let (mut first, first_remote) = session(&roster);
+        let (mut second, second_remote) = session(&roster);
+
+        first_remote.push("call:login:\"a\" \"nick\"");
+        assert_eq!(first.recv(), Some("call:login:\"a\" \"nick\"".to_string()));
+
+        second_remote.push("call:login:\"a\" \"nick\"");
+        assert_eq!(second.recv(), None);
+        assert_eq!(second.remote.rejected, 1);
+        assert!(first.alive());
+    }
+
+    #[test]
+    fn a_reconnect_takes_over_from_a_lost_connection() {
+        let roster = roster();
+        let (mut first, first_remote) = session(&roster);
+        let (mut second, second_remote) = session(&roster);
+
+        first_remote.push("call:login:\"a\" \"nick\"");
+        first.recv();
+        go_quiet(&roster, "a");
+
+        second_remote.push("call:login:\"a\" \"nick\"");
+        assert_eq!(second.recv(), Some("call:login:\"a\" \"nick\"".to_string()));
+        assert!(!first.alive());
+
+        // the old connection has nothing left to say, even if the player is still behind it
+        first_remote.push("call:hold:1");
+        assert_eq!(first.recv(), None);
+        assert!(!first_remote.alive());
+
+        second_remote.push("call:hold:1");
+        assert_eq!(second.recv(), Some("call:hold:\"a\" 1".to_string()));
+    }
+
+    #[test]
+    fn allowed_calls_pass() {
+        let mock = Mock::new();
+        let mut allowlist = Allowlist::new(mock.clone(), &MATCHMAKING_CALLS);
+
+        mock.push("call:identify:\"abc\" \"nick\"");
+        mock.push("call:join:\"casual\"");
+        assert_eq!(allowlist.recv(), Some("call:identify:\"abc\" \"nick\"".to_string()));
+        assert_eq!(allowlist.recv(), Some("call:join:\"casual\"".to_string()));
+        assert_eq!(allowlist.recv(), None);
+        assert_eq!(allowlist.rejected, 0);
+    }
+
+    #[test]
+    fn other_messages_are_dropped() {
+        let mock = Mock::new();
+        let mut allowlist = Allowlist::new(mock.clone(), &GAME_CALLS);
+
+        mock.push("call:hold:1 2");
+        mock.push("call:login:\"a\"");
+        mock.push("call:server_update:");
+        mock.push("games/0/score/set:1000");
+        mock.push("call:hold:not json");
+        mock.push("call:hold:3");
+        assert_eq!(allowlist.recv(), Some("call:hold:3".to_string()));
+        assert_eq!(allowlist.rejected, 5);
+        assert!(mock.alive());
+    }
+
+    #[test]
+    fn misbehaving_connections_are_closed() {
+        let mock = Mock::new();
+        let mut allowlist = Allowlist::new(mock.clone(), &GAME_CALLS);
+
+        for _ in 0..MAX_REJECTED {
+            mock.push("call:forfeit:\"a\" \"bored\"");
+        }
+        mock.push("call:hold:1");
+        assert_eq!(allowlist.recv(), None);
+        assert!(!mock.alive());
+
+        // nothing gets through anymore, not even allowed calls
+        assert_eq!(allowlist.recv(), None);
+        assert_eq!(allowlist.rejected, MAX_REJECTED);
+    }
+}
@@ -0,0 +1,359 @@
+use std::mem::replace;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use tetris_model::instance::PlayerState;
+
+/// The boards of a game as they were at the end of the last update of its instance. Instances
+/// publish their boards after every update, connections use them to catch up on boards they
+/// haven't been following closely.
+#[derive(Clone)]
+pub struct Boards {
+    inner: Arc<Mutex<Published>>,
+    /// The epoch of the last publication, so views can see there's nothing new without locking.
+    epoch: Arc<AtomicUsize>,
+}
+
+struct Published {
+    /// Counts the publications, so views can tell whether something was published since.
+    epoch: u64,
+    boards: Vec<Board>,
+    /// Set when a view needs full player states from the next publication.
+    states_wanted: bool,
+}
+
+struct Board {
+    version: u64,
+    target: usize,
+    field: String,
+    garbage: String,
+    /// The full player state, only filled in when it was asked for.
+    state: String,
+}
+
+/// How closely a connection follows a board.
+#[derive(Clone, Copy)]
+enum Detail {
+    /// Every change is sent right away.
+    Full,
+    /// Changes are left out and the board is sent as a whole every now and then.
+    Throttled,
+    /// The board just got interesting. Changes are left out until the board is published again,
+    /// the board is sent as a whole after that and changes are sent right away from then on.
+    Resync(u64),
+    /// Like `Resync`, but for the player's own board, which is sent with everything in it.
+    Reset(u64),
+}
+
+/// What a single player gets to see of a game. Players follow their own board and the board of
+/// their target closely, the boards of everyone else are only shown as small previews, so those
+/// are sent as snapshots at a reduced rate instead of cell by cell.
+pub struct View {
+    boards: Boards,
+    seat: usize,
+    interval: Duration,
+    target: usize,
+    detail: Vec<Detail>,
+    sent: Vec<(u64, Option<Instant>)>,
+    resets: usize,
+    /// The epoch of the publication the last sync looked at.
+    synced: u64,
+    /// When the first snapshot that was held back to keep to the interval becomes due.
+    held: Option<Instant>,
+}
+
+impl Boards {
+    pub fn new(games: &[PlayerState]) -> Self {
+        let boards = Self {
+            inner: Arc::new(Mutex::new(Published {
+                epoch: 0,
+                boards: Vec::new(),
+                states_wanted: false,
+            })),
+            epoch: Arc::new(AtomicUsize::new(0)),
+        };
+        boards.publish(games, &[]);
+        boards
+    }
+
+    /// Updates the published boards, this should be done after every update of the instance.
+    /// `inputs` holds the number of the last input of every player that was applied.
+    pub fn publish(&self, games: &[PlayerState], inputs: &[usize]) {
+        let mut published = self.inner.lock().unwrap();
+        published.epoch += 1;
+        self.epoch.store(published.epoch as usize, Ordering::SeqCst);
+        let states_wanted = replace(&mut published.states_wanted, false);
+
+        for (i, game) in games.iter().enumerate() {
+            let field = serde_json::to_string(&game.field).unwrap();
+            let garbage = serde_json::to_string(&game.garbage).unwrap();
+
+            if i == published.boards.len() {
+                published.boards.push(Board {
+                    version: 0,
+                    target: 0,
+                    field: String::new(),
+                    garbage: String::new(),
+                    state: String::new(),
+                });
+            }
+
+            let board = &mut published.boards[i];
+            board.target = game.target;
+            if states_wanted {
+                // the checksum goes along with the state, so it matches what's sent
+                let mut state = serde_json::to_value(game).unwrap();
+                let applied = inputs.get(i).cloned().unwrap_or(0);
+                state["checksum"] = Value::from(vec![applied as u64, game.state_hash() as u64]);
+                board.state = state.to_string();
+            }
+            if board.field != field || board.garbage != garbage {
+                board.version += 1;
+                board.field = field;
+                board.garbage = garbage;
+            }
+        }
+    }
+
+    fn snapshot(board: &Board, index: usize) -> [String; 2] {
+        [
+            format!("games/{}/field/set:{}", index, board.field),
+            format!("games/{}/garbage/set:{}", index, board.garbage),
+        ]
+    }
+}
+
+impl View {
+    /// Creates the view of the player at `seat`. Boards of others are sent no more than once
+    /// every `interval`.
+    pub fn new(boards: Boards, seat: usize, interval: Duration) -> Self {
+        let (sent, target, synced) = {
+            let published = boards.inner.lock().unwrap();
+            let target = published.boards.get(seat).map(|b| b.target).unwrap_or(seat);
+            let sent: Vec<(u64, Option<Instant>)> = published.boards
+                .iter()
+                .map(|b| (b.version, None))
+                .collect();
+            (sent, target, published.epoch)
+        };
+
+        // everything up to now was sent in full, so the client is up to date on every board
+        let detail = (0..sent.len())
+            .map(|i| if i == seat || i == target { Detail::Full } else { Detail::Throttled })
+            .collect();
+
+        Self {
+            boards,
+            seat,
+            interval,
+            target,
+            detail,
+            sent,
+            resets: 0,
+            synced,
+            held: None,
+        }
+    }
+
+    /// Sends the player's own board with everything in it once it's published again.
+    /// Clients ask for this when their copy doesn't match the server's anymore.
+    pub fn reset(&mut self) {
+        let mut published = self.boards.inner.lock().unwrap();
+        published.states_wanted = true;
+        self.detail[self.seat] = Detail::Reset(published.epoch);
+    }
+
+    /// Decides whether a message from the instance should be sent right away.
+    pub fn filter(&mut self, message: &str) -> bool {
+        let path = message.split(':').next().unwrap_or("");
+        let mut segments = path.split('/');
+        if segments.next() != Some("games") {
+            return true;
+        }
+
+        let index = match segments.next().and_then(|i| i.parse::<usize>().ok()) {
+            Some(index) if index < self.detail.len() => index,
+            _ => return true,
+        };
+        let member = segments.next().unwrap_or("");
+
+        if index == self.seat && member == "target" && path.ends_with("/set") {
+            if let Ok(target) = message[path.len() + 1..].parse::<usize>() {
+                self.retarget(target);
+            }
+        }
+
+        match self.detail[index] {
+            Detail::Full => true,
+            Detail::Reset(_) => false,
+            _ => !(member == "field" || member == "garbage" || member == "call"),
+        }
+    }
+
+    /// Returns the snapshots that are due, these have to be sent before anything else.
+    pub fn sync(&mut self) -> Vec<String> {
+        // nothing becomes due before the boards are published again or a held back snapshot is
+        // ready, which is the case for most messages
+        let now = Instant::now();
+        let epoch = self.boards.epoch.load(Ordering::SeqCst) as u64;
+        if epoch == self.synced && self.held.map(|held| now < held).unwrap_or(true) {
+            return Vec::new();
+        }
+
+        let published = self.boards.inner.lock().unwrap();
+        let mut messages = Vec::new();
+        self.synced = published.epoch;
+        self.held = None;
+
+        for (i, board) in published.boards.iter().enumerate().take(self.detail.len()) {
+            let (version, at) = self.sent[i];
+            let due = match self.detail[i] {
+                Detail::Full => false,
+                Detail::Throttled if board.version == version => false,
+                Detail::Throttled => match at.map(|at| at + self.interval) {
+                    Some(ready) if now < ready => {
+                        self.held = Some(self.held.map(|held| held.min(ready)).unwrap_or(ready));
+                        false
+                    },
+                    _ => true,
+                },
+                Detail::Resync(epoch) | Detail::Reset(epoch) => published.epoch > epoch,
+            };
+
+            if !due {
+                continue;
+            }
+
+            if let Detail::Reset(_) = self.detail[i] {
+                self.resets += 1;
+                messages.push(format!("games/{}/set:{}", i, board.state));
+                messages.push(format!("resynced/set:{}", self.resets));
+            } else {
+                messages.extend(Boards::snapshot(board, i).iter().cloned());
+            }
+            self.sent[i] = (board.version, Some(now));
+            if let Detail::Throttled = self.detail[i] {
+                continue;
+            }
+            self.detail[i] = Detail::Full;
+        }
+
+        messages
+    }
+
+    fn retarget(&mut self, target: usize) {
+        if target == self.target || target >= self.detail.len() {
+            return;
+        }
+
+        if self.target != self.seat {
+            self.detail[self.target] = Detail::Throttled;
+        }
+        if target != self.seat {
+            let epoch = self.boards.inner.lock().unwrap().epoch;
+            self.detail[target] = Detail::Resync(epoch);
+        }
+        self.target = target;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Three players, the first of which targets the second.
+    fn games() -> Vec<PlayerState> {
+        let mut games = vec![PlayerState::new(), PlayerState::new(), PlayerState::new()];
+        games[0].target = 1;
+        games[1].target = 0;
+        games[2].target = 0;
+        games
+    }
+
+    fn paths(messages: &[String]) -> Vec<&str> {
+        messages.iter().map(|m| m.split(':').next().unwrap()).collect()
+    }
+
+    #[test]
+    fn own_and_target_boards_are_followed_closely() {
+        let games = games();
+        let mut view = View::new(Boards::new(&games), 0, INTERVAL);
+
+        assert!(view.filter("games/0/field/set:[]"));
+        assert!(view.filter("games/1/field/set:[]"));
+        assert!(view.filter("games/1/garbage/push:[1,2]"));
+        assert!(!view.filter("games/2/field/set:[]"));
+        assert!(!view.filter("games/2/garbage/push:[1,2]"));
+        assert!(view.filter("games/2/score/set:100"));
+        assert!(view.filter("start_at/set:0"));
+    }
+
+    #[test]
+    fn throttled_boards_are_sent_as_snapshots() {
+        let mut games = games();
+        let boards = Boards::new(&games);
+        let mut view = View::new(boards.clone(), 0, INTERVAL);
+        assert!(view.sync().is_empty());
+
+        games[1].field[0] = 1;
+        games[2].field[0] = 1;
+        boards.publish(&games, &[]);
+        assert_eq!(paths(&view.sync()), vec!["games/2/field/set", "games/2/garbage/set"]);
+
+        // the next change has to wait for the interval
+        games[2].field[1] = 1;
+        boards.publish(&games, &[]);
+        assert!(view.sync().is_empty());
+
+        sleep(INTERVAL);
+        let messages = view.sync();
+        assert_eq!(paths(&messages), vec!["games/2/field/set", "games/2/garbage/set"]);
+        assert_eq!(messages[0], format!("games/2/field/set:{}",
+                                        serde_json::to_string(&games[2].field).unwrap()));
+    }
+
+    #[test]
+    fn a_new_target_is_resynced() {
+        let games = games();
+        let boards = Boards::new(&games);
+        let mut view = View::new(boards.clone(), 0, INTERVAL);
+
+        assert!(view.filter("games/0/target/set:2"));
+        assert!(!view.filter("games/1/field/set:[]"));
+        assert!(!view.filter("games/2/field/set:[]"));
+
+        // the board is sent as a whole once it's published again, and followed closely after
+        assert!(view.sync().is_empty());
+        boards.publish(&games, &[]);
+        assert_eq!(paths(&view.sync()), vec!["games/2/field/set", "games/2/garbage/set"]);
+        assert!(view.filter("games/2/field/set:[]"));
+    }
+
+    #[test]
+    fn a_reset_sends_the_full_state() {
+        let games = games();
+        let boards = Boards::new(&games);
+        let mut view = View::new(boards.clone(), 0, INTERVAL);
+
+        view.reset();
+        assert!(!view.filter("games/0/field/set:[]"));
+        assert!(!view.filter("games/0/score/set:100"));
+        assert!(view.filter("games/1/field/set:[]"));
+        assert!(view.sync().is_empty());
+
+        boards.publish(&games, &[7, 0, 0]);
+        let messages = view.sync();
+        assert_eq!(paths(&messages), vec!["games/0/set", "resynced/set"]);
+        assert_eq!(messages[1], "resynced/set:1");
+
+        let state: Value = serde_json::from_str(&messages[0]["games/0/set:".len()..]).unwrap();
+        assert_eq!(state["checksum"][0], 7);
+        assert_eq!(state["checksum"][1], games[0].state_hash() as u64);
+        assert!(view.filter("games/0/field/set:[]"));
+    }
+}
@@ -0,0 +1,38 @@
+use tetris_model::instance::{ActiveState, InstanceState};
+
+/// Where a match is played, on a server or on this machine.
+pub trait Arena {
+    /// Processes everything that happened since the last update. Fails when the match can't
+    /// go on, with a description for the player.
+    fn update(&mut self) -> Result<(), String>;
+
+    fn state(&self) -> &InstanceState;
+
+    /// Returns the index of the player that's playing here.
+    fn player(&self) -> usize;
+
+    /// Returns the time in milliseconds since the unix epoch on the clock of whoever runs the
+    /// match, if it's known.
+    fn now(&self) -> Option<u64>;
+
+    /// Returns true while the connection to the match is lost.
+    fn lost(&self) -> bool;
+
+    /// Places the current tetrimino of the player at `state`.
+    fn place(&mut self, state: ActiveState);
+
+    /// Swaps the current tetrimino of the player with the one on hold.
+    fn hold(&mut self);
+
+    /// Describes how the match is played, shown next to the board.
+    fn status(&self) -> String;
+
+    /// Returns true if the player is in the match. The match starts at the same moment for
+    /// everyone, as far as our estimate of the clock goes.
+    fn in_game(&self) -> bool {
+        match self.now() {
+            Some(now) => self.state().in_game_at(self.player(), now),
+            None => self.state().in_game(self.player()),
+        }
+    }
+}
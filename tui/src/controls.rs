@@ -0,0 +1,92 @@
+use termion::event::Key;
+
+/// The things a player can do during a match, the same ones the graphical client can bind.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Left,
+    Right,
+    SoftDrop,
+    HardDrop,
+    RotateCW,
+    RotateCCW,
+    Hold,
+}
+
+/// Maps keys to actions. Terminals only report key presses, holding a key down repeats it at
+/// the rate the terminal is set to.
+pub struct Controls {
+    bindings: Vec<(Key, Action)>,
+}
+
+impl Default for Controls {
+    /// The same bindings the graphical client starts out with.
+    fn default() -> Self {
+        Self {
+            bindings: vec![
+                (Key::Left, Action::Left),
+                (Key::Right, Action::Right),
+                (Key::Down, Action::SoftDrop),
+                (Key::Up, Action::HardDrop),
+                (Key::Char('d'), Action::RotateCW),
+                (Key::Char('a'), Action::RotateCCW),
+                (Key::Char(' '), Action::Hold),
+            ],
+        }
+    }
+}
+
+impl Controls {
+    /// Parses bindings like `left=h,hard=space`, which replace the default keys of the actions
+    /// that are named. Actions are `left`, `right`, `soft`, `hard`, `cw`, `ccw` and `hold`.
+    /// Keys are single characters or `left`, `right`, `up`, `down`, `space`, `tab` and `enter`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut controls = Self::default();
+
+        for binding in spec.split(',').filter(|b| !b.is_empty()) {
+            let mut parts = binding.splitn(2, '=');
+            let (action, key) = match (parts.next(), parts.next()) {
+                (Some(action), Some(key)) => (action, key),
+                _ => return Err(format!("Expected action=key, got {}", binding)),
+            };
+
+            let action = match action.to_lowercase().as_str() {
+                "left" => Action::Left,
+                "right" => Action::Right,
+                "soft" => Action::SoftDrop,
+                "hard" => Action::HardDrop,
+                "cw" => Action::RotateCW,
+                "ccw" => Action::RotateCCW,
+                "hold" => Action::Hold,
+                _ => return Err(format!("Unknown action {}", action)),
+            };
+
+            let key = match key.to_lowercase().as_str() {
+                "left" => Key::Left,
+                "right" => Key::Right,
+                "up" => Key::Up,
+                "down" => Key::Down,
+                "space" => Key::Char(' '),
+                "tab" => Key::Char('\t'),
+                "enter" => Key::Char('\n'),
+                _ if key.chars().count() == 1 => Key::Char(key.chars().next().unwrap()),
+                _ => return Err(format!("Unknown key {}", key)),
+            };
+
+            controls.bindings.retain(|&(_, a)| a != action);
+            controls.bindings.push((key, action));
+        }
+
+        Ok(controls)
+    }
+
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.bindings.iter().find(|&&(k, _)| k == key).map(|&(_, action)| action)
+    }
+
+    /// Returns true for the keys that leave the game: escape, q and ctrl-c, unless they're bound
+    /// to an action.
+    pub fn quits(&self, key: Key) -> bool {
+        self.action(key).is_none() &&
+            (key == Key::Esc || key == Key::Char('q') || key == Key::Ctrl('c'))
+    }
+}
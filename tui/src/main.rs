@@ -0,0 +1,114 @@
+mod arena;
+mod controls;
+mod offline;
+mod online;
+mod play;
+mod profile;
+mod terminal;
+mod util;
+mod view;
+
+use clap::App as ClapApp;
+use clap::Arg;
+use std::path::PathBuf;
+use std::process::exit;
+
+use crate::controls::Controls;
+use crate::profile::Profile;
+use crate::terminal::Terminal;
+use tetris_net::connection::TlsOptions;
+
+fn main() {
+    let matches = ClapApp::new("tutris-tui")
+        .about("Plays Tutris 9 in a terminal")
+        .arg(Arg::with_name("server")
+            .help("The server to play on [default: ws://localhost:3000]")
+            .index(1))
+        .arg(Arg::with_name("offline")
+            .long("offline")
+            .help("Plays against bots on this machine instead of on a server"))
+        .arg(Arg::with_name("bots")
+            .long("bots")
+            .help("The number of bots to play against offline [default: 8]")
+            .takes_value(true))
+        .arg(Arg::with_name("queue")
+            .long("queue")
+            .help("The matchmaking queue to join [default: the first queue of the server]")
+            .takes_value(true))
+        .arg(Arg::with_name("nickname")
+            .long("nickname")
+            .help("The name other players see, remembered for next time")
+            .takes_value(true))
+        .arg(Arg::with_name("identity")
+            .long("identity")
+            .help("Ties your rating on the server to you, the server issues one when there's \
+                   none yet. Remembered for next time")
+            .takes_value(true))
+        .arg(Arg::with_name("keys")
+            .long("keys")
+            .help("Replaces key bindings, like left=h,right=l,hard=space. Actions are left, \
+                   right, soft, hard, cw, ccw and hold")
+            .takes_value(true))
+        .arg(Arg::with_name("ca")
+            .long("ca")
+            .help("A certificate authority in PEM format to trust for wss:// servers")
+            .takes_value(true))
+        .arg(Arg::with_name("accept-invalid-certs")
+            .long("accept-invalid-certs")
+            .help("Accepts any certificate of wss:// servers, only meant for testing"))
+        .get_matches();
+
+    let controls = match Controls::parse(matches.value_of("keys").unwrap_or("")) {
+        Ok(controls) => controls,
+        Err(e) => {
+            eprintln!("Invalid --keys: {}", e);
+            exit(1);
+        },
+    };
+
+    let bots = match matches.value_of("bots").unwrap_or("8").parse::<usize>() {
+        Ok(bots) if bots > 0 => bots,
+        _ => {
+            eprintln!("--bots takes a number of at least 1");
+            exit(1);
+        },
+    };
+
+    let tls = TlsOptions {
+        ca: matches.value_of("ca").map(PathBuf::from),
+        accept_invalid_certs: matches.is_present("accept-invalid-certs"),
+    };
+
+    let mut profile = Profile::load();
+    if matches.is_present("nickname") || matches.is_present("identity") {
+        profile.nickname = matches.value_of("nickname").unwrap_or(&profile.nickname).to_string();
+        profile.identity = matches.value_of("identity").unwrap_or(&profile.identity).to_string();
+        if let Err(e) = profile.save() {
+            eprintln!("Unable to save the profile: {}", e);
+        }
+    }
+
+    let mut terminal = match Terminal::new() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("Unable to use the terminal: {}", e);
+            exit(1);
+        },
+    };
+
+    let result = if matches.is_present("offline") {
+        offline::run(&mut terminal, &controls, bots, profile.nickname.as_str())
+    } else {
+        let server = util::server_address(matches.value_of("server")
+            .unwrap_or("ws://localhost:3000"));
+        let queue = matches.value_of("queue");
+        online::run(&mut terminal, &controls, server.as_str(), &tls, queue, &mut profile)
+    };
+
+    // the terminal has to be back to normal before anything is printed
+    drop(terminal);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
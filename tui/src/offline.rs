@@ -0,0 +1,231 @@
+use crate::arena::Arena;
+use crate::controls::Controls;
+use crate::play::play;
+use crate::terminal::Terminal;
+use mirror::{Error, Remote, SharedServer};
+use rand::random;
+use std::sync::mpsc::{channel, Sender};
+use tetris_model::instance::{sanitize_nickname, ActiveState, InstanceState, PlayerState};
+use tetris_model::shapes::SHAPES;
+use tetris_net::local_millis;
+
+/// The key of the player that sits at this terminal.
+const PLAYER_KEY: &str = "player";
+
+/// Nobody connects to an offline match, but the server still needs to know what a connection
+/// would look like.
+struct Nobody;
+
+/// A bot that places every tetrimino where it leaves the flattest field, at a human pace.
+struct Bot {
+    key: String,
+    index: usize,
+    next: u64,
+    inputs: usize,
+}
+
+/// A match that runs on this machine, against bots.
+pub struct Offline {
+    server: SharedServer<InstanceState, Nobody>,
+    _remotes: Sender<Nobody>,
+    bots: Vec<Bot>,
+    inputs: usize,
+}
+
+/// Plays a match against `bots` bots.
+pub fn run(terminal: &mut Terminal, controls: &Controls, bots: usize, nickname: &str)
+    -> Result<(), String> {
+    let mut offline = Offline::new(bots, nickname)?;
+    play(terminal, &mut offline, controls)
+}
+
+impl Remote for Nobody {
+    fn close(&mut self) { }
+
+    fn alive(&self) -> bool {
+        false
+    }
+
+    fn send(&mut self, _message: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        None
+    }
+}
+
+impl Offline {
+    fn new(bots: usize, nickname: &str) -> Result<Self, String> {
+        let bots: Vec<Bot> = (1..=bots.max(1)).map(Bot::new).collect();
+        let mut keys = vec![PLAYER_KEY.to_string()];
+        keys.extend(bots.iter().map(|bot| bot.key.clone()));
+
+        let (remotes, listener) = channel();
+        let mut server = SharedServer::new(InstanceState::new(keys), listener);
+
+        // everyone is there right away, so the countdown starts as soon as we log in
+        let nickname: serde_json::Value = sanitize_nickname(nickname).into();
+        login(&mut server, PLAYER_KEY, nickname.to_string().as_str())?;
+        for bot in bots.iter() {
+            login(&mut server, bot.key.as_str(), format!("\"Bot {}\"", bot.index).as_str())?;
+        }
+
+        Ok(Self {
+            server,
+            _remotes: remotes,
+            bots,
+            inputs: 0,
+        })
+    }
+
+    fn command(&mut self, command: String) {
+        self.server.local_command(command.as_str()).ok();
+    }
+}
+
+fn login(server: &mut SharedServer<InstanceState, Nobody>, key: &str, nickname: &str)
+    -> Result<(), String> {
+    server.local_command(format!("call:login:\"{}\" {}", key, nickname).as_str())
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+impl Arena for Offline {
+    fn update(&mut self) -> Result<(), String> {
+        self.server.update();
+        self.server.local_command("call:server_update:").map_err(|e| format!("{:?}", e))?;
+
+        let now = local_millis();
+        for bot in self.bots.iter_mut() {
+            if self.server.in_game(bot.index) {
+                if let Some(command) = bot.command(&self.server.games[bot.index], now) {
+                    self.server.local_command(command.as_str()).map_err(|e| format!("{:?}", e))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn state(&self) -> &InstanceState {
+        &self.server
+    }
+
+    fn player(&self) -> usize {
+        0
+    }
+
+    fn now(&self) -> Option<u64> {
+        Some(local_millis())
+    }
+
+    fn lost(&self) -> bool {
+        false
+    }
+
+    fn place(&mut self, state: ActiveState) {
+        self.inputs += 1;
+        let state = serde_json::to_string(&state).unwrap();
+        let forced = self.server.games[0].forced;
+        let command = format!("call:drop:\"{}\" {} {} {}", PLAYER_KEY, state, self.inputs, forced);
+        self.command(command);
+    }
+
+    fn hold(&mut self) {
+        self.inputs += 1;
+        let command = format!("call:hold:\"{}\" {}", PLAYER_KEY, self.inputs);
+        self.command(command);
+    }
+
+    fn status(&self) -> String {
+        "Offline".to_string()
+    }
+}
+
+impl Bot {
+    fn new(index: usize) -> Self {
+        Self {
+            key: format!("bot{}", index),
+            index,
+            next: 0,
+            inputs: 0,
+        }
+    }
+
+    /// Returns the next command of the bot, if it's time for one.
+    fn command(&mut self, game: &PlayerState, now: u64) -> Option<String> {
+        if self.next == 0 {
+            // don't start at the same moment as everyone else
+            self.next = now + random::<u64>() % 1000;
+        }
+        if now < self.next {
+            return None;
+        }
+        self.next = now + 500 + random::<u64>() % 700;
+        self.inputs += 1;
+
+        let state = serde_json::to_string(&choose(game)).unwrap();
+        Some(format!("call:drop:\"{}\" {} {} {}", self.key, state, self.inputs, game.forced))
+    }
+}
+
+/// Picks where to place the current tetrimino of `game`. Every now and then the bot makes a
+/// mistake and goes with something that merely fits.
+fn choose(game: &PlayerState) -> ActiveState {
+    let moves = game.landings();
+    if random::<u32>() % 10 == 0 {
+        return moves[random::<usize>() % moves.len()];
+    }
+
+    moves
+        .into_iter()
+        .map(|state| (evaluate(game, state), state))
+        .fold(None, |best: Option<(f64, ActiveState)>, (score, state)| match best {
+            Some((best_score, _)) if best_score >= score => best,
+            _ => Some((score, state)),
+        })
+        .map(|(_, state)| state)
+        .unwrap_or_else(ActiveState::new)
+}
+
+/// Rates the field after placing the current tetrimino of `game` at `state`. Cleared lines
+/// count in favour, holes, height and bumps against.
+fn evaluate(game: &PlayerState, state: ActiveState) -> f64 {
+    let mut field = game.field.clone();
+    let shape = &SHAPES[game.current as usize][state.rotation as usize];
+    for y in 0..4 {
+        for x in 0..4 {
+            let row = state.y + y as i32;
+            if shape[x + y * 4] != 0 && row >= 0 {
+                field[(row * 10 + state.x + x as i32) as usize] = 1;
+            }
+        }
+    }
+
+    let full = |y: usize| field[y * 10..y * 10 + 10].iter().all(|&b| b > 0);
+    let rows: Vec<usize> = (0..21).filter(|&y| !full(y)).collect();
+    let lines = 21 - rows.len();
+
+    // heights and holes of the field as it is after the cleared lines are gone
+    let mut heights = [0usize; 10];
+    let mut holes = 0;
+    for x in 0..10 {
+        let mut top = None;
+        for (depth, &y) in rows.iter().enumerate() {
+            if field[y * 10 + x] > 0 {
+                top = top.or(Some(depth));
+            } else if top.is_some() {
+                holes += 1;
+            }
+        }
+        heights[x] = top.map(|top| rows.len() - top).unwrap_or(0);
+    }
+
+    let height: usize = heights.iter().sum();
+    let bumpiness: usize = heights.windows(2)
+        .map(|w| (w[0] as i32 - w[1] as i32).abs() as usize)
+        .sum();
+
+    0.76 * lines as f64 - 0.51 * height as f64 - 0.36 * holes as f64 - 0.18 * bumpiness as f64
+}
@@ -0,0 +1,254 @@
+use crate::arena::Arena;
+use crate::controls::Controls;
+use crate::play::play;
+use crate::profile::Profile;
+use crate::terminal::Terminal;
+use futures::{future, Async, Future};
+use mirror::{Client, Remote};
+use serde_json::Value;
+use std::thread::sleep;
+use std::time::Duration;
+use tetris_model::instance::{sanitize_nickname, ActiveState, InstanceState};
+use tetris_model::matchmaking::MatchmakingState;
+use tetris_net::clock::Clock;
+use tetris_net::connection::{make_connection, version_mismatch, TlsOptions};
+use tetris_net::local_millis;
+use tetris_net::prediction::{Input, Prediction};
+
+/// Connection attempts that take longer than this many milliseconds are given up on.
+const ATTEMPT_TIMEOUT: u64 = 5_000;
+
+/// Time between attempts to get back into a match, in milliseconds.
+const RETRY_INTERVAL: u64 = 2_000;
+
+/// Time between updates while waiting for the server.
+const FRAME: Duration = Duration::from_millis(50);
+
+type Connecting<R> = Box<Future<Item=Client<InstanceState, R>, Error=String>>;
+type Connect<R> = Box<Fn() -> Connecting<R>>;
+
+/// A match on a server.
+pub struct Online<R: Remote> {
+    client: Client<InstanceState, R>,
+    connect: Connect<R>,
+    player: usize,
+    login: String,
+    prediction: Prediction,
+    clock: Clock,
+    lost_at: Option<u64>,
+    reconnecting: Option<(Connecting<R>, u64)>,
+    retry_at: u64,
+}
+
+/// Finds a match on `server` in `queue`, or the first queue of the server, and plays it.
+pub fn run(terminal: &mut Terminal, controls: &Controls, server: &str, tls: &TlsOptions,
+           queue: Option<&str>, profile: &mut Profile) -> Result<(), String> {
+    let address = format!("{}/instance/matchmaking", server);
+    let remote = make_connection(address.as_str(), tls)?;
+    let connecting = Client::<MatchmakingState, _>::new(remote).map_err(|e| format!("{:?}", e));
+    let mut matchmaking = match wait(terminal, controls, connecting, "Connecting...")? {
+        Some(matchmaking) => matchmaking,
+        None => return Ok(()),
+    };
+
+    let identity: Value = profile.identity.clone().into();
+    let nickname: Value = sanitize_nickname(profile.nickname.as_str()).into();
+    let queue: Value = match queue {
+        Some(queue) => queue.into(),
+        None => match matchmaking.queues.first() {
+            Some((name, _)) => name.clone().into(),
+            None => return Err("The server doesn't run any queues".to_string()),
+        },
+    };
+    matchmaking.command(format!("call:identify:{} {}", identity, nickname).as_str()).ok();
+    matchmaking.command(format!("call:join:{}", queue).as_str()).ok();
+
+    while !matchmaking.done {
+        matchmaking.update();
+        if !matchmaking.identity.is_empty() && matchmaking.identity != profile.identity {
+            // the server issued us an identity, it's needed to keep our rating next time
+            profile.identity = matchmaking.identity.clone();
+            profile.save().ok();
+        }
+        if !matchmaking.error.is_empty() {
+            let queues: Vec<&str> = matchmaking.queues.iter().map(|(n, _)| n.as_str()).collect();
+            return Err(format!("{}, the server runs {}", matchmaking.error, queues.join(", ")));
+        }
+        if !matchmaking.alive() {
+            return Err(if version_mismatch() {
+                "The server speaks another version of the protocol, please update".to_string()
+            } else {
+                "Lost the connection to the matchmaker".to_string()
+            });
+        }
+        if terminal.keys().into_iter().any(|key| controls.quits(key)) {
+            return Ok(());
+        }
+
+        let status = if matchmaking.max_players == 0 {
+            "Matching...".to_string()
+        } else {
+            format!("Matching {}/{} ~{}s", matchmaking.players_found, matchmaking.max_players,
+                    matchmaking.estimated_wait)
+        };
+        terminal.message(status.as_str())?;
+        sleep(FRAME);
+    }
+
+    let address = format!("{}/instance/{}", server, matchmaking.instance_address);
+    let tls = tls.clone();
+    let connect = connector(move || make_connection(address.as_str(), &tls));
+    let client = match wait(terminal, controls, connect(), "Joining the match...")? {
+        Some(client) => client,
+        None => return Ok(()),
+    };
+
+    let login = format!("call:login:\"{}\" {}", matchmaking.player_key, nickname);
+    let mut online = Online::new(client, connect, matchmaking.player_id, login);
+    play(terminal, &mut online, controls)
+}
+
+/// Turns a function that opens connections into one that connects mirror clients over them.
+fn connector<R, F>(connect: F) -> Connect<R> where
+    R: 'static + Remote,
+    F: 'static + Fn() -> Result<R, String>
+{
+    Box::new(move || -> Connecting<R> {
+        match connect() {
+            Ok(remote) => Box::new(Client::new(remote).map_err(|e| format!("{:?}", e))),
+            Err(e) => Box::new(future::err(e)),
+        }
+    })
+}
+
+/// Waits for `future` while showing `status`. Returns `None` if the player quits.
+fn wait<F>(terminal: &mut Terminal, controls: &Controls, mut future: F, status: &str)
+    -> Result<Option<F::Item>, String> where
+    F: Future<Error=String>
+{
+    loop {
+        if let Async::Ready(item) = future.poll()? {
+            return Ok(Some(item));
+        }
+        if terminal.keys().into_iter().any(|key| controls.quits(key)) {
+            return Ok(None);
+        }
+        terminal.message(status)?;
+        sleep(FRAME);
+    }
+}
+
+impl<R: Remote> Online<R> {
+    fn new(mut client: Client<InstanceState, R>, connect: Connect<R>, player: usize,
+           login: String) -> Self {
+        client.command(login.as_str()).ok();
+        Self {
+            client,
+            connect,
+            player,
+            login,
+            prediction: Prediction::new(),
+            clock: Clock::new(),
+            lost_at: None,
+            reconnecting: None,
+            retry_at: 0,
+        }
+    }
+
+    /// Tries to get a new connection to the match, until the server has given our seat away.
+    fn reconnect(&mut self, now: u64) -> Result<(), String> {
+        // there's no point in coming back with a client the server doesn't understand
+        if version_mismatch() {
+            return Err("The server speaks another version of the protocol, please update"
+                .to_string());
+        }
+
+        let lost_at = *self.lost_at.get_or_insert(now);
+        if now > lost_at + self.client.disconnect_timeout * 1000 {
+            return Err("Disconnected".to_string());
+        }
+
+        if let Some((mut future, started)) = self.reconnecting.take() {
+            match future.poll() {
+                Ok(Async::Ready(mut client)) => {
+                    client.command(self.login.as_str()).ok();
+                    self.client = client;
+                    self.prediction.reconnected(&mut self.client.games[self.player]);
+                    self.clock.reconnected();
+                    self.lost_at = None;
+                },
+                Ok(Async::NotReady) if now < started + ATTEMPT_TIMEOUT => {
+                    self.reconnecting = Some((future, started));
+                },
+                _ => self.retry_at = now + RETRY_INTERVAL,
+            }
+        } else if now >= self.retry_at {
+            self.reconnecting = Some(((self.connect)(), now));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Remote> Arena for Online<R> {
+    fn update(&mut self) -> Result<(), String> {
+        self.client.update();
+        let now = local_millis();
+
+        // connections that went quiet are given up on, even if they were never closed
+        let lost = self.lost_at.is_some() || !self.client.alive() || self.clock.silent();
+        if lost && !self.client.done {
+            return self.reconnect(now);
+        }
+
+        if let Some(command) = self.clock.update(self.client.clock) {
+            self.client.command(command.as_str()).ok();
+        }
+
+        // ask for the server's copy of our state when ours went out of sync
+        if self.in_game() {
+            let resynced = self.client.resynced;
+            if self.prediction.check(&mut self.client.games[self.player], resynced) {
+                self.client.command("call:resync:").ok();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn state(&self) -> &InstanceState {
+        &self.client
+    }
+
+    fn player(&self) -> usize {
+        self.player
+    }
+
+    fn now(&self) -> Option<u64> {
+        self.clock.server_now()
+    }
+
+    fn lost(&self) -> bool {
+        self.lost_at.is_some()
+    }
+
+    fn place(&mut self, state: ActiveState) {
+        let forced = self.client.games[self.player].forced;
+        let input = self.prediction.input(&mut self.client.games[self.player],
+                                          Input::Drop(state));
+        self.client.command(format!("call:drop:{} {} {}", serde_json::to_string(&state).unwrap(),
+                                    input, forced).as_str()).ok();
+    }
+
+    fn hold(&mut self) {
+        let input = self.prediction.input(&mut self.client.games[self.player], Input::Hold);
+        self.client.command(format!("call:hold:{}", input).as_str()).ok();
+    }
+
+    fn status(&self) -> String {
+        match self.clock.rtt() {
+            Some(rtt) => format!("Ping   {} ms", rtt),
+            None => "Online".to_string(),
+        }
+    }
+}
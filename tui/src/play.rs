@@ -0,0 +1,148 @@
+use crate::arena::Arena;
+use crate::controls::{Action, Controls};
+use crate::terminal::Terminal;
+use crate::view;
+use std::thread::sleep;
+use std::time::Duration;
+use tetris_model::instance::{ActiveState, COUNTDOWN};
+use tetris_net::local_millis;
+
+/// Time between frames.
+const FRAME: Duration = Duration::from_millis(16);
+
+/// Plays a match until it's over and the player leaves, or the player quits.
+pub fn play<A: Arena>(terminal: &mut Terminal, arena: &mut A, controls: &Controls)
+    -> Result<(), String> {
+    let player = arena.player();
+    let mut active = ActiveState::new();
+    let mut forced = arena.state().games[player].forced;
+    let mut was_lost = false;
+    let mut falling = 0;
+    let mut last = local_millis();
+
+    loop {
+        let now = local_millis();
+        let dt = now.saturating_sub(last);
+        last = now;
+
+        arena.update()?;
+
+        let keys = terminal.keys();
+        if keys.iter().any(|&key| controls.quits(key)) {
+            return Ok(());
+        }
+
+        // start over with the next tetrimino after getting back into the match, or when the
+        // server placed ours because we took too long
+        let lost = arena.lost();
+        if was_lost && !lost {
+            active = ActiveState::new();
+        }
+        was_lost = lost;
+
+        if arena.state().games[player].forced != forced {
+            forced = arena.state().games[player].forced;
+            active = ActiveState::new();
+        }
+
+        let playing = !lost && arena.in_game();
+        if playing {
+            for action in keys.into_iter().filter_map(|key| controls.action(key)) {
+                let game = &arena.state().games[player];
+                match action {
+                    Action::Left => active = game.slide_left(active),
+                    Action::Right => active = game.slide_right(active),
+                    Action::SoftDrop => active = game.slide_down(active),
+                    Action::RotateCW => active = game.rotate_right(active),
+                    Action::RotateCCW => active = game.rotate_left(active),
+                    Action::HardDrop => {
+                        active = game.hard_drop(active);
+                        arena.place(active);
+                        active = ActiveState::new();
+                    },
+                    Action::Hold => if !game.held {
+                        arena.hold();
+                        active = ActiveState::new();
+                    },
+                }
+            }
+
+            let speed = arena.state().speed;
+            falling += dt;
+            while falling >= speed {
+                falling -= speed;
+                let before = active;
+                active = arena.state().games[player].slide_down(active);
+
+                if before.y == active.y {
+                    arena.place(active);
+                    active = ActiveState::new();
+                }
+            }
+        } else {
+            falling = 0;
+        }
+
+        let overlay = message(arena, lost);
+        let lines = info(arena);
+        view::draw(terminal, arena.state(), player, if playing { Some(active) } else { None },
+                   lines.as_slice(), overlay.as_ref().map(|m| m.as_str()));
+        terminal.present()?;
+
+        sleep(FRAME);
+    }
+}
+
+/// Returns what's shown on top of the board, if anything.
+fn message<A: Arena>(arena: &A, lost: bool) -> Option<String> {
+    let state = arena.state();
+    let game = &state.games[arena.player()];
+
+    if lost {
+        return Some("Connection lost".to_string());
+    }
+
+    if state.done || game.ko {
+        let place = state.standings().iter().position(|&i| i == arena.player()).unwrap_or(0);
+        return Some(match place {
+            0 if state.done => "Winner!".to_string(),
+            place => format!("Game over, place {}", place + 1),
+        });
+    }
+
+    if !state.started {
+        // the countdown runs on the clock of whoever runs the match
+        let now = match arena.now() {
+            Some(now) => now,
+            None => return Some(state.status.clone()),
+        };
+        let left = state.start_at as i64 - now as i64;
+        let countdown = COUNTDOWN.as_secs() as i64 * 1000;
+        return if state.start_at == 0 || left > countdown {
+            Some(state.status.clone())
+        } else if left > 0 {
+            Some(format!("{}", (left + 999) / 1000))
+        } else if left > -1000 {
+            Some("GO!".to_string())
+        } else {
+            None
+        };
+    }
+
+    None
+}
+
+/// Returns the lines shown next to the board.
+fn info<A: Arena>(arena: &A) -> Vec<String> {
+    let state = arena.state();
+    let game = &state.games[arena.player()];
+    let left = state.games.iter().filter(|g| !g.ko).count();
+
+    vec![
+        format!("Lines  {}", game.lines_cleared),
+        format!("Sent   {}", game.garbage_sent),
+        format!("Left   {}/{}", left, state.games.len()),
+        arena.status(),
+        "q to leave".to_string(),
+    ]
+}
@@ -0,0 +1,52 @@
+use serde::*;
+use std::env::var_os;
+use std::fs::{create_dir_all, read_to_string, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Who's playing, as far as the matchmaker is concerned. Kept between runs in
+/// `~/.config/tutris9/tui.json`, the way the other clients keep their profile.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub identity: String,
+    #[serde(default)]
+    pub nickname: String,
+}
+
+impl Profile {
+    /// Loads the saved profile, or an empty one if there is none yet.
+    pub fn load() -> Self {
+        path()
+            .and_then(|path| read_to_string(path).ok())
+            .and_then(|text| serde_json::from_str(text.as_str()).ok())
+            .unwrap_or_default()
+    }
+
+    /// Saves the profile. The identity works like a password, so only we may read the file.
+    pub fn save(&self) -> Result<(), String> {
+        let path = path().ok_or("There's no home directory to save the profile in")?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        serde_json::to_writer(&mut file, self).map_err(|e| e.to_string())?;
+        file.write_all(b"\n").map_err(|e| e.to_string())
+    }
+}
+
+/// Returns where the profile is kept, under `$XDG_CONFIG_HOME` or else `~/.config`.
+fn path() -> Option<PathBuf> {
+    var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|config| config.join("tutris9").join("tui.json"))
+}
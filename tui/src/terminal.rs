@@ -0,0 +1,143 @@
+use std::io::{stdout, Stdout, Write};
+
+use termion::{async_stdin, clear, color, cursor, style, terminal_size, AsyncReader};
+use termion::color::AnsiValue;
+use termion::event::Key;
+use termion::input::{Keys, TermRead};
+use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::AlternateScreen;
+
+/// A character on the screen. Colors are taken from the 256 color palette, `None` leaves the
+/// terminal's own color.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub ch: char,
+    pub fg: Option<u8>,
+    pub bg: Option<u8>,
+}
+
+const BLANK: Cell = Cell { ch: ' ', fg: None, bg: None };
+
+/// The terminal the game is drawn to. Every frame is drawn from scratch, but only the cells that
+/// changed since the last frame are sent, which keeps the game playable over a slow connection.
+pub struct Terminal {
+    out: AlternateScreen<RawTerminal<Stdout>>,
+    keys: Keys<AsyncReader>,
+    size: (u16, u16),
+    cells: Vec<Cell>,
+    shown: Vec<Cell>,
+}
+
+impl Terminal {
+    /// Switches the terminal to raw mode on the alternate screen, until this is dropped.
+    pub fn new() -> Result<Self, String> {
+        let raw = stdout()
+            .into_raw_mode()
+            .map_err(|e| format!("Unable to use the terminal: {}", e))?;
+        let mut out = AlternateScreen::from(raw);
+        write!(out, "{}{}", cursor::Hide, clear::All).ok();
+
+        Ok(Self {
+            out,
+            keys: async_stdin().keys(),
+            size: (0, 0),
+            cells: Vec::new(),
+            shown: Vec::new(),
+        })
+    }
+
+    /// Returns the keys that were pressed since the last call.
+    pub fn keys(&mut self) -> Vec<Key> {
+        let mut keys = Vec::new();
+        while let Some(Ok(key)) = self.keys.next() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// Starts a new frame with nothing on it. Returns the size of the terminal.
+    pub fn clear(&mut self) -> (u16, u16) {
+        let size = terminal_size().unwrap_or((80, 24));
+        if size != self.size {
+            // everything has to be sent again after the terminal was resized
+            self.size = size;
+            self.shown.clear();
+            write!(self.out, "{}{}", style::Reset, clear::All).ok();
+        }
+        self.cells = vec![BLANK; size.0 as usize * size.1 as usize];
+        size
+    }
+
+    /// Draws a character, anything outside of the terminal is left out.
+    pub fn put(&mut self, x: i32, y: i32, ch: char, fg: Option<u8>, bg: Option<u8>) {
+        let (width, height) = (self.size.0 as i32, self.size.1 as i32);
+        if x >= 0 && y >= 0 && x < width && y < height {
+            self.cells[(x + y * width) as usize] = Cell { ch, fg, bg };
+        }
+    }
+
+    /// Draws a line of text starting at `x`.
+    pub fn text(&mut self, x: i32, y: i32, text: &str, fg: Option<u8>, bg: Option<u8>) {
+        for (i, ch) in text.chars().enumerate() {
+            self.put(x + i as i32, y, ch, fg, bg);
+        }
+    }
+
+    /// Draws a line of text centered on `x`.
+    pub fn centered(&mut self, x: i32, y: i32, text: &str, fg: Option<u8>, bg: Option<u8>) {
+        let x = x - text.chars().count() as i32 / 2;
+        self.text(x, y, text, fg, bg);
+    }
+
+    /// Shows a frame with nothing but `text` in the middle.
+    pub fn message(&mut self, text: &str) -> Result<(), String> {
+        let (width, height) = self.clear();
+        self.centered(width as i32 / 2, height as i32 / 2, text, None, None);
+        self.present()
+    }
+
+    /// Shows everything that was drawn since `clear`.
+    pub fn present(&mut self) -> Result<(), String> {
+        let width = self.size.0 as usize;
+        let mut output = String::new();
+        let mut colors: Option<(Option<u8>, Option<u8>)> = None;
+        let mut next = None;
+
+        for (i, cell) in self.cells.iter().enumerate() {
+            if self.shown.get(i) == Some(cell) {
+                continue;
+            }
+
+            // the cursor moves along by itself while cells are written next to each other
+            if next != Some(i) {
+                let (x, y) = (i % width, i / width);
+                output.push_str(&format!("{}", cursor::Goto(x as u16 + 1, y as u16 + 1)));
+            }
+            if colors != Some((cell.fg, cell.bg)) {
+                output.push_str(&match cell.fg {
+                    Some(fg) => format!("{}", color::Fg(AnsiValue(fg))),
+                    None => format!("{}", color::Fg(color::Reset)),
+                });
+                output.push_str(&match cell.bg {
+                    Some(bg) => format!("{}", color::Bg(AnsiValue(bg))),
+                    None => format!("{}", color::Bg(color::Reset)),
+                });
+                colors = Some((cell.fg, cell.bg));
+            }
+            output.push(cell.ch);
+            next = if (i + 1) % width == 0 { None } else { Some(i + 1) };
+        }
+
+        self.shown = self.cells.clone();
+        write!(self.out, "{}", output)
+            .and_then(|_| self.out.flush())
+            .map_err(|e| format!("Unable to draw to the terminal: {}", e))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        write!(self.out, "{}{}", style::Reset, cursor::Show).ok();
+        self.out.flush().ok();
+    }
+}
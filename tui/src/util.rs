@@ -0,0 +1,9 @@
+/// Completes the address of a server, `ws://` is assumed when no protocol is given.
+pub fn server_address(address: &str) -> String {
+    let address = address.trim().trim_end_matches('/');
+    if address.contains("//") {
+        address.to_string()
+    } else {
+        format!("ws://{}", address)
+    }
+}
@@ -0,0 +1,158 @@
+use crate::terminal::Terminal;
+use tetris_model::instance::{ActiveState, InstanceState, PlayerState};
+use tetris_model::shapes::SHAPES;
+
+/// Colors of the blocks by their value in the field, the seven tetriminos followed by garbage.
+const BLOCK_COLORS: [u8; 9] = [0, 51, 226, 129, 27, 208, 46, 196, 244];
+
+const EMPTY: u8 = 234;
+const BORDER: u8 = 240;
+const DIM: u8 = 242;
+const HIGHLIGHT: u8 = 196;
+const WHITE: u8 = 231;
+const BLACK: u8 = 16;
+
+const TOP: i32 = 1;
+const HOLD_X: i32 = 1;
+const BOARD_X: i32 = 13;
+const NEXT_X: i32 = 36;
+const OPPONENTS_X: i32 = 48;
+
+/// Room taken by the board of an opponent, its name above 10 lines of half blocks.
+const OPPONENT_WIDTH: i32 = 12;
+const OPPONENT_HEIGHT: i32 = 12;
+
+/// Draws the match as seen by `player`, whose current tetrimino is at `active` while it's
+/// being placed. `info` is shown next to the board and `message` on top of it.
+pub fn draw(terminal: &mut Terminal, state: &InstanceState, player: usize,
+            active: Option<ActiveState>, info: &[String], message: Option<&str>) {
+    let (width, _) = terminal.clear();
+    let game = &state.games[player];
+
+    // hold
+    terminal.text(HOLD_X, TOP, "HOLD", Some(DIM), None);
+    if game.hold < 8 {
+        let color = if game.held { Some(DIM) } else { None };
+        draw_preview(terminal, game.hold, HOLD_X, TOP + 2, color);
+    }
+
+    // the field, leaving out the row that's only there to tell when a player tops out
+    for y in 0..20 {
+        terminal.put(BOARD_X - 1, TOP + y, '│', Some(BORDER), None);
+        terminal.put(BOARD_X + 20, TOP + y, '│', Some(BORDER), None);
+        for x in 0..10 {
+            let block = game.field[((y + 1) * 10 + x) as usize];
+            draw_block(terminal, BOARD_X + x * 2, TOP + y, block, false);
+        }
+    }
+    terminal.put(BOARD_X - 1, TOP + 20, '└', Some(BORDER), None);
+    terminal.text(BOARD_X, TOP + 20, &"─".repeat(20), Some(BORDER), None);
+    terminal.put(BOARD_X + 20, TOP + 20, '┘', Some(BORDER), None);
+
+    // incoming garbage, one line for every pending attack
+    for i in 0..game.garbage.len().min(20) as i32 {
+        terminal.put(BOARD_X - 2, TOP + 19 - i, '▐', Some(HIGHLIGHT), None);
+    }
+
+    // the tetrimino that's being placed and where it would land
+    if let Some(active) = active {
+        draw_piece(terminal, game, game.hard_drop(active), true);
+        draw_piece(terminal, game, active, false);
+    }
+
+    // next
+    terminal.text(NEXT_X, TOP, "NEXT", Some(DIM), None);
+    for (i, &next) in game.next.iter().take(5).enumerate() {
+        draw_preview(terminal, next, NEXT_X, TOP + 2 + i as i32 * 3, None);
+    }
+
+    for (i, line) in info.iter().enumerate() {
+        terminal.text(NEXT_X, TOP + 17 + i as i32, line.as_str(), None, None);
+    }
+
+    // opponents, as many as fit next to the board
+    let columns = ((width as i32 - OPPONENTS_X) / OPPONENT_WIDTH).max(1);
+    let opponents = (0..state.games.len()).filter(|&i| i != player);
+    for (n, index) in opponents.enumerate() {
+        let x = OPPONENTS_X + (n as i32 % columns) * OPPONENT_WIDTH;
+        let y = TOP + (n as i32 / columns) * OPPONENT_HEIGHT;
+        draw_opponent(terminal, state, index, index == game.target, x, y);
+    }
+
+    if let Some(message) = message {
+        let text = format!(" {} ", message);
+        terminal.centered(BOARD_X + 10, TOP + 9, text.as_str(), Some(WHITE), Some(BLACK));
+    }
+}
+
+fn draw_block(terminal: &mut Terminal, x: i32, y: i32, block: u8, ghost: bool) {
+    let color = BLOCK_COLORS.get(block as usize).cloned().filter(|_| block > 0);
+    match (color, ghost) {
+        (Some(color), false) => {
+            terminal.put(x, y, ' ', None, Some(color));
+            terminal.put(x + 1, y, ' ', None, Some(color));
+        },
+        (Some(color), true) => {
+            terminal.put(x, y, '░', Some(color), Some(EMPTY));
+            terminal.put(x + 1, y, '░', Some(color), Some(EMPTY));
+        },
+        (None, _) => {
+            terminal.put(x, y, ' ', None, Some(EMPTY));
+            terminal.put(x + 1, y, '·', Some(BORDER), Some(EMPTY));
+        },
+    }
+}
+
+/// Draws the current tetrimino of `game` on the field at `state`.
+fn draw_piece(terminal: &mut Terminal, game: &PlayerState, state: ActiveState, ghost: bool) {
+    let shape = &SHAPES[game.current as usize][state.rotation as usize];
+    for y in 0..4 {
+        for x in 0..4 {
+            let block = shape[x + y * 4];
+            let row = state.y + y as i32;
+            if block != 0 && row >= 1 {
+                let x = BOARD_X + (state.x + x as i32) * 2;
+                draw_block(terminal, x, TOP + row - 1, block, ghost);
+            }
+        }
+    }
+}
+
+/// Draws a tetrimino the way it spawns, in the 8 by 2 characters at `x`, `y`.
+fn draw_preview(terminal: &mut Terminal, tetrimino: u8, x: i32, y: i32, color: Option<u8>) {
+    let shape = &SHAPES[tetrimino as usize][0];
+    for row in 1..3 {
+        for column in 0..4 {
+            let block = shape[column + row * 4];
+            if block != 0 {
+                let color = color.unwrap_or(BLOCK_COLORS[block as usize]);
+                let at = x + column as i32 * 2;
+                terminal.put(at, y + row as i32 - 1, ' ', None, Some(color));
+                terminal.put(at + 1, y + row as i32 - 1, ' ', None, Some(color));
+            }
+        }
+    }
+}
+
+/// Draws the board of an opponent at half the height, two rows of blocks per line.
+fn draw_opponent(terminal: &mut Terminal, state: &InstanceState, index: usize, target: bool,
+                 x: i32, y: i32) {
+    let game = &state.games[index];
+    let name: String = game.display_name(index).chars().take(10).collect();
+    let color = if target { HIGHLIGHT } else { DIM };
+    terminal.text(x, y, name.as_str(), Some(color), None);
+
+    let color = |block: u8| BLOCK_COLORS.get(block as usize).cloned().filter(|_| block > 0)
+        .unwrap_or(EMPTY);
+    for line in 0..10 {
+        for column in 0..10 {
+            let upper = game.field[((line * 2 + 1) * 10 + column) as usize];
+            let lower = game.field[((line * 2 + 2) * 10 + column) as usize];
+            terminal.put(x + column, y + 1 + line, '▀', Some(color(upper)), Some(color(lower)));
+        }
+    }
+
+    if game.ko {
+        terminal.text(x + 3, y + 5, "K.O.", Some(WHITE), Some(HIGHLIGHT));
+    }
+}